@@ -1,6 +1,10 @@
 use clap::Clap;
+use genomicsqlite::ConnectionMethods;
 use log::{debug, info, warn};
 use rusqlite::{params, OpenFlags, OptionalExtension};
+use std::collections::HashMap;
+use std::io::Read;
+use std::{fs, io};
 
 use crate::bad_command;
 use crate::load;
@@ -9,13 +13,22 @@ use crate::util::Result;
 
 #[derive(Clap)]
 pub struct Opts {
-    /// Assembly .gfab filename (to modify in-place; copy first if needed)
+    /// Assembly .gfab filename (to modify in-place, unless -o is given)
     pub gfab: String,
-    /// Uncompressed .paf filename [omit or - for standard input]
+    /// Uncompressed .paf/.gaf/.sam or .bam filename [omit or - for standard input]
     #[clap(default_value = "-")]
     pub mappings: String,
 
-    /// First delete all existing segment mappings from .gfab
+    /// Write the modified .gfab to this filename, leaving the original unchanged
+    #[clap(short)]
+    pub output_gfab: Option<String>,
+
+    /// Name of the set of mappings to add to (e.g. the reference assembly name), which can be
+    /// selected for sub --range and --guess-ranges; omit for the unnamed set
+    #[clap(long, name = "NAME")]
+    pub set: Option<String>,
+
+    /// First delete all existing segment mappings in the same set from .gfab
     #[clap(long)]
     pub replace: bool,
 
@@ -27,6 +40,32 @@ pub struct Opts {
     #[clap(long, default_value = "0")]
     pub length: u64,
 
+    /// Input format [default: by filename extension .sam or .bam, otherwise paf (including gaf)]
+    #[clap(long, name = "FORMAT", possible_values = &["paf", "gaf", "sam", "bam"])]
+    pub format: Option<String>,
+
+    /// Ignore secondary mappings (SAM flag 0x100 or PAF tp:A:S)
+    #[clap(long)]
+    pub no_secondary: bool,
+
+    /// Ignore supplementary mappings (SAM flag 0x800)
+    #[clap(long)]
+    pub no_supplementary: bool,
+
+    /// Ignore secondary and supplementary mappings
+    #[clap(long)]
+    pub primary_only: bool,
+
+    /// Ignore mappings with lower identity (PAF residue matches / alignment block length, or from
+    /// SAM NM:i), including mappings lacking this information
+    #[clap(long, name = "F", default_value = "0")]
+    pub min_identity: f64,
+
+    /// Keep at most N of each segment's mappings, preferring higher quality then longer alignment
+    /// block
+    #[clap(long, name = "N")]
+    pub max_per_segment: Option<usize>,
+
     /// Treat mappings' query names as text segment names even if they look like integer IDs
     #[clap(long)]
     pub always_names: bool,
@@ -35,6 +74,11 @@ pub struct Opts {
     #[clap(long)]
     pub ignore_unknown: bool,
 
+    /// GAF target paths are through the segments of this graph (instead of the .gfab being
+    /// modified), used to look up their lengths
+    #[clap(long, name = "GFAB")]
+    pub gaf_graph: Option<String>,
+
     /// log extra progress reports
     #[clap(short, long)]
     pub verbose: bool,
@@ -46,28 +90,74 @@ pub struct Opts {
 
 pub fn main(opts: &Opts) -> Result<()> {
     if opts.mappings == "-" && atty::is(atty::Stream::Stdin) {
-        bad_command!("pipe in .paf data or supply uncompressed filename")
+        bad_command!("pipe in .paf/.gaf/.sam/.bam data or supply filename")
     }
 
     // formulate GenomicSQLite configuration JSON
     let mut dbopts = json::object::Object::new();
     dbopts.insert("unsafe_load", json::JsonValue::from(true));
 
+    // copy the .gfab to the output filename, if any, to modify in its stead
+    let output = match &opts.output_gfab {
+        Some(output_gfab) => {
+            if opts.gfab.starts_with("http:") || opts.gfab.starts_with("https:") {
+                bad_command!("download the .gfab to add mappings to it")
+            }
+            if !output_gfab.ends_with(".gfab") {
+                warn!("output filename should end in .gfab")
+            }
+            util::url_or_extant_file(&opts.gfab)?;
+            let output = util::OutputFile::new(output_gfab)?;
+            debug!("copying {} to {} ...", &opts.gfab, &output.temp_filename);
+            fs::copy(&opts.gfab, &output.temp_filename)?;
+            Some(output)
+        }
+        None => None,
+    };
+
     // open db
     let (_gfab_version, mut db) = util::open_gfab(
-        &opts.gfab,
+        output
+            .as_ref()
+            .map_or(&opts.gfab, |output| &output.temp_filename),
         OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_NO_MUTEX,
         &dbopts,
     )?;
+    let gaf_schema = match &opts.gaf_graph {
+        Some(gaf_graph) => {
+            let mut dbopts_gaf = json::object::Object::new();
+            dbopts_gaf.insert("immutable", json::JsonValue::from(true));
+            util::url_or_extant_file(gaf_graph)?;
+            let attach_sql = db.genomicsqlite_attach_sql(gaf_graph, "gaf", &dbopts_gaf)?;
+            db.execute_batch(&attach_sql)?;
+            let gfab_version = util::check_gfab_schema(&db, "gaf.")?;
+            util::check_gfab_version(&gfab_version)?;
+            "gaf."
+        }
+        None => "",
+    };
 
     {
         // open transaction & apply schema
         let txn = db.transaction()?;
-        insert_paf(&txn, &opts)?;
-        debug!("flushing {} ...", &opts.gfab);
+        let notes = insert_mappings(&txn, opts, gaf_schema)?;
+        let mut inputs = vec![opts.mappings.as_str()];
+        inputs.extend(opts.gaf_graph.as_deref());
+        if opts.output_gfab.is_some() {
+            inputs.insert(0, &opts.gfab)
+        }
+        util::record_provenance(&txn, &inputs, Some(&notes))?;
+        debug!(
+            "flushing {} ...",
+            opts.output_gfab.as_ref().unwrap_or(&opts.gfab)
+        );
         txn.commit()?;
     }
 
+    if let Some(output) = output {
+        db.close().map_err(|(_, e)| e)?;
+        output.commit()?
+    }
     Ok(())
 }
 
@@ -75,141 +165,991 @@ macro_rules! invalid_paf {
     ($($arg:tt)*) => (util::Error::InvalidPaf(format!($($arg)*)))
 }
 
-pub fn insert_paf(db: &rusqlite::Connection, opts: &Opts) -> Result<()> {
-    // create temp table
-    db.execute_batch(
-        "CREATE TABLE temp.segment_mapping_hold(
-            segment_id INTEGER NOT NULL,
-            refseq_name TEXT NOT NULL COLLATE UINT,
-            refseq_begin INTEGER NOT NULL,
-            refseq_end INTEGER NOT NULL,
-            tags_json TEXT
-        );",
-    )?;
+// A mapping record read from PAF, GAF, SAM, or BAM
+struct Mapping {
+    query_name: String,
+    query_begin: u64,
+    query_end: u64,
+    reverse: bool,
+    target_name: String,
+    // PAF/GAF target length, if known
+    target_length: Option<u64>,
+    target_begin: u64,
+    target_end: u64,
+    // alignment block length & quality (255 if unavailable), and residue matches if known
+    block_length: u64,
+    quality: u64,
+    matches: Option<u64>,
+    secondary: bool,
+    supplementary: bool,
+    // CIGAR operations (PAF/GAF cg:Z or SAM/BAM), if available
+    cigar: Option<Vec<(u64, char)>>,
+}
 
-    let mut segment_id_check =
-        db.prepare("SELECT segment_id FROM gfa1_segment_meta WHERE segment_id = ?")?;
-    let mut segment_name_to_id =
-        db.prepare("SELECT segment_id FROM gfa1_segment_meta WHERE name = ?")?;
-    let mut insert_mapping = db.prepare("INSERT INTO temp.segment_mapping_hold(segment_id, refseq_name, refseq_begin, refseq_end, tags_json) VALUES(?,?,?,?,?)")?;
-
-    // iterate tsv records
-    let mut insert_count = 0;
-    let mut all_count = 0;
-    let mut unknown_count = 0;
-    let insert_paf1 = |line_num, tsv: &Vec<&str>| -> Result<()> {
-        all_count += 1;
-        if tsv.len() < 12 {
-            return Err(invalid_paf!("malformed PAF line {}", line_num));
-        }
+// A mapping of (part of) a segment onto a reference sequence (or a segment of a graph, for GAF)
+struct MappingPiece {
+    refseq_name: String,
+    refseq_begin: u64,
+    refseq_end: u64,
+    segment_begin: u64,
+    segment_end: u64,
+    reverse: bool,
+    // compact CIGAR (see compact_cigar)
+    cigar: Option<String>,
+}
+
+// GAF path step: target segment name, orientation, length
+type GafStep = (String, bool, u64);
+
+// Filters mapping records and buffers them into temp.segment_mapping_hold
+struct MappingInserter<'a> {
+    opts: &'a Opts,
+    gaf_schema: &'a str,
+    segment_id_check: rusqlite::Statement<'a>,
+    segment_name_to_id: rusqlite::Statement<'a>,
+    insert_mapping: rusqlite::Statement<'a>,
+    gaf_segment_query: rusqlite::Statement<'a>,
+    gaf_segments: HashMap<String, (String, u64)>,
+    insert_count: u64,
+    piece_count: u64,
+    all_count: u64,
+    unknown_count: u64,
+    no_identity_count: u64,
+}
+
+impl<'a> MappingInserter<'a> {
+    fn new(db: &'a rusqlite::Connection, opts: &'a Opts, gaf_schema: &'a str) -> Result<Self> {
+        Ok(MappingInserter {
+            opts,
+            gaf_schema,
+            segment_id_check: db
+                .prepare("SELECT segment_id FROM gfa1_segment_meta WHERE segment_id = ?")?,
+            segment_name_to_id: db
+                .prepare("SELECT segment_id FROM gfa1_segment_meta WHERE name = ?")?,
+            insert_mapping: db.prepare("INSERT INTO temp.segment_mapping_hold(segment_id, refseq_name, refseq_begin, refseq_end, tags_json, cigar, record_id, quality, block_length) VALUES(?,?,?,?,?,?,?,?,?)")?,
+            gaf_segment_query: db.prepare(&format!(
+                "SELECT coalesce(name, cast(segment_id AS TEXT)), sequence_length
+                 FROM {}gfa1_segment_meta WHERE segment_id = ?1 OR name = ?2",
+                gaf_schema
+            ))?,
+            gaf_segments: HashMap::new(),
+            insert_count: 0,
+            piece_count: 0,
+            all_count: 0,
+            unknown_count: 0,
+            no_identity_count: 0,
+        })
+    }
+
+    // insert the mapping (from the given record/line number) if it passes filters
+    fn insert(&mut self, line_num: usize, mapping: &Mapping) -> Result<()> {
+        let opts = self.opts;
         // check if mapping passes filters
-        if opts.length > 0 {
-            let aln_length: u64 = tsv[10].parse().map_err(|_| {
-                invalid_paf!(
-                    "(Ln {}) malformed alignment block length: {}",
-                    line_num,
-                    tsv[10]
-                )
-            })?;
-            if aln_length < opts.length {
-                return Ok(());
-            }
+        if mapping.block_length < opts.length
+            || mapping.quality < opts.quality
+            || (mapping.secondary && (opts.no_secondary || opts.primary_only))
+            || (mapping.supplementary && (opts.no_supplementary || opts.primary_only))
+        {
+            return Ok(());
         }
-        if opts.quality > 0 {
-            let map_q: u64 = tsv[11]
-                .parse()
-                .map_err(|_| invalid_paf!("(Ln {}) malformed mapQ: {}", line_num, tsv[11]))?;
-            if map_q < opts.quality {
-                return Ok(());
+        let identity = match mapping.matches {
+            Some(matches) if mapping.block_length > 0 => {
+                Some(matches as f64 / mapping.block_length as f64)
+            }
+            _ => None,
+        };
+        if opts.min_identity > 0.0 {
+            match identity {
+                Some(identity) if identity < opts.min_identity => return Ok(()),
+                Some(_) => (),
+                None => {
+                    self.no_identity_count += 1;
+                    return Ok(());
+                }
             }
         }
         // look up segment ID
+        let query_name = mapping.query_name.as_str();
         let mut maybe_segment_id = None;
         if !opts.always_names {
-            if let Some(id) = load::name_to_id(tsv[0]) {
+            if let Some(id) = load::name_to_id(query_name) {
                 maybe_segment_id = Some(id)
             }
         }
         if let Some(id) = maybe_segment_id {
-            maybe_segment_id = segment_id_check
+            maybe_segment_id = self
+                .segment_id_check
                 .query_row(params![id], |row| row.get(0))
                 .optional()?
         } else {
-            maybe_segment_id = segment_name_to_id
-                .query_row(params![tsv[0]], |row| row.get(0))
+            maybe_segment_id = self
+                .segment_name_to_id
+                .query_row(params![query_name], |row| row.get(0))
                 .optional()?
         }
-        if maybe_segment_id.is_none() {
-            if opts.ignore_unknown {
-                unknown_count += 1;
+        let segment_id: i64 = match maybe_segment_id {
+            Some(id) => id,
+            None if opts.ignore_unknown => {
+                self.unknown_count += 1;
                 return Ok(());
             }
-            return Err(invalid_paf!("query name isn't a known segment: {}", tsv[0]));
-        }
-        let segment_id = maybe_segment_id.unwrap();
-        let segment_begin: u64 = tsv[2]
-            .parse()
-            .map_err(|_| invalid_paf!("(Ln {}) malformed query start: {}", line_num, tsv[2]))?;
-        let segment_end: u64 = tsv[3]
-            .parse()
-            .map_err(|_| invalid_paf!("(Ln {}) malformed query end: {}", line_num, tsv[3]))?;
-        // parse target range
-        let target_name = tsv[5];
-        // TODO: handle GAF path if target_name starts with '>' or '<'
-        let target_begin: u64 = tsv[7]
-            .parse()
-            .map_err(|_| invalid_paf!("(Ln {}) malformed target start: {}", line_num, tsv[7]))?;
-        let target_end: u64 = tsv[8]
-            .parse()
-            .map_err(|_| invalid_paf!("(Ln {}) malformed target end: {}", line_num, tsv[8]))?;
-        if target_begin > target_end {
+            None => {
+                return Err(invalid_paf!(
+                    "query name isn't a known segment: {}",
+                    query_name
+                ))
+            }
+        };
+        if mapping.target_begin > mapping.target_end {
             return Err(invalid_paf!(
                 "(Ln {}) target begin > end: {} > {}",
                 line_num,
-                target_begin,
-                target_end
-            ))?;
-        }
-        // prepare tags
-        let mut tags_json = json::object::Object::new();
-        tags_json.insert("sb:i", json::JsonValue::from(segment_begin));
-        tags_json.insert("se:i", json::JsonValue::from(segment_end));
-        tags_json.insert("so:Z", json::JsonValue::from(tsv[4]));
-        // insert into temp table
-        insert_mapping.execute(params![
-            segment_id,
-            target_name,
-            target_begin as i64,
-            target_end as i64,
-            tags_json.dump()
-        ])?;
-        insert_count += 1;
+                mapping.target_begin,
+                mapping.target_end
+            ));
+        }
+
+        let target_name = mapping.target_name.as_str();
+        let pieces = if target_name.starts_with('>') || target_name.starts_with('<') {
+            // GAF: resolve the oriented path steps & split the mapping onto them
+            let steps = self.gaf_steps(line_num, target_name)?;
+            let path_length: u64 = steps.iter().map(|step| step.2).sum();
+            if mapping.target_length != Some(path_length) || mapping.target_end > path_length {
+                return Err(invalid_paf!(
+                    "(Ln {}) GAF path length {} inconsistent with its segments in graph ({})",
+                    line_num,
+                    mapping
+                        .target_length
+                        .map_or(String::from("*"), |n| n.to_string()),
+                    path_length
+                ));
+            }
+            split_gaf_mapping(
+                &steps,
+                mapping.target_begin,
+                mapping.target_end,
+                mapping.query_begin,
+                mapping.query_end,
+                mapping.reverse,
+                mapping.cigar.as_deref(),
+            )
+            .map_err(|msg| invalid_paf!("(Ln {}) {}", line_num, msg))?
+        } else {
+            if let Some(ops) = &mapping.cigar {
+                if cigar_lengths(ops)
+                    != (
+                        mapping.target_end - mapping.target_begin,
+                        mapping.query_end - mapping.query_begin,
+                    )
+                {
+                    return Err(invalid_paf!(
+                        "(Ln {}) CIGAR inconsistent with mapping coordinates",
+                        line_num
+                    ));
+                }
+            }
+            vec![MappingPiece {
+                refseq_name: String::from(target_name),
+                refseq_begin: mapping.target_begin,
+                refseq_end: mapping.target_end,
+                segment_begin: mapping.query_begin,
+                segment_end: mapping.query_end,
+                reverse: mapping.reverse,
+                cigar: mapping.cigar.as_deref().map(compact_cigar),
+            }]
+        };
+        for piece in pieces {
+            // prepare tags
+            let mut tags_json = json::object::Object::new();
+            tags_json.insert("sb:i", json::JsonValue::from(piece.segment_begin));
+            tags_json.insert("se:i", json::JsonValue::from(piece.segment_end));
+            tags_json.insert(
+                "so:Z",
+                json::JsonValue::from(if piece.reverse { "-" } else { "+" }),
+            );
+            if mapping.quality != 255 {
+                tags_json.insert("mq:i", json::JsonValue::from(mapping.quality));
+            }
+            if let Some(identity) = identity {
+                tags_json.insert(
+                    "id:f",
+                    json::JsonValue::from((identity * 10000.0).round() / 10000.0),
+                );
+            }
+            tags_json.insert(
+                "tp:A",
+                json::JsonValue::from(if mapping.secondary { "S" } else { "P" }),
+            );
+            // insert into temp table
+            self.insert_mapping.execute(params![
+                segment_id,
+                piece.refseq_name,
+                piece.refseq_begin as i64,
+                piece.refseq_end as i64,
+                tags_json.dump(),
+                piece.cigar,
+                self.insert_count as i64,
+                mapping.quality as i64,
+                mapping.block_length as i64
+            ])?;
+            self.piece_count += 1;
+        }
+        self.insert_count += 1;
         Ok(())
+    }
+
+    // look up the (name, orientation, length) of each segment in the GAF path
+    fn gaf_steps(&mut self, line_num: usize, path: &str) -> Result<Vec<GafStep>> {
+        let mut steps: Vec<GafStep> = Vec::new();
+        for (reverse, name) in parse_gaf_path(path) {
+            if !self.gaf_segments.contains_key(name) {
+                let maybe_id = load::name_to_id(name).filter(|_| !self.opts.always_names);
+                let found: Option<(String, Option<i64>)> = self
+                    .gaf_segment_query
+                    .query_row(params![maybe_id, name], |row| {
+                        Ok((row.get(0)?, row.get(1)?))
+                    })
+                    .optional()?;
+                match found {
+                    Some((found_name, Some(length))) => {
+                        self.gaf_segments
+                            .insert(String::from(name), (found_name, length as u64));
+                    }
+                    Some(_) => {
+                        return Err(invalid_paf!(
+                            "(Ln {}) GAF path segment lacks sequence length: {}",
+                            line_num,
+                            name
+                        ))
+                    }
+                    None => {
+                        return Err(invalid_paf!(
+                            "(Ln {}) GAF path segment not found in graph{}: {}",
+                            line_num,
+                            if self.gaf_schema.is_empty() {
+                                " (see --gaf-graph)"
+                            } else {
+                                ""
+                            },
+                            name
+                        ))
+                    }
+                }
+            }
+            let (found_name, length) = &self.gaf_segments[name];
+            steps.push((found_name.clone(), reverse, *length))
+        }
+        Ok(steps)
+    }
+}
+
+// Returns a summary of the insertions for the provenance log
+pub fn insert_mappings(db: &rusqlite::Connection, opts: &Opts, gaf_schema: &str) -> Result<String> {
+    let has_sets = util::column_exists(db, "", "gfa1_segment_mapping", "mapping_set")?;
+    if opts.set.is_some() && !has_sets {
+        bad_command!("run `gfabase upgrade` on the .gfab to use mapping sets")
+    }
+    // create temp table
+    db.execute_batch(
+        "CREATE TABLE temp.segment_mapping_hold(
+            segment_id INTEGER NOT NULL,
+            refseq_name TEXT NOT NULL COLLATE UINT,
+            refseq_begin INTEGER NOT NULL,
+            refseq_end INTEGER NOT NULL,
+            tags_json TEXT,
+            cigar TEXT,
+            record_id INTEGER NOT NULL,
+            quality INTEGER NOT NULL,
+            block_length INTEGER NOT NULL
+        );",
+    )?;
+
+    let format = match &opts.format {
+        Some(format) => format.as_str(),
+        None if opts.mappings.ends_with(".sam") => "sam",
+        None if opts.mappings.ends_with(".bam") => "bam",
+        None => "paf",
     };
-    util::iter_tsv_no_comments(insert_paf1, &opts.mappings, Some('#' as u8))?;
-    if unknown_count > 0 {
+    let mut inserter = MappingInserter::new(db, opts, gaf_schema)?;
+    match format {
+        "sam" => util::iter_tsv_no_comments(
+            |line_num, tsv| {
+                inserter.all_count += 1;
+                match parse_sam(line_num, tsv)? {
+                    Some(mapping) => inserter.insert(line_num, &mapping),
+                    None => Ok(()),
+                }
+            },
+            &opts.mappings,
+            Some(b'@'),
+        )?,
+        "bam" => read_bam(&opts.mappings, |record_num, mapping| {
+            inserter.all_count += 1;
+            match mapping {
+                Some(mapping) => inserter.insert(record_num, &mapping),
+                None => Ok(()),
+            }
+        })?,
+        _ => util::iter_tsv_no_comments(
+            |line_num, tsv| {
+                inserter.all_count += 1;
+                let mapping = parse_paf(line_num, tsv, opts)?;
+                inserter.insert(line_num, &mapping)
+            },
+            &opts.mappings,
+            Some(b'#'),
+        )?,
+    }
+    if inserter.unknown_count > 0 {
         warn!(
             "ignored {} mappings with unknown query names",
-            unknown_count
+            inserter.unknown_count
+        )
+    }
+    if inserter.no_identity_count > 0 {
+        warn!(
+            "ignored {} mappings lacking identity information for --min-identity",
+            inserter.no_identity_count
         )
     }
+    let (insert_count, all_count, piece_count) = (
+        inserter.insert_count,
+        inserter.all_count,
+        inserter.piece_count,
+    );
+    std::mem::drop(inserter);
+
+    if let Some(max_per_segment) = opts.max_per_segment {
+        // keep the best mapping records (and all their pieces) for each segment
+        let deleted = db.execute(
+            "DELETE FROM temp.segment_mapping_hold WHERE (segment_id, record_id) IN
+                (SELECT segment_id, record_id FROM
+                    (SELECT segment_id, record_id,
+                        row_number() OVER (
+                            PARTITION BY segment_id
+                            ORDER BY nullif(quality, 255) DESC NULLS LAST, block_length DESC, record_id
+                        ) AS record_rank
+                     FROM (SELECT DISTINCT segment_id, record_id, quality, block_length
+                           FROM temp.segment_mapping_hold))
+                 WHERE record_rank > ?)",
+            params![max_per_segment as i64],
+        )?;
+        if deleted > 0 {
+            debug!(
+                "dropped {} mapping pieces exceeding --max-per-segment",
+                deleted
+            )
+        }
+    }
 
     // delete existing mappings if desired
-    debug!("buffered {} of {} mappings", insert_count, all_count);
+    debug!(
+        "buffered {} of {} mappings ({} pieces)",
+        insert_count, all_count, piece_count
+    );
+    let mut deleted = 0;
     if opts.replace {
-        let deleted = db.execute("DELETE FROM gfa1_segment_mapping", [])?;
+        deleted = if has_sets {
+            db.execute(
+                "DELETE FROM gfa1_segment_mapping WHERE mapping_set IS ?",
+                params![opts.set],
+            )?
+        } else {
+            db.execute("DELETE FROM gfa1_segment_mapping", [])?
+        };
         if deleted > 0 {
             warn!("deleted {} existing mappings", deleted)
         }
     }
-    // sort temp table into gfab
+    // sort temp table into gfab (omitting CIGARs if the .gfab predates them)
+    let cigar_column = if util::column_exists(db, "", "gfa1_segment_mapping", "cigar")? {
+        ", cigar"
+    } else {
+        if db
+            .query_row(
+                "SELECT 1 FROM temp.segment_mapping_hold WHERE cigar IS NOT NULL LIMIT 1",
+                [],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some()
+        {
+            warn!("omitting mapping CIGARs; run `gfabase upgrade` on the .gfab to store them")
+        }
+        ""
+    };
     debug!("sorting mappings...");
-    db.execute_batch(
-        "INSERT INTO gfa1_segment_mapping(segment_id, refseq_name, refseq_begin, refseq_end, tags_json)
-            SELECT segment_id, refseq_name, refseq_begin, refseq_end, tags_json
+    let sql = format!(
+        "INSERT INTO gfa1_segment_mapping(segment_id, refseq_name, refseq_begin, refseq_end, tags_json{}{})
+            SELECT segment_id, refseq_name, refseq_begin, refseq_end, tags_json{}{}
             FROM temp.segment_mapping_hold NOT INDEXED
             ORDER BY refseq_name COLLATE UINT, refseq_begin, refseq_end",
-    )?;
+        cigar_column,
+        if has_sets { ", mapping_set" } else { "" },
+        cigar_column,
+        if has_sets { ", ?1" } else { "" }
+    );
+    if has_sets {
+        db.execute(&sql, params![opts.set])?;
+    } else {
+        db.execute(&sql, [])?;
+    }
     info!("inserted {} of {} mappings", insert_count, all_count);
-    return Ok(());
+    let mut notes = format!("inserted {} of {} mappings", insert_count, all_count);
+    if deleted > 0 {
+        notes = format!("deleted {} existing mappings; {}", deleted, notes)
+    }
+    Ok(notes)
+}
+
+fn parse_paf(line_num: usize, tsv: &[&str], opts: &Opts) -> Result<Mapping> {
+    if tsv.len() < 12 {
+        return Err(invalid_paf!("malformed PAF line {}", line_num));
+    }
+    let parse = |i: usize, what: &str| -> Result<u64> {
+        tsv[i]
+            .parse()
+            .map_err(|_| invalid_paf!("(Ln {}) malformed {}: {}", line_num, what, tsv[i]))
+    };
+    Ok(Mapping {
+        query_name: String::from(tsv[0]),
+        query_begin: parse(2, "query start")?,
+        query_end: parse(3, "query end")?,
+        reverse: tsv[4] == "-",
+        target_name: String::from(tsv[5]),
+        target_length: tsv[6].parse().ok(),
+        target_begin: parse(7, "target start")?,
+        target_end: parse(8, "target end")?,
+        // parse these strictly only if needed for filtering
+        block_length: if opts.length > 0 || opts.min_identity > 0.0 {
+            parse(10, "alignment block length")?
+        } else {
+            tsv[10].parse().unwrap_or(0)
+        },
+        quality: if opts.quality > 0 {
+            parse(11, "mapQ")?
+        } else {
+            tsv[11].parse().unwrap_or(255)
+        },
+        matches: tsv[9].parse().ok(),
+        secondary: tsv[12..].contains(&"tp:A:S"),
+        supplementary: false,
+        cigar: match tsv[12..].iter().find_map(|tag| tag.strip_prefix("cg:Z:")) {
+            Some(cigar) => Some(
+                parse_cigar(cigar)
+                    .ok_or_else(|| invalid_paf!("(Ln {}) malformed cg:Z {}", line_num, cigar))?,
+            ),
+            None => None,
+        },
+    })
+}
+
+// SAM FLAG bits
+const SAM_UNMAPPED: u64 = 0x4;
+const SAM_REVERSE: u64 = 0x10;
+const SAM_SECONDARY: u64 = 0x100;
+const SAM_SUPPLEMENTARY: u64 = 0x800;
+
+// Derive the query & target coordinates of a SAM/BAM alignment from its CIGAR operations. The
+// query coordinates are on the forward strand of the query sequence (as in PAF). The residue
+// matches are counted from =/X operations if present, otherwise derived from the NM:i edit
+// distance, if available.
+fn sam_mapping(
+    query_name: &str,
+    flag: u64,
+    target_name: &str,
+    pos: u64,
+    mapq: u64,
+    cigar_ops: &[(u64, char)],
+    nm: Option<u64>,
+) -> std::result::Result<Mapping, String> {
+    let (mut leading_clip, mut trailing_clip) = (0, 0);
+    let (mut query_consumed, mut target_consumed, mut block_length) = (0, 0, 0);
+    let (mut eq_matches, mut has_eq_ops) = (0, false);
+    for (i, &(n, op)) in cigar_ops.iter().enumerate() {
+        match op {
+            'M' | '=' | 'X' => {
+                query_consumed += n;
+                target_consumed += n;
+                block_length += n;
+                if op != 'M' {
+                    has_eq_ops = true;
+                    if op == '=' {
+                        eq_matches += n
+                    }
+                }
+            }
+            'I' => {
+                query_consumed += n;
+                block_length += n
+            }
+            'D' => {
+                target_consumed += n;
+                block_length += n
+            }
+            'N' => target_consumed += n,
+            'S' | 'H' if query_consumed == 0 && i < cigar_ops.len() - 1 => leading_clip += n,
+            'S' | 'H' => trailing_clip += n,
+            'P' => (),
+            _ => return Err(format!("invalid CIGAR operation {}", op)),
+        }
+    }
+    let reverse = flag & SAM_REVERSE != 0;
+    let query_begin = if reverse { trailing_clip } else { leading_clip };
+    Ok(Mapping {
+        query_name: String::from(query_name),
+        query_begin,
+        query_end: query_begin + query_consumed,
+        reverse,
+        target_name: String::from(target_name),
+        target_length: None,
+        target_begin: pos,
+        target_end: pos + target_consumed,
+        block_length,
+        quality: mapq,
+        matches: if has_eq_ops {
+            Some(eq_matches)
+        } else {
+            nm.map(|nm| block_length.saturating_sub(nm))
+        },
+        secondary: flag & SAM_SECONDARY != 0,
+        supplementary: flag & SAM_SUPPLEMENTARY != 0,
+        cigar: Some(cigar_ops.to_vec()),
+    })
+}
+
+// Parse SAM line; None if unmapped
+fn parse_sam(line_num: usize, tsv: &[&str]) -> Result<Option<Mapping>> {
+    if tsv.len() < 11 {
+        return Err(invalid_paf!("malformed SAM line {}", line_num));
+    }
+    let parse = |i: usize, what: &str| -> Result<u64> {
+        tsv[i]
+            .parse()
+            .map_err(|_| invalid_paf!("(Ln {}) malformed SAM {}: {}", line_num, what, tsv[i]))
+    };
+    let flag = parse(1, "FLAG")?;
+    if flag & SAM_UNMAPPED != 0 || tsv[2] == "*" || tsv[5] == "*" {
+        return Ok(None);
+    }
+    let pos = parse(3, "POS")?;
+    if pos == 0 {
+        return Ok(None);
+    }
+    let cigar_ops = parse_cigar(tsv[5])
+        .ok_or_else(|| invalid_paf!("(Ln {}) malformed SAM CIGAR: {}", line_num, tsv[5]))?;
+    let nm = tsv[11..]
+        .iter()
+        .find_map(|tag| tag.strip_prefix("NM:i:"))
+        .and_then(|nm| nm.parse().ok());
+    let mapping = sam_mapping(
+        tsv[0],
+        flag,
+        tsv[2],
+        pos - 1,
+        parse(4, "MAPQ")?,
+        &cigar_ops,
+        nm,
+    )
+    .map_err(|msg| invalid_paf!("(Ln {}) {}", line_num, msg))?;
+    Ok(Some(mapping))
+}
+
+// Read BAM records (decompressing BGZF in-process), passing each mapping (or None if unmapped) to
+// the callback along with the record number.
+fn read_bam<F>(filename: &str, mut f: F) -> Result<()>
+where
+    F: FnMut(usize, Option<Mapping>) -> Result<()>,
+{
+    let input: Box<dyn io::Read> = if filename.is_empty() || filename == "-" {
+        Box::new(io::stdin())
+    } else {
+        Box::new(fs::File::open(filename)?)
+    };
+    let mut reader = io::BufReader::new(flate2::read::MultiGzDecoder::new(input));
+
+    // header
+    let mut magic = [0u8; 4];
+    reader
+        .read_exact(&mut magic)
+        .map_err(|_| invalid_paf!("not BAM format"))?;
+    if &magic != b"BAM\x01" {
+        return Err(invalid_paf!("not BAM format"));
+    }
+    let l_text = read_i32(&mut reader)?;
+    io::copy(&mut (&mut reader).take(l_text as u64), &mut io::sink())?;
+    let n_ref = read_i32(&mut reader)?;
+    let mut refseq_names = Vec::new();
+    for _ in 0..n_ref {
+        let l_name = read_i32(&mut reader)? as usize;
+        let mut name = vec![0u8; l_name];
+        reader.read_exact(&mut name)?;
+        refseq_names.push(String::from_utf8_lossy(&name[..l_name.saturating_sub(1)]).into_owned());
+        read_i32(&mut reader)?; // l_ref
+    }
+
+    // records
+    let mut record_num = 0;
+    let mut block = Vec::new();
+    loop {
+        let mut block_size = [0u8; 4];
+        match reader.read_exact(&mut block_size) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+        record_num += 1;
+        let block_size = i32::from_le_bytes(block_size);
+        if !(BAM_RECORD_MIN_SIZE..=BAM_RECORD_MAX_SIZE).contains(&block_size) {
+            return Err(invalid_paf!(
+                "(BAM record {}) invalid block_size {}",
+                record_num,
+                block_size
+            ));
+        }
+        block.resize(block_size as usize, 0);
+        reader
+            .read_exact(&mut block)
+            .map_err(|_| invalid_paf!("truncated BAM record {}", record_num))?;
+        let mapping = parse_bam_record(&block, &refseq_names)
+            .map_err(|msg| invalid_paf!("(BAM record {}) {}", record_num, msg))?;
+        f(record_num, mapping)?
+    }
+    Ok(())
+}
+
+fn read_i32(reader: &mut impl io::Read) -> Result<i32> {
+    let mut buf = [0u8; 4];
+    reader
+        .read_exact(&mut buf)
+        .map_err(|_| invalid_paf!("truncated BAM header"))?;
+    Ok(i32::from_le_bytes(buf))
+}
+
+// a record's fixed-length fields take 32 bytes; even an ultra-long read's record is far below 1GiB
+const BAM_RECORD_MIN_SIZE: i32 = 32;
+const BAM_RECORD_MAX_SIZE: i32 = 1 << 30;
+const BAM_CIGAR_OPS: &[u8; 9] = b"MIDNSHP=X";
+
+fn parse_bam_record(
+    block: &[u8],
+    refseq_names: &[String],
+) -> std::result::Result<Option<Mapping>, String> {
+    let truncated = || String::from("truncated record");
+    let u32_at = |i: usize| -> std::result::Result<u32, String> {
+        block
+            .get(i..i + 4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .ok_or_else(truncated)
+    };
+    let u16_at = |i: usize| -> std::result::Result<u16, String> {
+        block
+            .get(i..i + 2)
+            .map(|b| u16::from_le_bytes([b[0], b[1]]))
+            .ok_or_else(truncated)
+    };
+    let ref_id = u32_at(0)? as i32;
+    let pos = u32_at(4)? as i32;
+    let l_read_name = *block.get(8).ok_or_else(truncated)? as usize;
+    let mapq = *block.get(9).ok_or_else(truncated)? as u64;
+    let n_cigar_op = u16_at(12)? as usize;
+    let flag = u16_at(14)? as u64;
+    let l_seq = u32_at(16)? as usize;
+    if flag & SAM_UNMAPPED != 0 || ref_id < 0 || pos < 0 || n_cigar_op == 0 {
+        return Ok(None);
+    }
+    let refseq_name = refseq_names
+        .get(ref_id as usize)
+        .ok_or_else(|| format!("invalid refID {}", ref_id))?;
+    let read_name_bytes = block.get(32..32 + l_read_name).ok_or_else(truncated)?;
+    let read_name = String::from_utf8_lossy(&read_name_bytes[..l_read_name.saturating_sub(1)]);
+    let cigar_offset = 32 + l_read_name;
+    let mut cigar: Vec<u32> = (0..n_cigar_op)
+        .map(|k| u32_at(cigar_offset + 4 * k))
+        .collect::<std::result::Result<_, _>>()?;
+
+    // scan the auxiliary tags for NM:i, and CG:B:I if the CIGAR is a placeholder kSmN (CIGARs
+    // with >65535 operations are stored there)
+    let long_cigar = n_cigar_op == 2 && cigar[0] & 0xf == 4 && (cigar[0] >> 4) as usize == l_seq;
+    let mut nm = None;
+    let mut i = cigar_offset + 4 * n_cigar_op + l_seq.div_ceil(2) + l_seq;
+    while i + 3 <= block.len() {
+        let (tag, ty) = (&block[i..i + 2], block[i + 2]);
+        i += 3;
+        let size = match ty {
+            b'A' | b'c' | b'C' => 1,
+            b's' | b'S' => 2,
+            b'i' | b'I' | b'f' => 4,
+            b'Z' | b'H' => {
+                let end = block[i..]
+                    .iter()
+                    .position(|&b| b == 0)
+                    .ok_or_else(truncated)?;
+                end + 1
+            }
+            b'B' => {
+                let sub_size = match block.get(i).ok_or_else(truncated)? {
+                    b'c' | b'C' => 1,
+                    b's' | b'S' => 2,
+                    _ => 4,
+                };
+                let count = u32_at(i + 1)? as usize;
+                if tag == b"CG" && long_cigar {
+                    cigar = (0..count)
+                        .map(|k| u32_at(i + 5 + 4 * k))
+                        .collect::<std::result::Result<_, _>>()?;
+                }
+                5 + sub_size * count
+            }
+            _ => return Err(format!("invalid tag type {}", ty as char)),
+        };
+        if tag == b"NM" {
+            nm = match ty {
+                b'c' | b'C' => block.get(i).map(|&b| b as u64),
+                b's' | b'S' => u16_at(i).ok().map(|n| n as u64),
+                b'i' | b'I' => u32_at(i).ok().map(|n| n as u64),
+                _ => None,
+            }
+        }
+        i += size
+    }
+
+    let cigar_ops: Vec<(u64, char)> = cigar
+        .iter()
+        .map(|op| {
+            BAM_CIGAR_OPS
+                .get((op & 0xf) as usize)
+                .map(|&ch| ((op >> 4) as u64, ch as char))
+                .ok_or_else(|| format!("invalid CIGAR operation {}", op & 0xf))
+        })
+        .collect::<std::result::Result<_, _>>()?;
+    sam_mapping(
+        &read_name,
+        flag,
+        refseq_name,
+        pos as u64,
+        mapq,
+        &cigar_ops,
+        nm,
+    )
+    .map(Some)
+}
+
+// Parse GAF path e.g. >s1<s2>s3 into (reverse, segment name) steps
+fn parse_gaf_path(path: &str) -> Vec<(bool, &str)> {
+    let mut steps = Vec::new();
+    let mut rest = path;
+    while !rest.is_empty() {
+        let reverse = rest.starts_with('<');
+        let name_end = rest[1..].find(['>', '<']).map_or(rest.len(), |i| i + 1);
+        steps.push((reverse, &rest[1..name_end]));
+        rest = &rest[name_end..]
+    }
+    steps
+}
+
+// Split a mapping of [query_begin, query_end) onto [path_begin, path_end) of a GAF path into pieces
+// on each path segment, in the segment's forward coordinates. The corresponding query offsets
+// follow the CIGAR if available (which is also split among the pieces), otherwise are interpolated
+// linearly.
+fn split_gaf_mapping(
+    steps: &[GafStep],
+    path_begin: u64,
+    path_end: u64,
+    query_begin: u64,
+    query_end: u64,
+    reverse: bool,
+    cigar: Option<&[(u64, char)]>,
+) -> std::result::Result<Vec<MappingPiece>, String> {
+    // path offsets of the step boundaries within the mapping, relative to path_begin
+    let mut step_ranges = Vec::new();
+    let mut boundaries = Vec::new();
+    let mut pos = 0;
+    for (i, (_, _, length)) in steps.iter().enumerate() {
+        let (lo, hi) = (pos.max(path_begin), (pos + length).min(path_end));
+        if lo < hi {
+            step_ranges.push((i, pos, lo, hi));
+            boundaries.push(lo - path_begin);
+        }
+        pos += length
+    }
+    boundaries.push(path_end - path_begin);
+
+    let query_length = query_end - query_begin;
+    let query_offsets = match cigar {
+        Some(ops) => {
+            if cigar_lengths(ops) != (path_end - path_begin, query_length) {
+                return Err(String::from("cg:Z inconsistent with mapping coordinates"));
+            }
+            cigar_query_offsets(ops, &boundaries)
+        }
+        None => boundaries
+            .iter()
+            .map(|&t| {
+                if path_end > path_begin {
+                    (t as f64 * query_length as f64 / (path_end - path_begin) as f64).round() as u64
+                } else {
+                    0
+                }
+            })
+            .collect(),
+    };
+
+    Ok(step_ranges
+        .iter()
+        .enumerate()
+        .map(|(j, &(i, step_begin, lo, hi))| {
+            let (name, step_reverse, length) = &steps[i];
+            let (offset_lo, offset_hi) = (lo - step_begin, hi - step_begin);
+            let (refseq_begin, refseq_end) = if *step_reverse {
+                (length - offset_hi, length - offset_lo)
+            } else {
+                (offset_lo, offset_hi)
+            };
+            let (q_lo, q_hi) = (query_offsets[j], query_offsets[j + 1]);
+            let (segment_begin, segment_end) = if reverse {
+                (query_end - q_hi, query_end - q_lo)
+            } else {
+                (query_begin + q_lo, query_begin + q_hi)
+            };
+            // the piece of the CIGAR, reversed to the segment's forward orientation if needed
+            let piece_cigar = cigar.map(|ops| {
+                let mut piece_ops = slice_cigar(
+                    ops,
+                    boundaries[j],
+                    boundaries[j + 1],
+                    j + 1 == step_ranges.len(),
+                );
+                if *step_reverse {
+                    piece_ops.reverse()
+                }
+                compact_cigar(&piece_ops)
+            });
+            MappingPiece {
+                refseq_name: name.clone(),
+                refseq_begin,
+                refseq_end,
+                segment_begin,
+                segment_end,
+                reverse: reverse != *step_reverse,
+                cigar: piece_cigar,
+            }
+        })
+        .collect())
+}
+
+/// Parse CIGAR string into (length, operation) pairs
+pub fn parse_cigar(cigar: &str) -> Option<Vec<(u64, char)>> {
+    let mut ans = Vec::new();
+    let mut num = String::new();
+    for ch in cigar.chars() {
+        if ch.is_ascii_digit() {
+            num.push(ch)
+        } else {
+            if !"MIDNSHP=X".contains(ch) {
+                return None;
+            }
+            ans.push((num.parse().ok()?, ch));
+            num.clear()
+        }
+    }
+    if !num.is_empty() {
+        return None;
+    }
+    Some(ans)
+}
+
+/// Compact form of CIGAR stored in gfa1_segment_mapping: only M (including = and X), I, and D
+/// (including N) operations, omitting clipping & padding and merging adjacent operations. The
+/// target is the forward refseq range and the query is the segment range, reverse-complemented if
+/// so:Z:- (as in PAF & SAM).
+pub fn compact_cigar(ops: &[(u64, char)]) -> String {
+    let mut compact: Vec<(u64, char)> = Vec::new();
+    for &(n, op) in ops {
+        let op = match op {
+            'M' | '=' | 'X' => 'M',
+            'D' | 'N' => 'D',
+            'I' => 'I',
+            _ => continue,
+        };
+        match compact.last_mut() {
+            Some(last) if last.1 == op => last.0 += n,
+            _ if n > 0 => compact.push((n, op)),
+            _ => (),
+        }
+    }
+    compact
+        .iter()
+        .map(|(n, op)| format!("{}{}", n, op))
+        .collect()
+}
+
+// (target, query) lengths spanned by the CIGAR, excluding clipping
+fn cigar_lengths(ops: &[(u64, char)]) -> (u64, u64) {
+    let (mut tc, mut qc) = (0, 0);
+    for &(n, op) in ops {
+        match op {
+            'M' | '=' | 'X' => {
+                tc += n;
+                qc += n
+            }
+            'D' | 'N' => tc += n,
+            'I' => qc += n,
+            _ => (),
+        }
+    }
+    (tc, qc)
+}
+
+// Query offsets (from the alignment start) corresponding to the given ascending target offsets,
+// following the CIGAR. Insertions at a boundary are attributed to the following piece.
+fn cigar_query_offsets(ops: &[(u64, char)], boundaries: &[u64]) -> Vec<u64> {
+    let mut ans = Vec::new();
+    let (mut tc, mut qc) = (0, 0);
+    for &(n, op) in ops {
+        let (consumes_target, consumes_query) = match op {
+            'M' | '=' | 'X' => (true, true),
+            'D' | 'N' => (true, false),
+            'I' => (false, true),
+            _ => (false, false),
+        };
+        while ans.len() < boundaries.len() && boundaries[ans.len()] <= tc {
+            ans.push(qc)
+        }
+        if consumes_target {
+            while ans.len() < boundaries.len() && boundaries[ans.len()] < tc + n {
+                let t = boundaries[ans.len()];
+                ans.push(if consumes_query { qc + (t - tc) } else { qc })
+            }
+            tc += n
+        }
+        if consumes_query {
+            qc += n
+        }
+    }
+    while ans.len() < boundaries.len() {
+        ans.push(qc)
+    }
+    ans
+}
+
+// The CIGAR operations covering target offsets [lo, hi), consistent with cigar_query_offsets in
+// attributing insertions at lo to this piece, and at hi to the following one (unless last).
+fn slice_cigar(ops: &[(u64, char)], lo: u64, hi: u64, last: bool) -> Vec<(u64, char)> {
+    let mut ans = Vec::new();
+    let mut tc = 0;
+    for &(n, op) in ops {
+        match op {
+            'M' | '=' | 'X' | 'D' | 'N' => {
+                let (a, b) = (tc.max(lo), (tc + n).min(hi));
+                if a < b {
+                    ans.push((b - a, op))
+                }
+                tc += n
+            }
+            'I' if lo <= tc && (tc < hi || (last && tc == hi)) => ans.push((n, op)),
+            _ => (),
+        }
+    }
+    ans
 }
@@ -48,19 +48,76 @@ pub fn index(db: &rusqlite::Connection) -> Result<()> {
         }
     }
 
+    index_walks(db)?;
+
+    db.execute_batch(
+        "CREATE INDEX gfa1_connectivity_component ON gfa1_connectivity(component_id);
+         CREATE INDEX gfa1_walk_connectivity_component ON gfa1_walk_connectivity(component_id)",
+    )?;
+    Ok(())
+}
+
+// (Re)populate gfa1_walk_connectivity from gfa1_connectivity
+pub fn index_walks(db: &rusqlite::Connection) -> Result<()> {
     // index each Walk to the associated connected component. By definition, all segments in a Walk
     // must be in one connected component, so it suffices just to look up one exemplar segment.
     // Also, checking all of them would be costly.
     db.execute_batch(
-        "INSERT INTO gfa1_walk_connectivity(walk_id,component_id)
+        "DELETE FROM gfa1_walk_connectivity;
+         INSERT INTO gfa1_walk_connectivity(walk_id,component_id)
          SELECT walk_id, component_id
          FROM gfa1_walk INNER JOIN gfa1_connectivity ON gfa1_walk.min_segment_id = gfa1_connectivity.segment_id"
     )?;
+    Ok(())
+}
 
+// Fallback for a .gfab lacking the connectivity index: compute connectivity just for the
+// component(s) reachable from the segments in temp.start_segments, storing the results in
+// temp.reachable_connectivity (same columns as gfa1_connectivity).
+pub fn index_reachable(db: &rusqlite::Connection, schema: &str) -> Result<()> {
     db.execute_batch(
-        "CREATE INDEX gfa1_connectivity_component ON gfa1_connectivity(component_id);
-         CREATE INDEX gfa1_walk_connectivity_component ON gfa1_walk_connectivity(component_id)",
+        "CREATE TABLE temp.reachable_connectivity(
+            segment_id INTEGER PRIMARY KEY,
+            component_id INTEGER NOT NULL,
+            is_cutpoint INTEGER NOT NULL
+        )",
+    )?;
+
+    let mut neighbors = db.prepare(&format!(
+        "  SELECT from_segment FROM {s}gfa1_link WHERE to_segment = ?1 AND from_segment != ?1
+         UNION
+           SELECT to_segment FROM {s}gfa1_link WHERE from_segment = ?1 AND to_segment != ?1",
+        s = schema
+    ))?;
+    let mut insert = db.prepare(
+        "INSERT INTO temp.reachable_connectivity(segment_id,component_id,is_cutpoint) VALUES(?,?,?)",
     )?;
+    let mut visited_query =
+        db.prepare("SELECT 1 FROM temp.reachable_connectivity WHERE segment_id = ?")?;
+    // use a bloom filter in front of visited_query
+    let approx_segment_count: i64 = db.query_row(
+        &format!(
+            "SELECT coalesce(max(segment_id),100000) FROM {}gfa1_segment_meta",
+            schema
+        ),
+        [],
+        |row| row.get(0),
+    )?;
+    let mut visited_bloom = Bloom::new_for_fp_rate(approx_segment_count as usize, 0.05);
+
+    let mut start_segments = db.prepare("SELECT segment_id FROM temp.start_segments")?;
+    let mut start_segments_cursor = start_segments.query([])?;
+    while let Some(segrow) = start_segments_cursor.next()? {
+        let segment_id: i64 = segrow.get(0)?;
+        if !(visited_bloom.check(&segment_id)
+            && visited_query
+                .query_row(params!(segment_id), |_| Ok(()))
+                .optional()?
+                .is_some())
+        {
+            component_dfs(segment_id, &mut neighbors, &mut insert, &mut visited_bloom)?
+        }
+    }
     Ok(())
 }
 
@@ -0,0 +1,691 @@
+use clap::Clap;
+use genomicsqlite::ConnectionMethods;
+use log::info;
+use rusqlite::types::ValueRef;
+use rusqlite::{params, OpenFlags, OptionalExtension};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+
+use crate::util::Result;
+use crate::{util, validate, view};
+
+#[derive(Clap)]
+pub struct Opts {
+    /// old .gfab filename or http[s] URL
+    pub old_gfab: String,
+
+    /// new .gfab filename or http[s] URL
+    pub new_gfab: String,
+
+    /// Also write GFA of only the added/changed records from the new .gfab to this filename [- for
+    /// standard output]
+    #[clap(short)]
+    pub output_gfa: Option<String>,
+
+    /// Maximum number of examples to print per category
+    #[clap(long, default_value = "10")]
+    pub max_examples: usize,
+
+    /// Memory budget (GiB)
+    #[clap(long, default_value = "1")]
+    pub memory_gbytes: u32,
+
+    /// log extra progress reports
+    #[clap(short, long)]
+    pub verbose: bool,
+
+    /// log errors only
+    #[clap(short, long)]
+    pub quiet: bool,
+}
+
+// Differences found in one category of records
+struct Changes {
+    description: &'static str,
+    count: usize,
+    examples: Vec<String>,
+    max_examples: usize,
+}
+
+impl Changes {
+    fn new(description: &'static str, max_examples: usize) -> Changes {
+        Changes {
+            description,
+            count: 0,
+            examples: Vec::new(),
+            max_examples,
+        }
+    }
+
+    // count one difference, formulating its description only if it'll be printed as an example
+    fn add<F: FnOnce() -> Result<String>>(&mut self, describe: F) -> Result<()> {
+        self.count += 1;
+        if self.examples.len() < self.max_examples {
+            self.examples.push(describe()?)
+        }
+        Ok(())
+    }
+
+    fn print(&self) {
+        println!("{}\t{}", self.description, self.count);
+        for example in &self.examples {
+            println!("\t{}", example);
+        }
+        if self.count > self.examples.len() {
+            println!("\t...");
+        }
+    }
+}
+
+pub fn main(opts: &Opts) -> Result<()> {
+    let mut dbopts = json::object::Object::new();
+    dbopts.insert("immutable", json::JsonValue::from(true));
+    dbopts.insert(
+        "page_cache_MiB",
+        json::JsonValue::from(opts.memory_gbytes * 400),
+    );
+
+    // open new .gfab as main, and attach old
+    util::url_or_extant_file(&opts.old_gfab)?;
+    let (_gfab_version, db) = util::open_gfab(
+        &opts.new_gfab,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        &dbopts,
+    )?;
+    let attach_sql = db.genomicsqlite_attach_sql(&opts.old_gfab, "old", &dbopts)?;
+    db.execute_batch(&attach_sql)?;
+    let old_gfab_version = util::check_gfab_schema(&db, "old.")?;
+    util::check_gfab_version(&old_gfab_version)?;
+
+    // temp tables collecting the added/changed records of the new .gfab
+    db.execute_batch(
+        "CREATE TABLE temp.diff_segments(segment_id INTEGER PRIMARY KEY);
+         CREATE TABLE temp.diff_links(link_id INTEGER PRIMARY KEY);
+         CREATE TABLE temp.diff_paths(path_id INTEGER PRIMARY KEY);
+         CREATE TABLE temp.diff_walks(walk_id INTEGER PRIMARY KEY)",
+    )?;
+
+    info!("comparing segments ...");
+    let (mut changes, segment_map) = diff_segments(&db, opts.max_examples)?;
+    info!("comparing links ...");
+    changes.append(&mut diff_links(&db, &segment_map, opts.max_examples)?);
+    info!("comparing paths ...");
+    changes.append(&mut diff_paths(&db, &segment_map, opts.max_examples)?);
+    info!("comparing walks ...");
+    let (mut walk_changes, walk_changes_by_sample) =
+        diff_walks(&db, &segment_map, opts.max_examples)?;
+    changes.append(&mut walk_changes);
+
+    let mut total = 0;
+    for category in &changes {
+        category.print();
+        total += category.count;
+    }
+    for (sample, [added, removed, changed]) in &walk_changes_by_sample {
+        println!(
+            "walks of sample {}\t{} added, {} removed, {} changed",
+            sample, added, removed, changed
+        );
+    }
+
+    if let Some(output_gfa) = &opts.output_gfa {
+        let mut writer_box = view::writer(output_gfa)?;
+        let out = &mut *writer_box;
+        view::write_header(&db, out)?;
+        view::write_segments(
+            &db,
+            "WHERE segment_id IN (SELECT segment_id FROM temp.diff_segments)",
+            true,
+            |_, _| Ok(()),
+            out,
+        )?;
+        view::write_links(
+            &db,
+            "WHERE gfa1_link._rowid_ IN (SELECT link_id FROM temp.diff_links)",
+            out,
+        )?;
+        view::write_paths(
+            &db,
+            "WHERE path_id IN (SELECT path_id FROM temp.diff_paths)",
+            out,
+        )?;
+        view::write_walks(
+            &db,
+            "WHERE walk_id IN (SELECT walk_id FROM temp.diff_walks)",
+            out,
+        )?;
+        out.flush()?
+    }
+
+    if total == 0 {
+        info!("🗹 no differences");
+    }
+    Ok(())
+}
+
+fn blob_hash(sequence: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    sequence.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn prepare_old_name_query<'a>(db: &'a rusqlite::Connection) -> Result<rusqlite::Statement<'a>> {
+    Ok(db.prepare(
+        "SELECT coalesce(name, cast(segment_id AS TEXT)) FROM old.gfa1_segment_meta WHERE segment_id = ?",
+    )?)
+}
+
+// Match old segments to new ones, first by name, then by identical sequence. Returns the change
+// categories & the old => new segment ID map.
+fn diff_segments(
+    db: &rusqlite::Connection,
+    max_examples: usize,
+) -> Result<(Vec<Changes>, HashMap<i64, i64>)> {
+    let mut added = Changes::new("segments added", max_examples);
+    let mut removed = Changes::new("segments removed", max_examples);
+    let mut renamed = Changes::new("segments renamed (matched by sequence)", max_examples);
+    let mut changed = Changes::new("segments changed", max_examples);
+    let mut insert_diff = db.prepare("INSERT INTO temp.diff_segments(segment_id) VALUES(?)")?;
+
+    // match by name
+    let mut old_by_name = HashMap::new();
+    {
+        let mut old_query = db.prepare(
+            "SELECT segment_id, coalesce(name, cast(segment_id AS TEXT)) FROM old.gfa1_segment_meta",
+        )?;
+        let mut old_cursor = old_query.query([])?;
+        while let Some(row) = old_cursor.next()? {
+            let name: String = row.get(1)?;
+            old_by_name.insert(name, row.get::<usize, i64>(0)?);
+        }
+    }
+    let mut segment_map = HashMap::new();
+    let mut unmatched_new = Vec::new();
+    {
+        let mut new_query = db.prepare(
+            "SELECT segment_id, coalesce(name, cast(segment_id AS TEXT)) FROM main.gfa1_segment_meta",
+        )?;
+        let mut new_cursor = new_query.query([])?;
+        while let Some(row) = new_cursor.next()? {
+            let new_id: i64 = row.get(0)?;
+            let name: String = row.get(1)?;
+            match old_by_name.remove(&name) {
+                Some(old_id) => {
+                    segment_map.insert(old_id, new_id);
+                }
+                None => unmatched_new.push((new_id, name)),
+            }
+        }
+    }
+
+    // compare the name-matched segments
+    db.execute_batch(
+        "CREATE TABLE temp.segment_match(old_id INTEGER PRIMARY KEY, new_id INTEGER NOT NULL)",
+    )?;
+    {
+        let mut insert_match =
+            db.prepare("INSERT INTO temp.segment_match(old_id, new_id) VALUES(?,?)")?;
+        for (old_id, new_id) in &segment_map {
+            insert_match.execute(params![old_id, new_id])?;
+        }
+    }
+    let mut changed_query = db.prepare(
+        "SELECT * FROM
+            (SELECT
+                new_id, coalesce(n.name, cast(n.segment_id AS TEXT)),
+                o.sequence_length AS old_length, n.sequence_length AS new_length,
+                (SELECT sequence_twobit FROM old.gfa1_segment_sequence WHERE segment_id = old_id)
+                    IS NOT
+                    (SELECT sequence_twobit FROM main.gfa1_segment_sequence WHERE segment_id = new_id)
+                    AS sequence_changed,
+                coalesce(o.tags_json, '{}') != coalesce(n.tags_json, '{}') AS tags_changed
+             FROM temp.segment_match
+                INNER JOIN old.gfa1_segment_meta AS o ON o.segment_id = old_id
+                INNER JOIN main.gfa1_segment_meta AS n ON n.segment_id = new_id)
+         WHERE sequence_changed OR tags_changed OR old_length IS NOT new_length",
+    )?;
+    let mut changed_cursor = changed_query.query([])?;
+    while let Some(row) = changed_cursor.next()? {
+        let new_id: i64 = row.get(0)?;
+        let name: String = row.get(1)?;
+        let old_length: Option<i64> = row.get(2)?;
+        let new_length: Option<i64> = row.get(3)?;
+        let sequence_changed: bool = row.get(4)?;
+        let tags_changed: bool = row.get(5)?;
+        changed.add(|| {
+            let mut what = Vec::new();
+            if old_length != new_length {
+                let fmt = |len: Option<i64>| len.map_or(String::from("*"), |l| l.to_string());
+                what.push(format!("length {} -> {}", fmt(old_length), fmt(new_length)))
+            }
+            if sequence_changed {
+                what.push(String::from("sequence"))
+            }
+            if tags_changed {
+                what.push(String::from("tags"))
+            }
+            Ok(format!("{}: {}", name, what.join(", ")))
+        })?;
+        insert_diff.execute(params![new_id])?;
+    }
+
+    // match the remaining segments by identical sequence: in either orientation if both files have
+    // the (orientation-independent) sequence hashes, otherwise by identical stored sequence value
+    let use_sequence_hash =
+        util::column_exists(db, "old", "gfa1_segment_sequence", "sequence_hash")?
+            && util::column_exists(db, "main", "gfa1_segment_sequence", "sequence_hash")?;
+    let (key_column, identical_sql) = if use_sequence_hash {
+        (
+            "sequence_hash",
+            "SELECT o.dna = n.dna, o.dna = dna_revcomp(n.dna) FROM
+                (SELECT upper(twobit_dna(sequence_twobit)) AS dna
+                 FROM old.gfa1_segment_sequence WHERE segment_id = ?1) AS o,
+                (SELECT upper(twobit_dna(sequence_twobit)) AS dna
+                 FROM main.gfa1_segment_sequence WHERE segment_id = ?2) AS n",
+        )
+    } else {
+        (
+            "sequence_twobit",
+            "SELECT o.sequence_twobit = n.sequence_twobit, 0
+             FROM old.gfa1_segment_sequence AS o, main.gfa1_segment_sequence AS n
+             WHERE o.segment_id = ?1 AND n.segment_id = ?2",
+        )
+    };
+    let mut old_key_query = db.prepare(&format!(
+        "SELECT {} FROM old.gfa1_segment_sequence WHERE segment_id = ?",
+        key_column
+    ))?;
+    let mut new_key_query = db.prepare(&format!(
+        "SELECT {} FROM main.gfa1_segment_sequence WHERE segment_id = ?",
+        key_column
+    ))?;
+    let mut identical_query = db.prepare(identical_sql)?;
+    let mut old_by_sequence: HashMap<i64, Vec<(i64, String)>> = HashMap::new();
+    let mut unmatched_old: BTreeMap<i64, String> = BTreeMap::new();
+    for (name, old_id) in old_by_name {
+        let mut cursor = old_key_query.query(params![old_id])?;
+        if let Some(row) = cursor.next()? {
+            if let Some(key) = sequence_key(row.get_ref(0)?) {
+                old_by_sequence
+                    .entry(key)
+                    .or_default()
+                    .push((old_id, name.clone()));
+            }
+        }
+        unmatched_old.insert(old_id, name);
+    }
+    for (new_id, new_name) in unmatched_new {
+        let mut matched = None;
+        {
+            let mut cursor = new_key_query.query(params![new_id])?;
+            if let Some(row) = cursor.next()? {
+                if let Some(key) = sequence_key(row.get_ref(0)?) {
+                    if let Some(candidates) = old_by_sequence.get_mut(&key) {
+                        for i in 0..candidates.len() {
+                            let (identical, revcomp) = identical_query
+                                .query_row(params![candidates[i].0, new_id], |row| {
+                                    Ok((row.get::<usize, bool>(0)?, row.get::<usize, bool>(1)?))
+                                })
+                                .optional()?
+                                .unwrap_or((false, false));
+                            if identical || revcomp {
+                                matched = Some((candidates.swap_remove(i), revcomp && !identical));
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        match matched {
+            Some(((old_id, old_name), revcomp)) => {
+                segment_map.insert(old_id, new_id);
+                unmatched_old.remove(&old_id);
+                renamed.add(|| {
+                    Ok(format!(
+                        "{} -> {}{}",
+                        old_name,
+                        new_name,
+                        if revcomp { " (reverse complement)" } else { "" }
+                    ))
+                })?;
+            }
+            None => added.add(|| Ok(new_name))?,
+        }
+        insert_diff.execute(params![new_id])?;
+    }
+    for old_name in unmatched_old.into_values() {
+        removed.add(|| Ok(old_name))?
+    }
+
+    Ok((vec![added, removed, renamed, changed], segment_map))
+}
+
+// Key for matching segments by sequence: the stored sequence_hash, or else a hash of the stored
+// sequence value
+fn sequence_key(value: ValueRef<'_>) -> Option<i64> {
+    match value {
+        ValueRef::Integer(hash) => Some(hash),
+        ValueRef::Blob(bytes) | ValueRef::Text(bytes) if !bytes.is_empty() => {
+            Some(blob_hash(bytes) as i64)
+        }
+        _ => None,
+    }
+}
+
+// (from_segment, from_reverse, to_segment, to_reverse)
+type LinkKey = (i64, bool, i64, bool);
+
+// Choose one of the two equivalent bidirected forms of a link, reversing its CIGAR if flipped.
+// Absent & "*" CIGARs are treated alike.
+fn canonical_link(key: LinkKey, maybe_cigar: Option<String>) -> (LinkKey, Option<String>) {
+    let cigar = maybe_cigar.filter(|c| c != "*");
+    let (from_segment, from_reverse, to_segment, to_reverse) = key;
+    let flipped = (to_segment, !to_reverse, from_segment, !from_reverse);
+    if flipped < key {
+        (flipped, cigar.map(|c| validate::reverse_cigar(&c)))
+    } else {
+        (key, cigar)
+    }
+}
+
+fn diff_links(
+    db: &rusqlite::Connection,
+    segment_map: &HashMap<i64, i64>,
+    max_examples: usize,
+) -> Result<Vec<Changes>> {
+    let mut added = Changes::new("links added", max_examples);
+    let mut removed = Changes::new("links removed", max_examples);
+    let mut changed = Changes::new("links changed", max_examples);
+    let mut insert_diff = db.prepare("INSERT INTO temp.diff_links(link_id) VALUES(?)")?;
+    let mut old_name_query = prepare_old_name_query(db)?;
+    let mut new_name_query = validate::prepare_name_query(db)?;
+
+    // load old links, keyed by their new segment IDs
+    let mut old_links = HashMap::new();
+    {
+        let mut old_query = db.prepare(
+            "SELECT from_segment, from_reverse, to_segment, to_reverse, cigar, coalesce(tags_json, '{}')
+             FROM old.gfa1_link",
+        )?;
+        let mut old_cursor = old_query.query([])?;
+        while let Some(row) = old_cursor.next()? {
+            let from_segment: i64 = row.get(0)?;
+            let from_reverse: bool = row.get(1)?;
+            let to_segment: i64 = row.get(2)?;
+            let to_reverse: bool = row.get(3)?;
+            let tags_json: String = row.get(5)?;
+            match (segment_map.get(&from_segment), segment_map.get(&to_segment)) {
+                (Some(new_from), Some(new_to)) => {
+                    let (key, cigar) =
+                        canonical_link((*new_from, from_reverse, *new_to, to_reverse), row.get(4)?);
+                    old_links.insert(key, (cigar, tags_json));
+                }
+                _ => removed.add(|| {
+                    Ok(format!(
+                        "{} -> {}",
+                        validate::oriented_segment_name(
+                            &mut old_name_query,
+                            from_segment,
+                            from_reverse
+                        )?,
+                        validate::oriented_segment_name(
+                            &mut old_name_query,
+                            to_segment,
+                            to_reverse
+                        )?
+                    ))
+                })?,
+            }
+        }
+    }
+
+    let mut new_query = db.prepare(
+        "SELECT _rowid_, from_segment, from_reverse, to_segment, to_reverse, cigar, coalesce(tags_json, '{}')
+         FROM main.gfa1_link",
+    )?;
+    let mut new_cursor = new_query.query([])?;
+    while let Some(row) = new_cursor.next()? {
+        let link_id: i64 = row.get(0)?;
+        let from = (row.get(1)?, row.get(2)?);
+        let to = (row.get(3)?, row.get(4)?);
+        let tags_json: String = row.get(6)?;
+        let (key, cigar) = canonical_link((from.0, from.1, to.0, to.1), row.get(5)?);
+        let mut describe = || -> Result<String> {
+            Ok(format!(
+                "{} -> {}",
+                validate::oriented_segment_name(&mut new_name_query, from.0, from.1)?,
+                validate::oriented_segment_name(&mut new_name_query, to.0, to.1)?
+            ))
+        };
+        match old_links.remove(&key) {
+            None => added.add(describe)?,
+            Some((old_cigar, old_tags_json)) => {
+                if old_cigar == cigar && old_tags_json == tags_json {
+                    continue;
+                }
+                changed.add(|| {
+                    Ok(describe()?
+                        + if old_cigar != cigar {
+                            ": overlap"
+                        } else {
+                            ": tags"
+                        })
+                })?
+            }
+        }
+        insert_diff.execute(params![link_id])?;
+    }
+    for (from_segment, from_reverse, to_segment, to_reverse) in old_links.into_keys() {
+        removed.add(|| {
+            Ok(format!(
+                "{} -> {}",
+                validate::oriented_segment_name(&mut new_name_query, from_segment, from_reverse)?,
+                validate::oriented_segment_name(&mut new_name_query, to_segment, to_reverse)?
+            ))
+        })?
+    }
+
+    Ok(vec![added, removed, changed])
+}
+
+// path element: (segment_id, reverse, cigar_vs_previous), with old segment IDs mapped to new
+// (None if the segment was removed)
+type PathElement = (Option<i64>, bool, Option<String>);
+
+fn diff_paths(
+    db: &rusqlite::Connection,
+    segment_map: &HashMap<i64, i64>,
+    max_examples: usize,
+) -> Result<Vec<Changes>> {
+    let mut added = Changes::new("paths added", max_examples);
+    let mut removed = Changes::new("paths removed", max_examples);
+    let mut changed = Changes::new("paths changed", max_examples);
+    let mut insert_diff = db.prepare("INSERT INTO temp.diff_paths(path_id) VALUES(?)")?;
+
+    let mut old_paths = HashMap::new();
+    {
+        let mut old_query = db.prepare(
+            "SELECT path_id, coalesce(name, cast(path_id AS TEXT)), coalesce(tags_json, '{}')
+             FROM old.gfa1_path",
+        )?;
+        let mut old_cursor = old_query.query([])?;
+        while let Some(row) = old_cursor.next()? {
+            let name: String = row.get(1)?;
+            old_paths.insert(
+                name,
+                (row.get::<usize, i64>(0)?, row.get::<usize, String>(2)?),
+            );
+        }
+    }
+
+    let mut old_elements_query = db.prepare(
+        "SELECT segment_id, reverse, cigar_vs_previous FROM old.gfa1_path_element
+         WHERE path_id = ? ORDER BY ordinal",
+    )?;
+    let mut new_elements_query = db.prepare(
+        "SELECT segment_id, reverse, cigar_vs_previous FROM main.gfa1_path_element
+         WHERE path_id = ? ORDER BY ordinal",
+    )?;
+    let elements = |query: &mut rusqlite::Statement,
+                    path_id: i64,
+                    map: Option<&HashMap<i64, i64>>|
+     -> Result<Vec<PathElement>> {
+        let mut ans = Vec::new();
+        let mut cursor = query.query(params![path_id])?;
+        while let Some(row) = cursor.next()? {
+            let segment_id: i64 = row.get(0)?;
+            ans.push((
+                match map {
+                    Some(map) => map.get(&segment_id).copied(),
+                    None => Some(segment_id),
+                },
+                row.get(1)?,
+                row.get(2)?,
+            ))
+        }
+        Ok(ans)
+    };
+
+    let mut new_query = db.prepare(
+        "SELECT path_id, coalesce(name, cast(path_id AS TEXT)), coalesce(tags_json, '{}')
+         FROM main.gfa1_path ORDER BY path_id",
+    )?;
+    let mut new_cursor = new_query.query([])?;
+    while let Some(row) = new_cursor.next()? {
+        let path_id: i64 = row.get(0)?;
+        let name: String = row.get(1)?;
+        let tags_json: String = row.get(2)?;
+        match old_paths.remove(&name) {
+            None => added.add(|| Ok(name))?,
+            Some((old_path_id, old_tags_json)) => {
+                let elements_changed = elements(&mut new_elements_query, path_id, None)?
+                    != elements(&mut old_elements_query, old_path_id, Some(segment_map))?;
+                let tags_changed = tags_json != old_tags_json;
+                if !elements_changed && !tags_changed {
+                    continue;
+                }
+                changed.add(|| {
+                    Ok(name
+                        + if elements_changed {
+                            ": elements"
+                        } else {
+                            ": tags"
+                        })
+                })?
+            }
+        }
+        insert_diff.execute(params![path_id])?;
+    }
+    let mut old_names: Vec<String> = old_paths.into_keys().collect();
+    old_names.sort();
+    for name in old_names {
+        removed.add(|| Ok(name))?
+    }
+
+    Ok(vec![added, removed, changed])
+}
+
+// (sample, hap_idx, refseq_name, refseq_begin); the sample may be NULL
+type WalkKey = (Option<String>, i64, String, i64);
+
+// per sample: [added, removed, changed]
+type WalkChangesBySample = BTreeMap<String, [usize; 3]>;
+
+fn diff_walks(
+    db: &rusqlite::Connection,
+    segment_map: &HashMap<i64, i64>,
+    max_examples: usize,
+) -> Result<(Vec<Changes>, WalkChangesBySample)> {
+    let mut added = Changes::new("walks added", max_examples);
+    let mut removed = Changes::new("walks removed", max_examples);
+    let mut changed = Changes::new("walks changed", max_examples);
+    let mut by_sample = WalkChangesBySample::new();
+    let mut insert_diff = db.prepare("INSERT INTO temp.diff_walks(walk_id) VALUES(?)")?;
+    let describe = |(sample, hap_idx, refseq_name, refseq_begin): &WalkKey| {
+        format!(
+            "{}#{}#{}:{}",
+            sample.as_deref().unwrap_or("*"),
+            hap_idx,
+            refseq_name,
+            refseq_begin
+        )
+    };
+    let sample_of = |key: &WalkKey| key.0.clone().unwrap_or_else(|| String::from("*"));
+
+    let walks_sql = "SELECT walk_id, sample, hap_idx, refseq_name, refseq_begin, refseq_end,
+                        coalesce(tags_json, '{}')
+                     FROM SCHEMA.gfa1_walk ORDER BY walk_id";
+    let mut old_walks = HashMap::new();
+    {
+        let mut old_query = db.prepare(&walks_sql.replace("SCHEMA.", "old."))?;
+        let mut old_cursor = old_query.query([])?;
+        while let Some(row) = old_cursor.next()? {
+            let key: WalkKey = (row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?);
+            let walk_id: i64 = row.get(0)?;
+            let refseq_end: i64 = row.get(5)?;
+            let tags_json: String = row.get(6)?;
+            old_walks.insert(key, (walk_id, refseq_end, tags_json));
+        }
+    }
+
+    let mut old_iter_walk_query = view::prepare_iter_walk_in(db, "old.")?;
+    let mut new_iter_walk_query = view::prepare_iter_walk_in(db, "main.")?;
+    let mut new_query = db.prepare(&walks_sql.replace("SCHEMA.", "main."))?;
+    let mut new_cursor = new_query.query([])?;
+    while let Some(row) = new_cursor.next()? {
+        let walk_id: i64 = row.get(0)?;
+        let key: WalkKey = (row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?);
+        let refseq_end: i64 = row.get(5)?;
+        let tags_json: String = row.get(6)?;
+        let counts = by_sample.entry(sample_of(&key)).or_insert([0, 0, 0]);
+        match old_walks.remove(&key) {
+            None => {
+                counts[0] += 1;
+                added.add(|| Ok(describe(&key)))?
+            }
+            Some((old_walk_id, old_refseq_end, old_tags_json)) => {
+                let mut new_steps = Vec::new();
+                view::iter_walk(&mut new_iter_walk_query, walk_id, |segment_id, reverse| {
+                    new_steps.push((Some(segment_id), reverse));
+                    Ok(true)
+                })?;
+                let mut old_steps = Vec::new();
+                view::iter_walk(
+                    &mut old_iter_walk_query,
+                    old_walk_id,
+                    |segment_id, reverse| {
+                        old_steps.push((segment_map.get(&segment_id).copied(), reverse));
+                        Ok(true)
+                    },
+                )?;
+                let mut what = Vec::new();
+                if refseq_end != old_refseq_end {
+                    what.push(format!("end {} -> {}", old_refseq_end, refseq_end))
+                }
+                if new_steps != old_steps {
+                    what.push(String::from("steps"))
+                }
+                if tags_json != old_tags_json {
+                    what.push(String::from("tags"))
+                }
+                if what.is_empty() {
+                    continue;
+                }
+                counts[2] += 1;
+                changed.add(|| Ok(format!("{}: {}", describe(&key), what.join(", "))))?
+            }
+        }
+        insert_diff.execute(params![walk_id])?;
+    }
+    let mut old_keys: Vec<WalkKey> = old_walks.into_keys().collect();
+    old_keys.sort();
+    for key in old_keys {
+        by_sample.entry(sample_of(&key)).or_insert([0, 0, 0])[1] += 1;
+        removed.add(|| Ok(describe(&key)))?
+    }
+    by_sample.retain(|_, counts| counts.iter().any(|n| *n > 0));
+
+    Ok((vec![added, removed, changed], by_sample))
+}
@@ -0,0 +1,332 @@
+// Export of (sub)graphs for rendering with other tools, as alternatives to Bandage: Graphviz DOT,
+// GraphML, and Cytoscape.js JSON. Segments become nodes with attributes for the name, sequence
+// length, selected tags, and any tags inserted by the tag editor (e.g. gr:Z guessed ranges). Links
+// become directed edges with attributes for the orientation of each end.
+
+use json::JsonValue;
+use log::warn;
+use std::collections::{BTreeMap, HashMap};
+use std::{fs, io};
+
+use crate::util::Result;
+use crate::{bad_command, util, view};
+
+/// Output formats selectable with `--format` (fasta & gfa are written elsewhere)
+pub const FORMATS: &[&str] = &["gfa", "fasta", "dot", "graphml", "cyjs"];
+
+pub fn writer(filename: &str, format: &str) -> Result<Box<dyn io::Write>> {
+    if filename.is_empty() || filename == "-" {
+        return Ok(Box::new(io::BufWriter::new(io::stdout())));
+    }
+    let suffixes: &[&str] = match format {
+        "dot" => &[".dot", ".gv"],
+        "graphml" => &[".graphml"],
+        "cyjs" => &[".cyjs", ".json"],
+        _ => &[],
+    };
+    if !suffixes.iter().any(|suffix| filename.ends_with(suffix)) {
+        warn!("output filename should end with {}", suffixes.join(" or "))
+    }
+    Ok(Box::new(io::BufWriter::new(fs::File::create(filename)?)))
+}
+
+struct Node {
+    name: String,
+    length: Option<i64>,
+    // attribute name (tag name without type) => value
+    attributes: BTreeMap<String, JsonValue>,
+}
+
+struct Edge {
+    from: String,
+    from_reverse: bool,
+    to: String,
+    to_reverse: bool,
+    cigar: Option<String>,
+}
+
+fn orientation(reverse: bool) -> &'static str {
+    if reverse {
+        "-"
+    } else {
+        "+"
+    }
+}
+
+/// Write the graph of the segments & links selected by the respective WHERE clauses in the given
+/// format (dot, graphml, or cyjs). `tags` is a comma-separated list of segment tags to include.
+pub fn write_graph(
+    db: &rusqlite::Connection,
+    format: &str,
+    segments_where_clause: &str,
+    links_where_clause: &str,
+    tags: &str,
+    mut tag_editor: impl FnMut(i64, &mut JsonValue) -> Result<()>,
+    writer: &mut dyn io::Write,
+) -> Result<()> {
+    let selected_tags: Vec<&str> = tags.split(',').filter(|t| !t.is_empty()).collect();
+
+    let mut nodes = Vec::new();
+    let mut node_names = HashMap::new();
+    let mut segments_query = db.prepare(&format!(
+        "SELECT segment_id, coalesce(name, cast(segment_id AS TEXT)), sequence_length,
+                coalesce(tags_json, '{{}}')
+         FROM gfa1_segment_meta {} ORDER BY segment_id",
+        segments_where_clause
+    ))?;
+    let mut segments_cursor = segments_query.query([])?;
+    while let Some(segrow) = segments_cursor.next()? {
+        let segment_id: i64 = segrow.get(0)?;
+        let name: String = segrow.get(1)?;
+        let tags_json: String = segrow.get(3)?;
+        let tags = json::parse(&tags_json).map_err(|_| util::Error::InvalidGfab {
+            message: String::from("invalid tags_json"),
+            table: String::from("gfa1_segment_meta"),
+            rowid: segment_id,
+        })?;
+        let mut selected = view::select_tags(&selected_tags, None, &tags);
+        tag_editor(segment_id, &mut selected)?;
+        let attributes = selected
+            .entries()
+            .map(|(k, v)| (String::from(k.split(':').next().unwrap()), v.clone()))
+            .collect();
+        node_names.insert(segment_id, name.clone());
+        nodes.push(Node {
+            name,
+            length: segrow.get(2)?,
+            attributes,
+        })
+    }
+
+    let mut edges = Vec::new();
+    let mut links_query = db.prepare(&format!(
+        "SELECT from_segment, from_reverse, to_segment, to_reverse, cigar
+         FROM gfa1_link {} ORDER BY from_segment, to_segment",
+        links_where_clause
+    ))?;
+    let mut links_cursor = links_query.query([])?;
+    let node_name = |segment_id: i64| {
+        node_names
+            .get(&segment_id)
+            .cloned()
+            .unwrap_or_else(|| segment_id.to_string())
+    };
+    while let Some(linkrow) = links_cursor.next()? {
+        edges.push(Edge {
+            from: node_name(linkrow.get(0)?),
+            from_reverse: linkrow.get::<_, i64>(1)? != 0,
+            to: node_name(linkrow.get(2)?),
+            to_reverse: linkrow.get::<_, i64>(3)? != 0,
+            cigar: linkrow.get(4)?,
+        })
+    }
+
+    match format {
+        "dot" => write_dot(&nodes, &edges, writer),
+        "graphml" => write_graphml(&nodes, &edges, writer),
+        "cyjs" => write_cyjs(&nodes, &edges, writer),
+        _ => bad_command!("unknown graph format {}", format),
+    }
+}
+
+fn dot_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn dot_quote(s: &str) -> String {
+    format!("\"{}\"", dot_escape(s))
+}
+
+fn write_dot(nodes: &[Node], edges: &[Edge], writer: &mut dyn io::Write) -> Result<()> {
+    writer.write_all(b"digraph gfa {\n")?;
+    for node in nodes {
+        // multi-line label with name, length, and guessed range
+        let mut label = vec![dot_escape(&node.name)];
+        let mut attributes = vec![(String::from("name"), dot_quote(&node.name))];
+        if let Some(length) = node.length {
+            label.push(format!("{} nt", length));
+            attributes.push((String::from("length"), length.to_string()));
+        }
+        if let Some(gr) = node.attributes.get("gr").and_then(|gr| gr.as_str()) {
+            label.push(dot_escape(gr))
+        }
+        for (k, v) in &node.attributes {
+            attributes.push((
+                k.clone(),
+                match v.as_str() {
+                    Some(s) => dot_quote(s),
+                    None => v.dump(),
+                },
+            ))
+        }
+        attributes.push((String::from("label"), format!("\"{}\"", label.join("\\n"))));
+        writer.write_fmt(format_args!(
+            "  {} [{}];\n",
+            dot_quote(&node.name),
+            attributes
+                .iter()
+                .map(|(k, v)| format!("{}={}", dot_quote(k), v))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))?;
+    }
+    for edge in edges {
+        let orientations = format!(
+            "{}/{}",
+            orientation(edge.from_reverse),
+            orientation(edge.to_reverse)
+        );
+        writer.write_fmt(format_args!(
+            "  {} -> {} [from_orient=\"{}\", to_orient=\"{}\", label=\"{}\"",
+            dot_quote(&edge.from),
+            dot_quote(&edge.to),
+            orientation(edge.from_reverse),
+            orientation(edge.to_reverse),
+            orientations
+        ))?;
+        if let Some(cigar) = &edge.cigar {
+            writer.write_fmt(format_args!(", cigar={}", dot_quote(cigar)))?;
+        }
+        writer.write_all(b"];\n")?;
+    }
+    writer.write_all(b"}\n")?;
+    Ok(())
+}
+
+pub fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn write_graphml(nodes: &[Node], edges: &[Edge], writer: &mut dyn io::Write) -> Result<()> {
+    // declare node attribute keys, typed by their values
+    let mut node_keys = BTreeMap::new();
+    for node in nodes {
+        for (k, v) in &node.attributes {
+            let ty = if v.is_string() {
+                "string"
+            } else if v.as_i64().is_some() {
+                "long"
+            } else {
+                "double"
+            };
+            let key_ty = node_keys.entry(k.clone()).or_insert(ty);
+            if *key_ty != ty {
+                *key_ty = if *key_ty != "string" && ty != "string" {
+                    "double"
+                } else {
+                    "string"
+                }
+            }
+        }
+    }
+
+    writer.write_all(
+        b"<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+          <graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">\n\
+          \x20 <key id=\"name\" for=\"node\" attr.name=\"name\" attr.type=\"string\"/>\n\
+          \x20 <key id=\"length\" for=\"node\" attr.name=\"length\" attr.type=\"long\"/>\n",
+    )?;
+    for (k, ty) in &node_keys {
+        if k != "name" && k != "length" {
+            writer.write_fmt(format_args!(
+                "  <key id=\"{}\" for=\"node\" attr.name=\"{}\" attr.type=\"{}\"/>\n",
+                xml_escape(k),
+                xml_escape(k),
+                ty
+            ))?;
+        }
+    }
+    writer.write_all(
+        b"  <key id=\"from_orient\" for=\"edge\" attr.name=\"from_orient\" attr.type=\"string\"/>\n\
+          \x20 <key id=\"to_orient\" for=\"edge\" attr.name=\"to_orient\" attr.type=\"string\"/>\n\
+          \x20 <key id=\"cigar\" for=\"edge\" attr.name=\"cigar\" attr.type=\"string\"/>\n\
+          \x20 <graph id=\"gfa\" edgedefault=\"directed\">\n",
+    )?;
+    for node in nodes {
+        let name = xml_escape(&node.name);
+        writer.write_fmt(format_args!(
+            "    <node id=\"{}\">\n      <data key=\"name\">{}</data>\n",
+            name, name
+        ))?;
+        if let Some(length) = node.length {
+            writer.write_fmt(format_args!(
+                "      <data key=\"length\">{}</data>\n",
+                length
+            ))?;
+        }
+        for (k, v) in &node.attributes {
+            if k != "name" && k != "length" {
+                writer.write_fmt(format_args!(
+                    "      <data key=\"{}\">{}</data>\n",
+                    xml_escape(k),
+                    xml_escape(&v.as_str().map(String::from).unwrap_or_else(|| v.dump()))
+                ))?;
+            }
+        }
+        writer.write_all(b"    </node>\n")?;
+    }
+    for (i, edge) in edges.iter().enumerate() {
+        writer.write_fmt(format_args!(
+            "    <edge id=\"e{}\" source=\"{}\" target=\"{}\">\n\
+             \x20     <data key=\"from_orient\">{}</data>\n\
+             \x20     <data key=\"to_orient\">{}</data>\n",
+            i,
+            xml_escape(&edge.from),
+            xml_escape(&edge.to),
+            orientation(edge.from_reverse),
+            orientation(edge.to_reverse)
+        ))?;
+        if let Some(cigar) = &edge.cigar {
+            writer.write_fmt(format_args!(
+                "      <data key=\"cigar\">{}</data>\n",
+                xml_escape(cigar)
+            ))?;
+        }
+        writer.write_all(b"    </edge>\n")?;
+    }
+    writer.write_all(b"  </graph>\n</graphml>\n")?;
+    Ok(())
+}
+
+fn write_cyjs(nodes: &[Node], edges: &[Edge], writer: &mut dyn io::Write) -> Result<()> {
+    let mut cy_nodes = JsonValue::new_array();
+    for node in nodes {
+        let mut data = JsonValue::new_object();
+        for (k, v) in &node.attributes {
+            data.insert(k, v.clone()).unwrap()
+        }
+        data.insert("id", node.name.as_str()).unwrap();
+        data.insert("name", node.name.as_str()).unwrap();
+        if let Some(length) = node.length {
+            data.insert("length", length).unwrap()
+        }
+        cy_nodes.push(json::object! { data: data }).unwrap()
+    }
+    let mut cy_edges = JsonValue::new_array();
+    for (i, edge) in edges.iter().enumerate() {
+        let mut data = json::object! {
+            id: format!("e{}", i),
+            source: edge.from.as_str(),
+            target: edge.to.as_str(),
+            from_orient: orientation(edge.from_reverse),
+            to_orient: orientation(edge.to_reverse),
+        };
+        if let Some(cigar) = &edge.cigar {
+            data.insert("cigar", cigar.as_str()).unwrap()
+        }
+        cy_edges.push(json::object! { data: data }).unwrap()
+    }
+    let cyjs = json::object! {
+        elements: {
+            nodes: cy_nodes,
+            edges: cy_edges,
+        }
+    };
+    writer.write_all(cyjs.pretty(2).as_bytes())?;
+    writer.write_all(b"\n")?;
+    Ok(())
+}
@@ -0,0 +1,156 @@
+use clap::Clap;
+use log::{debug, info, log_enabled};
+use rusqlite::{params, OpenFlags, OptionalExtension};
+use std::cmp;
+
+use crate::util::Result;
+use crate::{connectivity, kmers, load, util, view};
+
+#[derive(Clap)]
+pub struct Opts {
+    /// gfab filename (to modify in-place)
+    pub gfab: String,
+
+    /// (Re)build index of graph connectivity
+    #[clap(long)]
+    pub connectivity: bool,
+
+    /// Recompute each Walk's segment ID bounds from its steps (and its connectivity index entry)
+    #[clap(long)]
+    pub walk_steps: bool,
+
+    /// (Re)build genomic range indexes of segment mappings & walks
+    #[clap(long)]
+    pub gri: bool,
+
+    /// (Re)build k-mer index of segment sequences, used by `gfabase sub --query`
+    #[clap(long)]
+    pub kmers: bool,
+
+    /// k-mer length for --kmers (up to 31)
+    #[clap(long, name = "K", default_value = "15")]
+    pub kmer_k: usize,
+
+    /// Window size for --kmers: index the k-mer with the smallest hash among each W consecutive
+    /// k-mers
+    #[clap(long, name = "W", default_value = "10")]
+    pub kmer_w: usize,
+
+    /// Memory budget (GiB)
+    #[clap(long, default_value = "4")]
+    pub memory_gbytes: u32,
+
+    /// log extra progress reports
+    #[clap(short, long)]
+    pub verbose: bool,
+
+    /// log errors only
+    #[clap(short, long)]
+    pub quiet: bool,
+}
+
+pub fn main(opts: &Opts) -> Result<()> {
+    // no index families specified => all of them (except k-mers, unless already present)
+    let all = !(opts.connectivity || opts.walk_steps || opts.gri || opts.kmers);
+
+    let mut dbopts = json::object::Object::new();
+    dbopts.insert("unsafe_load", json::JsonValue::from(true));
+    dbopts.insert(
+        "page_cache_MiB",
+        json::JsonValue::from(cmp::max(1024, opts.memory_gbytes * 400)),
+    );
+
+    let (_gfab_version, mut db) = util::open_gfab(
+        &opts.gfab,
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        &dbopts,
+    )?;
+
+    {
+        let txn = db.transaction()?;
+        info!("indexing...");
+
+        if all || opts.walk_steps {
+            index_walk_steps(&txn)?;
+        }
+        if all || opts.gri {
+            index_gris(&txn)?;
+        }
+        if all || opts.connectivity {
+            debug!("\tindexing graph connectivity ...");
+            txn.execute_batch(
+                "DROP TABLE IF EXISTS gfa1_walk_connectivity;
+                 DROP TABLE IF EXISTS gfa1_connectivity",
+            )?;
+            connectivity::index(&txn)?;
+        } else if opts.walk_steps && connectivity::has_index(&txn, "")? {
+            debug!("\tindexing walk connectivity ...");
+            connectivity::index_walks(&txn)?;
+        }
+
+        if opts.kmers || (all && kmers::has_index(&txn, "")?) {
+            debug!("\tindexing segment sequence k-mers ...");
+            kmers::index(&txn, opts.kmer_k, opts.kmer_w)?;
+        }
+
+        util::record_provenance(&txn, &[], None)?;
+
+        debug!("\tANALYZE ...");
+        txn.execute_batch("PRAGMA analysis_limit = 1000; ANALYZE main")?;
+
+        debug!("flushing {} ...", &opts.gfab);
+        txn.commit()?;
+    }
+
+    if log_enabled!(log::Level::Debug) {
+        load::summary(&db)?;
+    }
+    db.close().map_err(|(_, e)| e)?;
+    info!("🗹 done");
+    Ok(())
+}
+
+// Build each GRI if absent, otherwise REINDEX it
+fn index_gris(db: &rusqlite::Connection) -> Result<()> {
+    debug!("\tindexing segment mappings & walks by genomic range ...");
+    for table in load::GRI_TABLES.iter() {
+        let has_gri = db
+            .query_row(
+                "SELECT 1 FROM sqlite_master WHERE type='index' AND name=?",
+                params![format!("{}__gri", table)],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some();
+        if has_gri {
+            db.execute_batch(&format!("REINDEX {}__gri", table))?;
+        } else {
+            load::create_gri(db, table)?;
+        }
+    }
+    Ok(())
+}
+
+// Recompute gfa1_walk.{min,max}_segment_id by decoding each walk's steps
+pub fn index_walk_steps(db: &rusqlite::Connection) -> Result<()> {
+    debug!("\tindexing walk steps ...");
+    let mut iter_walk_query = view::prepare_iter_walk(db)?;
+    let mut update_walk = db
+        .prepare("UPDATE gfa1_walk SET min_segment_id = ?, max_segment_id = ? WHERE walk_id = ?")?;
+    let mut walks_query = db.prepare("SELECT walk_id FROM gfa1_walk_steps")?;
+    let mut walks_cursor = walks_query.query([])?;
+    while let Some(row) = walks_cursor.next()? {
+        let walk_id: i64 = row.get(0)?;
+        let mut min_segment_id = i64::MAX;
+        let mut max_segment_id = i64::MIN;
+        view::iter_walk(&mut iter_walk_query, walk_id, |segment_id, _| {
+            min_segment_id = cmp::min(min_segment_id, segment_id);
+            max_segment_id = cmp::max(max_segment_id, segment_id);
+            Ok(true)
+        })?;
+        if min_segment_id <= max_segment_id {
+            update_walk.execute(params![min_segment_id, max_segment_id, walk_id])?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,208 @@
+// K-mer index of segment sequences: store the (w,k)-minimizers of each segment sequence, keyed by
+// their canonical hash. A query sequence is searched by looking up its own minimizers as seeds,
+// then extending the seed hits along links, so that a query spanning several segments can be
+// found even if it seeds only weakly in each of them.
+
+use log::{debug, warn};
+use rusqlite::params;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::util::Result;
+use crate::{bad_command, util};
+
+// Ignore minimizers occurring more than this many times in the index (typically from repeats),
+// which are uninformative and expensive to extend.
+const MAX_KMER_OCCURRENCES: usize = 1000;
+
+pub fn index(db: &rusqlite::Connection, k: usize, w: usize) -> Result<()> {
+    if !(1..=31).contains(&k) || w < 1 {
+        bad_command!("k-mer index requires 1 <= k <= 31 and w >= 1");
+    }
+    db.execute_batch(
+        "DROP TABLE IF EXISTS gfa1_segment_kmer;
+         DROP TABLE IF EXISTS gfa1_segment_kmer_params",
+    )?;
+    db.execute_batch(include_str!("schema/GFA1.kmers.sql"))?;
+    db.execute(
+        "INSERT INTO gfa1_segment_kmer_params(k, w) VALUES(?, ?)",
+        params![k as i64, w as i64],
+    )?;
+
+    let mut insert = db.prepare(
+        "INSERT OR IGNORE INTO gfa1_segment_kmer(kmer_hash, segment_id, position) VALUES(?,?,?)",
+    )?;
+    let mut sequences_query =
+        db.prepare("SELECT segment_id, twobit_dna(sequence_twobit) FROM gfa1_segment_sequence")?;
+    let mut sequences_cursor = sequences_query.query([])?;
+    let mut count = 0;
+    while let Some(row) = sequences_cursor.next()? {
+        let segment_id: i64 = row.get(0)?;
+        let sequence: String = row.get(1)?;
+        for (hash, position) in minimizers(sequence.as_bytes(), k, w) {
+            count += insert.execute(params![hash as i64, segment_id, position as i64])?;
+        }
+    }
+    debug!("\tindexed {} k-mers (k = {}, w = {})", count, k, w);
+    Ok(())
+}
+
+pub fn has_index(db: &rusqlite::Connection, schema: &str) -> Result<bool> {
+    util::table_exists(db, schema, "gfa1_segment_kmer_params")
+}
+
+// Thomas Wang's invertible integer hash, scrambling the two-bit encoded k-mers so that the
+// minimizers aren't biased towards poly-A.
+fn hash64(key: u64, mask: u64) -> u64 {
+    let mut key = (!key).wrapping_add(key << 21) & mask;
+    key ^= key >> 24;
+    key = (key.wrapping_add(key << 3)).wrapping_add(key << 8) & mask;
+    key ^= key >> 14;
+    key = (key.wrapping_add(key << 2)).wrapping_add(key << 4) & mask;
+    key ^= key >> 28;
+    key.wrapping_add(key << 31) & mask
+}
+
+// Compute the (w,k)-minimizers of the sequence as (canonical hash, position). K-mers including
+// non-ACGT characters are skipped, and windows don't span them. A stretch with fewer than w
+// k-mers still yields its smallest one (so that short segments & queries are indexed).
+pub fn minimizers(sequence: &[u8], k: usize, w: usize) -> Vec<(u64, usize)> {
+    assert!((1..=31).contains(&k) && w >= 1);
+    let mask: u64 = (1 << (2 * k)) - 1;
+    let shift = 2 * (k as u64 - 1);
+    let mut ans: Vec<(u64, usize)> = Vec::new();
+    let mut emit = |minimizer: (u64, usize)| {
+        if ans.last() != Some(&minimizer) {
+            ans.push(minimizer)
+        }
+    };
+
+    let mut fwd: u64 = 0;
+    let mut rev: u64 = 0;
+    let mut valid = 0; // length of the current stretch of ACGT
+    let mut kmers = 0; // k-mers in the current stretch
+    let mut window: VecDeque<(u64, usize)> = VecDeque::new(); // ascending hashes
+    for (i, ch) in sequence.iter().enumerate() {
+        let code = match ch.to_ascii_uppercase() {
+            b'A' => 0,
+            b'C' => 1,
+            b'G' => 2,
+            b'T' | b'U' => 3,
+            _ => 4,
+        };
+        if code > 3 {
+            if kmers > 0 && kmers < w {
+                emit(window[0])
+            }
+            valid = 0;
+            kmers = 0;
+            window.clear();
+            continue;
+        }
+        fwd = ((fwd << 2) | code) & mask;
+        rev = (rev >> 2) | ((3 - code) << shift);
+        valid += 1;
+        if valid < k {
+            continue;
+        }
+
+        let position = i + 1 - k;
+        let hash = std::cmp::min(hash64(fwd, mask), hash64(rev, mask));
+        while let Some(&(back_hash, _)) = window.back() {
+            if back_hash <= hash {
+                break;
+            }
+            window.pop_back();
+        }
+        window.push_back((hash, position));
+        kmers += 1;
+        if kmers >= w {
+            while window[0].1 + w <= position {
+                window.pop_front();
+            }
+            emit(window[0])
+        }
+    }
+    if kmers > 0 && kmers < w {
+        emit(window[0])
+    }
+    ans
+}
+
+// Search for the query sequence in the k-mer index, returning the segments that it seeds with at
+// least min_seeds distinct minimizers (or all of the query's minimizers, if it has fewer). The
+// seed counts are summed over groups of seeded segments joined by links.
+pub fn search(
+    db: &rusqlite::Connection,
+    schema: &str,
+    query: &str,
+    min_seeds: usize,
+) -> Result<Vec<i64>> {
+    let (k, w): (i64, i64) = db.query_row(
+        &format!("SELECT k, w FROM {}gfa1_segment_kmer_params", schema),
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    let query_minimizers = minimizers(query.as_bytes(), k as usize, w as usize);
+
+    // seed: look up each of the query's minimizers
+    let mut seeds: HashMap<i64, HashSet<u64>> = HashMap::new();
+    let mut lookup = db.prepare(&format!(
+        "SELECT DISTINCT segment_id FROM {}gfa1_segment_kmer WHERE kmer_hash = ? LIMIT ?",
+        schema
+    ))?;
+    let query_hashes: HashSet<u64> = query_minimizers.iter().map(|(hash, _)| *hash).collect();
+    for hash in &query_hashes {
+        let mut hits = Vec::new();
+        let mut cursor = lookup.query(params![*hash as i64, MAX_KMER_OCCURRENCES as i64 + 1])?;
+        while let Some(row) = cursor.next()? {
+            hits.push(row.get::<usize, i64>(0)?)
+        }
+        if hits.len() > MAX_KMER_OCCURRENCES {
+            continue;
+        }
+        for segment_id in hits {
+            seeds.entry(segment_id).or_default().insert(*hash);
+        }
+    }
+    if !query_hashes.is_empty() && seeds.is_empty() {
+        warn!("no k-mer seeds found for query sequence");
+    }
+
+    // extend: group the seeded segments connected by links, and total their seeds
+    let mut neighbors = db.prepare(&format!(
+        "  SELECT from_segment FROM {s}gfa1_link WHERE to_segment = ?1
+         UNION
+           SELECT to_segment FROM {s}gfa1_link WHERE from_segment = ?1",
+        s = schema
+    ))?;
+    let threshold = std::cmp::max(1, std::cmp::min(min_seeds, query_hashes.len()));
+    let mut visited = HashSet::new();
+    let mut ans = Vec::new();
+    let mut seeded: Vec<i64> = seeds.keys().copied().collect();
+    seeded.sort_unstable();
+    for start in seeded {
+        if !visited.insert(start) {
+            continue;
+        }
+        let mut group = vec![start];
+        let mut group_seeds: HashSet<u64> = HashSet::new();
+        let mut i = 0;
+        while i < group.len() {
+            let segment_id = group[i];
+            group_seeds.extend(seeds[&segment_id].iter());
+            let mut cursor = neighbors.query(params![segment_id])?;
+            while let Some(row) = cursor.next()? {
+                let neighbor: i64 = row.get(0)?;
+                if seeds.contains_key(&neighbor) && visited.insert(neighbor) {
+                    group.push(neighbor)
+                }
+            }
+            i += 1;
+        }
+        if group_seeds.len() >= threshold {
+            ans.append(&mut group)
+        }
+    }
+    ans.sort_unstable();
+    Ok(ans)
+}
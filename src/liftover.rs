@@ -0,0 +1,297 @@
+// Translation of positions between segments and reference sequences through the segment mappings,
+// following their CIGARs (as stored by add-mappings) base-by-base through indels. A position
+// within an insertion (or a deletion, translating from the reference) has no counterpart. Mappings
+// lacking a CIGAR are taken to be ungapped if the segment & reference ranges have equal length,
+// otherwise positions are interpolated approximately.
+
+use clap::Clap;
+use log::warn;
+use rusqlite::{params, OpenFlags, OptionalExtension, ToSql};
+use std::{fs, io};
+
+use crate::util::Result;
+use crate::{add_mappings, bad_command, load, util};
+
+#[derive(Clap)]
+pub struct Opts {
+    /// gfab filename or http[s] URL
+    pub gfab: String,
+
+    /// Positions (one-based) to translate, SEGMENT:POS or with --to-segments, REFSEQ:POS
+    #[clap(name = "POSITION", required = true)]
+    pub positions: Vec<String>,
+
+    /// Translate reference positions to segment positions (instead of the reverse)
+    #[clap(long)]
+    pub to_segments: bool,
+
+    /// output TSV filename [omit or - for standard output]
+    #[clap(short, default_value = "-")]
+    pub output_tsv: String,
+
+    /// Use only the mappings in this set (see add-mappings --set) instead of the unnamed set
+    #[clap(long, name = "NAME")]
+    pub mapping_set: Option<String>,
+
+    /// Treat SEGMENTs as text names even if they look like integer IDs
+    #[clap(long)]
+    pub always_names: bool,
+
+    /// log extra progress reports
+    #[clap(short, long)]
+    pub verbose: bool,
+
+    /// log errors only
+    #[clap(short, long)]
+    pub quiet: bool,
+}
+
+pub fn main(opts: &Opts) -> Result<()> {
+    let mut dbopts = json::object::Object::new();
+    dbopts.insert("immutable", json::JsonValue::from(true));
+    let (_gfab_version, db) = util::open_gfab(
+        &opts.gfab,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        &dbopts,
+    )?;
+
+    // (mapping CIGARs are absent if the .gfab predates them)
+    let select_mappings = format!(
+        "SELECT m.segment_id, coalesce(s.name, cast(m.segment_id AS TEXT)), s.sequence_length,
+                refseq_name, refseq_begin, refseq_end, m.tags_json, {}
+         FROM gfa1_segment_mapping AS m INNER JOIN gfa1_segment_meta AS s USING(segment_id)",
+        util::optional_columns(&db, "", "gfa1_segment_mapping", &["cigar"])?
+    );
+    // use the given set of mappings, or else the unnamed set (any set name is bound as the last
+    // query parameter)
+    let set_condition = match util::mapping_set_condition(&db, "", opts.mapping_set.as_deref())? {
+        "" => String::new(),
+        condition => format!("AND {}", condition),
+    };
+    let mut mappings_query = if opts.to_segments {
+        db.prepare(&format!(
+            "{} WHERE m._rowid_ IN genomic_range_rowids('gfa1_segment_mapping', ?1, ?2, ?2 + 1) {}
+             ORDER BY m.segment_id, refseq_begin",
+            select_mappings, set_condition
+        ))?
+    } else {
+        db.prepare(&format!(
+            "{} WHERE m.segment_id = ?1 {} ORDER BY refseq_name, refseq_begin",
+            select_mappings, set_condition
+        ))?
+    };
+    let mut find_segment_by_name =
+        db.prepare("SELECT segment_id FROM gfa1_segment_meta WHERE name = ?")?;
+
+    let mut writer: Box<dyn io::Write> = if opts.output_tsv.is_empty() || opts.output_tsv == "-" {
+        Box::new(io::BufWriter::new(io::stdout()))
+    } else {
+        Box::new(io::BufWriter::new(fs::File::create(&opts.output_tsv)?))
+    };
+    let mut approximate = false;
+    for position in &opts.positions {
+        // parse NAME:POS
+        let (name, pos) = match position.rsplit_once(':').map(|(name, pos)| {
+            (
+                name,
+                pos.replace(",", "").parse::<i64>().ok().filter(|&p| p >= 1),
+            )
+        }) {
+            Some((name, Some(pos))) if !name.is_empty() => (name, pos - 1),
+            _ => bad_command!("invalid position {} (expected e.g. NAME:1,234)", position),
+        };
+
+        let mappings = if opts.to_segments {
+            let mut args: Vec<&dyn ToSql> = vec![&name, &pos];
+            args.extend(opts.mapping_set.as_ref().map(|s| s as &dyn ToSql));
+            query_mappings(&mut mappings_query, &args[..])?
+        } else {
+            let segment_id: i64 = match load::name_to_id(name).filter(|_| !opts.always_names) {
+                Some(id) => id,
+                None => match find_segment_by_name
+                    .query_row(params![name], |row| row.get(0))
+                    .optional()?
+                {
+                    Some(id) => id,
+                    None => bad_command!("unknown segment {}", name),
+                },
+            };
+            let mut args: Vec<&dyn ToSql> = vec![&segment_id];
+            args.extend(opts.mapping_set.as_ref().map(|s| s as &dyn ToSql));
+            query_mappings(&mut mappings_query, &args[..])?
+        };
+
+        let mut translated = false;
+        for mapping in mappings {
+            let ans = if opts.to_segments {
+                mapping
+                    .refseq_to_segment(pos, &mut approximate)
+                    .map(|p| (&mapping.segment_name, p))
+            } else {
+                mapping
+                    .segment_to_refseq(pos, &mut approximate)
+                    .map(|p| (&mapping.refseq_name, p))
+            };
+            if let Some((name, p)) = ans {
+                writer.write_fmt(format_args!(
+                    "{}\t{}:{}\t{}\n",
+                    position,
+                    name,
+                    p + 1,
+                    if mapping.reverse { '-' } else { '+' }
+                ))?;
+                translated = true
+            }
+        }
+        if !translated {
+            writer.write_fmt(format_args!("{}\t*\t*\n", position))?;
+        }
+    }
+    writer.flush()?;
+    if approximate {
+        warn!("some mappings lack CIGARs; their positions were interpolated approximately")
+    }
+    Ok(())
+}
+
+// A segment mapping, with zero-based, half-open coordinates
+struct Mapping {
+    segment_name: String,
+    segment_begin: i64,
+    segment_end: i64,
+    refseq_name: String,
+    refseq_begin: i64,
+    refseq_end: i64,
+    reverse: bool,
+    cigar: Option<Vec<(u64, char)>>,
+}
+
+fn query_mappings<P: rusqlite::Params>(
+    query: &mut rusqlite::Statement,
+    params: P,
+) -> Result<Vec<Mapping>> {
+    let mut ans = Vec::new();
+    let mut cursor = query.query(params)?;
+    while let Some(row) = cursor.next()? {
+        let segment_id: i64 = row.get(0)?;
+        let segment_length: Option<i64> = row.get(2)?;
+        let refseq_begin: i64 = row.get(4)?;
+        let refseq_end: i64 = row.get(5)?;
+        let invalid = |message: &str| util::Error::InvalidGfab {
+            message: String::from(message),
+            table: String::from("gfa1_segment_mapping"),
+            rowid: segment_id,
+        };
+        // mappings lacking segment range tags (e.g. from rGFA) cover the whole segment
+        let tags = match row.get::<_, Option<String>>(6)? {
+            Some(tags_json) => json::parse(&tags_json).map_err(|_| invalid("invalid tags_json"))?,
+            None => json::JsonValue::new_object(),
+        };
+        let cigar = match row.get::<_, Option<String>>(7)? {
+            Some(cigar) => {
+                Some(add_mappings::parse_cigar(&cigar).ok_or_else(|| invalid("invalid cigar"))?)
+            }
+            None => None,
+        };
+        ans.push(Mapping {
+            segment_name: row.get(1)?,
+            segment_begin: tags["sb:i"].as_i64().unwrap_or(0),
+            segment_end: tags["se:i"]
+                .as_i64()
+                .or(segment_length)
+                .unwrap_or(refseq_end - refseq_begin),
+            refseq_name: row.get(3)?,
+            refseq_begin,
+            refseq_end,
+            reverse: tags["so:Z"].as_str() == Some("-"),
+            cigar,
+        })
+    }
+    Ok(ans)
+}
+
+impl Mapping {
+    // zero-based segment position => refseq position, if aligned
+    fn segment_to_refseq(&self, pos: i64, approximate: &mut bool) -> Option<i64> {
+        if pos < self.segment_begin || pos >= self.segment_end {
+            return None;
+        }
+        // offset in the (oriented) query
+        let q = if self.reverse {
+            self.segment_end - 1 - pos
+        } else {
+            pos - self.segment_begin
+        };
+        let t = self.translate(q, false, approximate)?;
+        Some(self.refseq_begin + t)
+    }
+
+    // zero-based refseq position => segment position, if aligned
+    fn refseq_to_segment(&self, pos: i64, approximate: &mut bool) -> Option<i64> {
+        if pos < self.refseq_begin || pos >= self.refseq_end {
+            return None;
+        }
+        let q = self.translate(pos - self.refseq_begin, true, approximate)?;
+        Some(if self.reverse {
+            self.segment_end - 1 - q
+        } else {
+            self.segment_begin + q
+        })
+    }
+
+    // translate an offset from the start of the alignment in the target (reference) or query
+    // (segment) to the corresponding offset in the other
+    fn translate(&self, offset: i64, from_target: bool, approximate: &mut bool) -> Option<i64> {
+        let (target_length, query_length) = (
+            self.refseq_end - self.refseq_begin,
+            self.segment_end - self.segment_begin,
+        );
+        let ops = match &self.cigar {
+            Some(ops) => ops,
+            None => {
+                let (from_length, to_length) = if from_target {
+                    (target_length, query_length)
+                } else {
+                    (query_length, target_length)
+                };
+                if from_length == to_length {
+                    return Some(offset);
+                }
+                *approximate = true;
+                return Some(std::cmp::min(
+                    offset * to_length / from_length,
+                    to_length - 1,
+                ));
+            }
+        };
+        let (mut tc, mut qc) = (0, 0);
+        for &(n, op) in ops {
+            let n = n as i64;
+            let (from, to) = if from_target { (tc, qc) } else { (qc, tc) };
+            let in_op = from <= offset && offset < from + n;
+            match op {
+                'M' => {
+                    if in_op {
+                        return Some(to + offset - from);
+                    }
+                    tc += n;
+                    qc += n
+                }
+                'I' => {
+                    if in_op && !from_target {
+                        return None;
+                    }
+                    qc += n
+                }
+                'D' => {
+                    if in_op && from_target {
+                        return None;
+                    }
+                    tc += n
+                }
+                _ => (),
+            }
+        }
+        None
+    }
+}
@@ -3,6 +3,7 @@ use genomicsqlite::ConnectionMethods;
 use json::object;
 use log::{debug, info, log_enabled, warn};
 use num_format::{Locale, ToFormattedString};
+use regex::Regex;
 use rusqlite::{params, OpenFlags, OptionalExtension, Statement, Transaction};
 use std::cmp;
 use std::collections::{HashMap, HashSet};
@@ -12,6 +13,7 @@ use crate::connectivity;
 use crate::invalid_gfa;
 use crate::util;
 use crate::util::Result;
+use crate::{spell, validate, view};
 
 #[derive(Clap)]
 pub struct Opts {
@@ -19,7 +21,7 @@ pub struct Opts {
     #[clap(short)]
     pub output_gfab: String,
 
-    /// Uncompressed GFA file/pipe [omit or - for standard input]
+    /// GFA file/pipe (decompressed if .gz) [omit or - for standard input]
     #[clap(default_value = "-")]
     pub input_gfa: String,
 
@@ -39,6 +41,30 @@ pub struct Opts {
     #[clap(long)]
     pub no_twobit: bool,
 
+    /// Check that consecutive Walk steps are joined by Links (error if not)
+    #[clap(long)]
+    pub check_walks: bool,
+
+    /// Add any Links missing between consecutive Walk steps (implies --check-walks, but without error)
+    #[clap(long)]
+    pub add_missing_links: bool,
+
+    // keep segments' LN:i even if inconsistent with the sequence, instead of erroring (for
+    // validate to report)
+    #[clap(skip)]
+    pub keep_inconsistent_lengths: bool,
+
+    /// Derive segment mappings from the Walks of this reference sample (e.g. GRCh38), or of
+    /// comma-separated samples each in the mapping set of the same name
+    #[clap(long, name = "SAMPLE")]
+    pub reference_sample: Option<String>,
+
+    /// Derive segment mappings from the Paths with names matching this regular expression (e.g.
+    /// ^GRCh38#); PanSN names SAMPLE#HAP#CONTIG map onto CONTIG (in mapping set SAMPLE, if there
+    /// are several samples)
+    #[clap(long, name = "REGEX")]
+    pub reference_path_regex: Option<String>,
+
     /// Memory budget (GiB)
     #[clap(long, default_value = "4")]
     pub memory_gbytes: u32,
@@ -64,9 +90,9 @@ pub fn main(opts: &Opts) -> Result<()> {
         bad_command!("pipe in .gfa data or supply input filename")
     }
 
-    // formulate GenomicSQLite configuration JSON
+    let output = util::OutputFile::new(&opts.output_gfab)?;
     let mut db = new_db(
-        &opts.output_gfab,
+        &output.temp_filename,
         opts.compress,
         std::cmp::max(1024, opts.memory_gbytes * 400),
     )?;
@@ -136,8 +162,17 @@ pub fn main(opts: &Opts) -> Result<()> {
                     FROM temp.walk_hold",
             )?;
             debug!("insertions complete");
+
+            if opts.check_walks || opts.add_missing_links {
+                check_walk_links(&txn, opts.add_missing_links)?;
+            }
+            if opts.reference_sample.is_some() || opts.reference_path_regex.is_some() {
+                insert_reference_mappings(&txn, opts)?;
+            }
         }
 
+        util::record_provenance(&txn, &[&opts.input_gfa], None)?;
+
         // indexing
         create_indexes(&txn, !opts.no_connectivity)?;
 
@@ -150,6 +185,7 @@ pub fn main(opts: &Opts) -> Result<()> {
         summary(&db)?;
     }
     db.close().map_err(|(_, e)| e)?;
+    output.commit()?;
     if records_processed > 0 {
         info!("🗹 done");
         Ok(())
@@ -179,7 +215,6 @@ pub fn new_db(
     };
 
     // create db
-    util::delete_existing_file(filename)?;
     let db = genomicsqlite::open(
         filename,
         OpenFlags::SQLITE_OPEN_CREATE
@@ -210,13 +245,7 @@ pub fn create_indexes(db: &rusqlite::Connection, connectivity: bool) -> Result<(
         }
     }
 
-    // add GRIs
-    debug!("\tindexing segment mappings & walks by genomic range ...");
-    for table in vec!["gfa1_segment_mapping", "gfa1_walk"] {
-        let gri_sql =
-            db.create_genomic_range_index_sql(table, "refseq_name", "refseq_begin", "refseq_end")?;
-        db.execute_batch(&gri_sql)?;
-    }
+    create_gris(db)?;
 
     if connectivity {
         debug!("\tindexing graph connectivity ...");
@@ -229,12 +258,31 @@ pub fn create_indexes(db: &rusqlite::Connection, connectivity: bool) -> Result<(
     Ok(())
 }
 
+pub const GRI_TABLES: [&str; 2] = ["gfa1_segment_mapping", "gfa1_walk"];
+
+// GenomicSQLite Genomic Range Indexes on segment mappings & walks
+pub fn create_gris(db: &rusqlite::Connection) -> Result<()> {
+    debug!("\tindexing segment mappings & walks by genomic range ...");
+    for table in GRI_TABLES.iter() {
+        create_gri(db, table)?;
+    }
+    Ok(())
+}
+
+// GenomicSQLite Genomic Range Index on one of GRI_TABLES
+pub fn create_gri(db: &rusqlite::Connection, table: &str) -> Result<()> {
+    let gri_sql =
+        db.create_genomic_range_index_sql(table, "refseq_name", "refseq_begin", "refseq_end")?;
+    db.execute_batch(&gri_sql)?;
+    Ok(())
+}
+
 fn insert_gfa1(filename: &str, txn: &Transaction, opts: &Opts) -> Result<usize> {
     // prepared statements
     let mut stmt_insert_segment_meta =
         txn.prepare("INSERT INTO temp.segment_meta_hold(segment_id,name,sequence_length,tags_json) VALUES(?,?,?,?)")?;
     let mut stmt_insert_segment_sequence = txn.prepare(&format!(
-        "INSERT INTO gfa1_segment_sequence(segment_id,sequence_twobit) VALUES(?,{})",
+        "INSERT INTO gfa1_segment_sequence(segment_id,sequence_twobit,sequence_hash) VALUES(?,{},?)",
         if !opts.no_twobit {
             "nucleotides_twobit(?)"
         } else {
@@ -281,6 +329,7 @@ fn insert_gfa1(filename: &str, txn: &Transaction, opts: &Opts) -> Result<usize>
                     txn,
                     !opts.no_sequences,
                     opts.always_names,
+                    opts.keep_inconsistent_lengths,
                     &mut stmt_insert_segment_meta,
                     &mut stmt_insert_segment_sequence,
                     &mut stmt_insert_segment_mapping,
@@ -362,6 +411,7 @@ fn insert_gfa1_segment(
     txn: &Transaction,
     sequences: bool,
     always_names: bool,
+    keep_inconsistent_lengths: bool,
     stmt_meta: &mut Statement,
     stmt_sequence: &mut Statement,
     stmt_mapping: &mut Statement,
@@ -391,6 +441,7 @@ fn insert_gfa1_segment(
     // it's consistent)
     let ln_tag = tags_json.remove("LN:i").map(|j| j.as_i64()).flatten();
     let maybe_sequence_len = match (maybe_sequence, ln_tag) {
+        (Some(_), Some(lni)) if keep_inconsistent_lengths => Some(lni),
         (Some(seq), Some(lni)) if lni != (seq.len() as i64) => {
             invalid_gfa!(
                 "(Ln {}) segment with inconsistent sequence length and LN tag: {}",
@@ -424,7 +475,7 @@ fn insert_gfa1_segment(
                     }
                 }
             }
-            stmt_sequence.execute(params![rowid_actual, seq])?;
+            stmt_sequence.execute(params![rowid_actual, seq, sequence_hash(seq)])?;
         }
     }
 
@@ -470,6 +521,136 @@ fn insert_gfa1_segment(
     Ok(())
 }
 
+// Derive segment mappings from the reference Walks and/or Paths, each step mapping onto the
+// reference range following the previous step's, less any overlap between them.
+fn insert_reference_mappings(db: &rusqlite::Connection, opts: &Opts) -> Result<()> {
+    let mut insert_mapping = db.prepare(
+        "INSERT INTO gfa1_segment_mapping(
+            segment_id, refseq_name, refseq_begin, refseq_end, tags_json, cigar, mapping_set)
+         VALUES(?,?,?,?,?,?,?)",
+    )?;
+    let mut mapping_count = 0;
+    let mut insert_pieces = |pieces: &[spell::Piece],
+                             refseq_name: &str,
+                             refseq_begin: i64,
+                             mapping_set: Option<&str>|
+     -> Result<()> {
+        let mut pos = refseq_begin;
+        for piece in pieces {
+            let trim = cmp::min(piece.trim, piece.length);
+            let length = piece.length - trim;
+            if length == 0 {
+                continue;
+            }
+            // the trimmed overlap is at the start of the oriented segment
+            let (segment_begin, segment_end) = if piece.reverse {
+                (0, length)
+            } else {
+                (trim, piece.length)
+            };
+            let tags_json = object! {
+                "sb:i": segment_begin,
+                "se:i": segment_end,
+                "so:Z": if piece.reverse { "-" } else { "+" }
+            };
+            insert_mapping.execute(params![
+                piece.segment_id,
+                refseq_name,
+                pos,
+                pos + length,
+                tags_json.dump(),
+                format!("{}M", length),
+                mapping_set
+            ])?;
+            pos += length;
+            mapping_count += 1
+        }
+        Ok(())
+    };
+
+    // reference Paths, with the sample, contig, and begin position parsed from any PanSN name
+    let mut ref_paths: Vec<(i64, Option<String>, String, i64)> = Vec::new();
+    if let Some(path_regex) = &opts.reference_path_regex {
+        let path_re = match Regex::new(path_regex) {
+            Ok(re) => re,
+            Err(e) => bad_command!("invalid --reference-path-regex: {}", e),
+        };
+        // PanSN path name, optionally with a [begin] or [begin-end] subrange suffix
+        let pansn_re = Regex::new(r"^([^#]+)#[^#]+#(.+?)(\[([0-9]+)(-[0-9]+)?\])?$").unwrap();
+        let paths: Vec<(i64, String)> = db
+            .prepare("SELECT path_id, coalesce(name, cast(path_id AS TEXT)) FROM gfa1_path")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        for (path_id, name) in paths {
+            if !path_re.is_match(&name) {
+                continue;
+            }
+            ref_paths.push(match pansn_re.captures(&name) {
+                Some(caps) => (
+                    path_id,
+                    Some(String::from(caps.get(1).unwrap().as_str())),
+                    String::from(caps.get(2).unwrap().as_str()),
+                    caps.get(4).map_or(0, |b| b.as_str().parse().unwrap_or(0)),
+                ),
+                None => (path_id, None, name, 0),
+            })
+        }
+        if ref_paths.is_empty() {
+            bad_command!("no Paths matching --reference-path-regex {}", path_regex)
+        }
+    }
+
+    // mappings go into the unnamed set, unless they're from several samples, which then each get
+    // a mapping set of the same name (since their contig names may coincide)
+    let mut samples: HashSet<&str> = HashSet::new();
+    if let Some(reference_sample) = &opts.reference_sample {
+        samples.extend(reference_sample.split(','))
+    }
+    for (_, sample, _, _) in &ref_paths {
+        samples.insert(sample.as_deref().unwrap_or(""));
+    }
+    let named_sets = samples.len() > 1;
+
+    if let Some(reference_sample) = &opts.reference_sample {
+        let mut walks_query = db.prepare(
+            "SELECT walk_id, refseq_name, refseq_begin FROM gfa1_walk
+             WHERE sample = ? ORDER BY refseq_name, refseq_begin",
+        )?;
+        for sample in reference_sample.split(',') {
+            let walks: Vec<(i64, String, i64)> = walks_query
+                .query_map(params![sample], |row| {
+                    Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+                })?
+                .collect::<rusqlite::Result<_>>()?;
+            if walks.is_empty() {
+                bad_command!("no Walks of --reference-sample {}", sample)
+            }
+            for (walk_id, refseq_name, refseq_begin) in walks {
+                let pieces = spell::walk_pieces(db, walk_id)?;
+                insert_pieces(
+                    &pieces,
+                    &refseq_name,
+                    refseq_begin,
+                    Some(sample).filter(|_| named_sets),
+                )?
+            }
+        }
+    }
+
+    for (path_id, sample, refseq_name, refseq_begin) in &ref_paths {
+        let pieces = spell::path_id_pieces(db, *path_id)?;
+        insert_pieces(
+            &pieces,
+            refseq_name,
+            *refseq_begin,
+            sample.as_deref().filter(|_| named_sets),
+        )?
+    }
+
+    info!("derived {} segment mappings from reference", mapping_count);
+    Ok(())
+}
+
 fn insert_gfa1_link(
     line_num: usize,
     tsv: &Vec<&str>,
@@ -616,25 +797,15 @@ fn insert_gfa1_walk(
     let tags_json_text = tags_json.dump();
 
     // JSON-encode the walk steps
-    let mut first_step = true;
-    let mut prev_segment_id = -1;
-    let mut prev_reverse = false;
-    let mut min_segment_id = i64::MAX;
-    let mut max_segment_id = i64::MIN;
-    let mut steps_json_text = String::from("[");
+    let mut encoder = WalkStepsEncoder::new();
     for pre_step in tsv[6].split('>') {
-        if first_step && pre_step.is_empty() {
+        if encoder.is_empty() && pre_step.is_empty() {
             continue;
         }
         let mut reverse = false;
         for segment_name in pre_step.split('<') {
-            if first_step {
-                if segment_name.is_empty() {
-                    continue;
-                }
-                steps_json_text.push_str("{\"");
-            } else {
-                steps_json_text.push_str(",{\"");
+            if encoder.is_empty() && segment_name.is_empty() {
+                continue;
             }
 
             let maybe_segment_id = if always_names {
@@ -651,48 +822,20 @@ fn insert_gfa1_walk(
                     }
                 },
             };
+            encoder.push(segment_id, reverse);
 
-            let segment_id_text = format!("s\":{}", segment_id);
-            // delta-encode the segment id, if that's shorter than writing it out
-            let maybe_delta_text = if first_step {
-                None
-            } else {
-                let (sign, delta) = if segment_id >= prev_segment_id {
-                    ("+", segment_id - prev_segment_id)
-                } else {
-                    ("-", prev_segment_id - segment_id)
-                };
-                let delta_text = format!("{}\":{}", sign, delta);
-                if delta_text.len() < segment_id_text.len() {
-                    Some(delta_text)
-                } else {
-                    None
-                }
-            };
-            steps_json_text.push_str(&maybe_delta_text.unwrap_or(segment_id_text));
-
-            // write orientation if it's flipping wrt previous
-            if first_step || prev_reverse != reverse {
-                steps_json_text.push_str(if reverse { ",\"r\":1}" } else { ",\"r\":0}" })
-            } else {
-                steps_json_text.push_str("}")
-            }
-
-            prev_segment_id = segment_id;
-            min_segment_id = cmp::min(min_segment_id, segment_id);
-            max_segment_id = cmp::max(max_segment_id, segment_id);
-            prev_reverse = reverse;
             // after the nested splits on '>' then '<', the first element of pre_step is forward
             // and remaining elements are reverse
             reverse = true;
-            first_step = false;
         }
     }
-    steps_json_text.push_str("]");
 
-    if first_step {
+    if encoder.is_empty() {
         invalid_gfa!("(Ln {}) empty walk", line_num)
     }
+    let min_segment_id = encoder.min_segment_id;
+    let max_segment_id = encoder.max_segment_id;
+    let steps_json_text = encoder.finish();
 
     stmt_walk.execute(params![
         sample,
@@ -714,6 +857,150 @@ fn insert_gfa1_walk(
     Ok(())
 }
 
+// Check that consecutive steps of each walk are joined by a link (in either bidirected form),
+// optionally adding any missing links. Runs before indexing, so keeps all links in memory.
+fn check_walk_links(db: &rusqlite::Connection, add_missing: bool) -> Result<()> {
+    debug!("checking walk steps against links...");
+    let mut links: HashSet<(validate::OrientedSegment, validate::OrientedSegment)> = HashSet::new();
+    {
+        let mut links_query =
+            db.prepare("SELECT from_segment, from_reverse, to_segment, to_reverse FROM gfa1_link")?;
+        let mut links_cursor = links_query.query([])?;
+        while let Some(row) = links_cursor.next()? {
+            let from_segment: i64 = row.get(0)?;
+            let from_reverse: bool = row.get(1)?;
+            let to_segment: i64 = row.get(2)?;
+            let to_reverse: bool = row.get(3)?;
+            links.insert(((from_segment, from_reverse), (to_segment, to_reverse)));
+            links.insert(((to_segment, !to_reverse), (from_segment, !from_reverse)));
+        }
+    }
+
+    let mut insert_link = db.prepare(
+        "INSERT INTO gfa1_link(from_segment,from_reverse,to_segment,to_reverse) VALUES(?,?,?,?)",
+    )?;
+    let mut name_query = validate::prepare_name_query(db)?;
+    let mut iter_walk_query = view::prepare_iter_walk(db)?;
+    let mut walks_query =
+        db.prepare("SELECT walk_id, sample, hap_idx, refseq_name FROM gfa1_walk ORDER BY walk_id")?;
+    let mut walks_cursor = walks_query.query([])?;
+    let mut missing = 0;
+    while let Some(row) = walks_cursor.next()? {
+        let walk_id: i64 = row.get(0)?;
+        let missing_links =
+            validate::walk_missing_links(&mut iter_walk_query, walk_id, |prev, cur| {
+                Ok(links.contains(&(prev, cur)))
+            })?;
+        for (step, (prev_segment, prev_reverse), (segment, reverse)) in missing_links {
+            if !links.contains(&((prev_segment, prev_reverse), (segment, reverse))) {
+                missing += 1;
+                let edge = format!(
+                    "{} -> {}",
+                    validate::oriented_segment_name(&mut name_query, prev_segment, prev_reverse)?,
+                    validate::oriented_segment_name(&mut name_query, segment, reverse)?
+                );
+                let sample: String = row.get(1)?;
+                let hap_idx: i64 = row.get(2)?;
+                let refseq_name: String = row.get(3)?;
+                if add_missing {
+                    debug!(
+                        "adding link {} for walk {}#{}#{} step {}",
+                        edge, sample, hap_idx, refseq_name, step
+                    );
+                    insert_link.execute(params![prev_segment, prev_reverse, segment, reverse])?;
+                    links.insert(((prev_segment, prev_reverse), (segment, reverse)));
+                    links.insert(((segment, !reverse), (prev_segment, !prev_reverse)));
+                } else {
+                    invalid_gfa!(
+                        "walk {}#{}#{} step {} lacks link {}",
+                        sample,
+                        hap_idx,
+                        refseq_name,
+                        step,
+                        edge
+                    )
+                }
+            }
+        }
+    }
+    if missing > 0 {
+        warn!("added {} links missing between walk steps", missing)
+    }
+    Ok(())
+}
+
+// Incrementally JSON-encode walk steps for gfa1_walk_steps (see schema/GFA1.sql), tracking the
+// min & max segment ID along the way
+pub struct WalkStepsEncoder {
+    text: String,
+    steps: usize,
+    prev_segment_id: i64,
+    prev_reverse: bool,
+    pub min_segment_id: i64,
+    pub max_segment_id: i64,
+}
+
+impl WalkStepsEncoder {
+    pub fn new() -> WalkStepsEncoder {
+        WalkStepsEncoder {
+            text: String::from("["),
+            steps: 0,
+            prev_segment_id: -1,
+            prev_reverse: false,
+            min_segment_id: i64::MAX,
+            max_segment_id: i64::MIN,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps == 0
+    }
+
+    pub fn push(&mut self, segment_id: i64, reverse: bool) {
+        let first_step = self.steps == 0;
+        self.text.push_str(if first_step { "{\"" } else { ",{\"" });
+
+        let segment_id_text = format!("s\":{}", segment_id);
+        // delta-encode the segment id, if that's shorter than writing it out
+        let maybe_delta_text = if first_step {
+            None
+        } else {
+            let (sign, delta) = if segment_id >= self.prev_segment_id {
+                ("+", segment_id - self.prev_segment_id)
+            } else {
+                ("-", self.prev_segment_id - segment_id)
+            };
+            let delta_text = format!("{}\":{}", sign, delta);
+            if delta_text.len() < segment_id_text.len() {
+                Some(delta_text)
+            } else {
+                None
+            }
+        };
+        self.text
+            .push_str(&maybe_delta_text.unwrap_or(segment_id_text));
+
+        // write orientation if it's flipping wrt previous
+        if first_step || self.prev_reverse != reverse {
+            self.text
+                .push_str(if reverse { ",\"r\":1}" } else { ",\"r\":0}" })
+        } else {
+            self.text.push_str("}")
+        }
+
+        self.prev_segment_id = segment_id;
+        self.prev_reverse = reverse;
+        self.min_segment_id = cmp::min(self.min_segment_id, segment_id);
+        self.max_segment_id = cmp::max(self.max_segment_id, segment_id);
+        self.steps += 1;
+    }
+
+    pub fn finish(mut self) -> String {
+        self.text.push_str("]");
+        self.text
+    }
+}
+
 fn segment_and_orientation(
     line_num: usize,
     segment: &str,
@@ -798,6 +1085,57 @@ fn prepare_tags_json(
     Ok(ans)
 }
 
+// Orientation-independent hash of a nucleotide sequence: the lesser of the 64-bit FNV-1a hashes
+// of the sequence and its reverse complement, after uppercasing and U => T (as the two-bit
+// encoding would). Since these hashes are stored in .gfab files, this must remain stable.
+pub fn sequence_hash(sequence: &str) -> i64 {
+    const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const FNV_PRIME: u64 = 0x100000001b3;
+    let normalize = |ch: u8| match ch.to_ascii_uppercase() {
+        b'U' => b'T',
+        other => other,
+    };
+    let complement = |ch: u8| match ch {
+        b'A' => b'T',
+        b'C' => b'G',
+        b'G' => b'C',
+        b'T' => b'A',
+        other => other,
+    };
+    let bytes = sequence.as_bytes();
+    let mut fwd = FNV_OFFSET_BASIS;
+    let mut rev = FNV_OFFSET_BASIS;
+    for i in 0..bytes.len() {
+        fwd = (fwd ^ normalize(bytes[i]) as u64).wrapping_mul(FNV_PRIME);
+        rev = (rev ^ complement(normalize(bytes[bytes.len() - 1 - i])) as u64)
+            .wrapping_mul(FNV_PRIME);
+    }
+    cmp::min(fwd, rev) as i64
+}
+
+// Compute any missing sequence_hash values (e.g. for sequences copied from an older .gfab)
+pub fn fill_sequence_hashes(db: &rusqlite::Connection) -> Result<usize> {
+    let mut hashes = Vec::new();
+    {
+        let mut query = db.prepare(
+            "SELECT segment_id, twobit_dna(sequence_twobit) FROM gfa1_segment_sequence
+             WHERE sequence_hash IS NULL",
+        )?;
+        let mut cursor = query.query([])?;
+        while let Some(row) = cursor.next()? {
+            let segment_id: i64 = row.get(0)?;
+            let sequence: String = row.get(1)?;
+            hashes.push((segment_id, sequence_hash(&sequence)));
+        }
+    }
+    let mut update =
+        db.prepare("UPDATE gfa1_segment_sequence SET sequence_hash = ? WHERE segment_id = ?")?;
+    for (segment_id, hash) in &hashes {
+        update.execute(params![hash, segment_id])?;
+    }
+    Ok(hashes.len())
+}
+
 pub fn summary(db: &rusqlite::Connection) -> Result<()> {
     debug!("tables & row counts:");
     let mut stmt_tables = db.prepare("SELECT name FROM sqlite_master WHERE type='table'")?;
@@ -7,9 +7,22 @@ use log::error;
 
 mod add_mappings;
 mod connectivity;
+mod diff;
+mod export;
+mod index;
+mod kmers;
+mod liftover;
 mod load;
+mod merge;
+mod remove_mappings;
+mod render;
+mod seq;
+mod spell;
+mod stats;
 mod sub;
+mod upgrade;
 mod util;
+mod validate;
 mod version;
 mod view;
 
@@ -39,11 +52,44 @@ enum SubCommand {
     /// assembly.gfab += mappings.{paf,gaf}
     AddMappings(add_mappings::Opts),
 
+    /// assembly.gfab -= selected segment mappings
+    RemoveMappings(remove_mappings::Opts),
+
     /// in.gfab => out.gfa
     View(view::Opts),
 
     /// in.gfab => subgraph.gfab
     Sub(sub::Opts),
+
+    /// in1.gfab in2.gfab ... => merged.gfab
+    Merge(merge::Opts),
+
+    /// in.gfab SEGMENT:BEGIN-END => sequence FASTA
+    Seq(seq::Opts),
+
+    /// in.gfab --path NAME | --walk SAMPLE#HAP#CONTIG => sequence FASTA
+    Spell(spell::Opts),
+
+    /// in.gfab SEGMENT:POS | --to-segments REFSEQ:POS => translated positions
+    Liftover(liftover::Opts),
+
+    /// in.gfab => rendered SVG (for small graphs)
+    Render(render::Opts),
+
+    /// old.gfab new.gfab => report of differences
+    Diff(diff::Opts),
+
+    /// (re)build indexes of .gfab in-place
+    Index(index::Opts),
+
+    /// old.gfab => new.gfab (migrate from older gfabase version)
+    Upgrade(upgrade::Opts),
+
+    /// report graph statistics
+    Stats(stats::Opts),
+
+    /// check integrity of .gfa or .gfab
+    Validate(validate::Opts),
 }
 
 fn main() -> Result<()> {
@@ -52,8 +98,19 @@ fn main() -> Result<()> {
         SubCommand::Version => false,
         SubCommand::Load(subopts) => subopts.verbose,
         SubCommand::AddMappings(subopts) => subopts.verbose,
+        SubCommand::RemoveMappings(subopts) => subopts.verbose,
         SubCommand::View(subopts) => subopts.verbose,
         SubCommand::Sub(subopts) => subopts.verbose,
+        SubCommand::Merge(subopts) => subopts.verbose,
+        SubCommand::Seq(subopts) => subopts.verbose,
+        SubCommand::Spell(subopts) => subopts.verbose,
+        SubCommand::Liftover(subopts) => subopts.verbose,
+        SubCommand::Render(subopts) => subopts.verbose,
+        SubCommand::Diff(subopts) => subopts.verbose,
+        SubCommand::Index(subopts) => subopts.verbose,
+        SubCommand::Upgrade(subopts) => subopts.verbose,
+        SubCommand::Stats(subopts) => subopts.verbose,
+        SubCommand::Validate(subopts) => subopts.verbose,
     } {
         opts.verbose = true;
     }
@@ -61,8 +118,19 @@ fn main() -> Result<()> {
         SubCommand::Version => false,
         SubCommand::Load(subopts) => subopts.quiet,
         SubCommand::AddMappings(subopts) => subopts.quiet,
+        SubCommand::RemoveMappings(subopts) => subopts.quiet,
         SubCommand::View(subopts) => subopts.quiet,
         SubCommand::Sub(subopts) => subopts.quiet,
+        SubCommand::Merge(subopts) => subopts.quiet,
+        SubCommand::Seq(subopts) => subopts.quiet,
+        SubCommand::Spell(subopts) => subopts.quiet,
+        SubCommand::Liftover(subopts) => subopts.quiet,
+        SubCommand::Render(subopts) => subopts.quiet,
+        SubCommand::Diff(subopts) => subopts.quiet,
+        SubCommand::Index(subopts) => subopts.quiet,
+        SubCommand::Upgrade(subopts) => subopts.quiet,
+        SubCommand::Stats(subopts) => subopts.quiet,
+        SubCommand::Validate(subopts) => subopts.quiet,
     } {
         opts.quiet = true;
     }
@@ -97,8 +165,19 @@ fn main() -> Result<()> {
         SubCommand::Version => version::main(),
         SubCommand::Load(subopts) => load::main(subopts),
         SubCommand::AddMappings(subopts) => add_mappings::main(subopts),
+        SubCommand::RemoveMappings(subopts) => remove_mappings::main(subopts),
         SubCommand::View(subopts) => view::main(subopts),
         SubCommand::Sub(subopts) => sub::main(subopts),
+        SubCommand::Merge(subopts) => merge::main(subopts),
+        SubCommand::Seq(subopts) => seq::main(subopts),
+        SubCommand::Spell(subopts) => spell::main(subopts),
+        SubCommand::Liftover(subopts) => liftover::main(subopts),
+        SubCommand::Render(subopts) => render::main(subopts),
+        SubCommand::Diff(subopts) => diff::main(subopts),
+        SubCommand::Index(subopts) => index::main(subopts),
+        SubCommand::Upgrade(subopts) => upgrade::main(subopts),
+        SubCommand::Stats(subopts) => stats::main(subopts),
+        SubCommand::Validate(subopts) => validate::main(subopts),
     };
 
     if let Err(util::Error::EmptyGfab) = rslt {
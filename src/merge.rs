@@ -0,0 +1,392 @@
+use clap::Clap;
+use genomicsqlite::ConnectionMethods;
+use log::{debug, info, log_enabled, warn};
+use rusqlite::{params, OptionalExtension};
+
+use crate::util::Result;
+use crate::{bad_command, load, util, view};
+
+#[derive(Clap)]
+pub struct Opts {
+    /// Input .gfab filenames or http[s] URLs
+    #[clap(required = true)]
+    pub input_gfabs: Vec<String>,
+
+    /// Destination .gfab filename
+    #[clap(short)]
+    pub output_gfab: String,
+
+    /// Omit segment sequences
+    #[clap(long)]
+    pub no_sequences: bool,
+
+    /// Omit index of graph connectivity
+    #[clap(long)]
+    pub no_connectivity: bool,
+
+    /// compression level (-5 to 22) for output .gfab
+    #[clap(long, default_value = "6")]
+    pub compress: i8,
+
+    /// Memory budget (GiB)
+    #[clap(long, default_value = "1")]
+    pub memory_gbytes: u32,
+
+    /// log extra progress reports
+    #[clap(short, long)]
+    pub verbose: bool,
+
+    /// log errors only
+    #[clap(short, long)]
+    pub quiet: bool,
+}
+
+pub fn main(opts: &Opts) -> Result<()> {
+    if !opts.output_gfab.ends_with(".gfab") {
+        warn!("output filename should end in .gfab")
+    }
+    for input_gfab in &opts.input_gfabs {
+        util::url_or_extant_file(input_gfab)?;
+    }
+
+    let output = util::OutputFile::new(&opts.output_gfab)?;
+    let mut db = load::new_db(
+        &output.temp_filename,
+        opts.compress,
+        opts.memory_gbytes * 200,
+    )?;
+    load::create_tables(&db)?;
+
+    let mut dbopts_in = json::object::Object::new();
+    dbopts_in.insert("immutable", json::JsonValue::from(true));
+    dbopts_in.insert(
+        "page_cache_MiB",
+        json::JsonValue::from(opts.memory_gbytes * 600),
+    );
+
+    for input_gfab in &opts.input_gfabs {
+        info!("merging {} ...", input_gfab);
+        // attach input database (outside of any transaction)
+        let attach_sql = db.genomicsqlite_attach_sql(input_gfab, "input", &dbopts_in)?;
+        db.execute_batch(&attach_sql)?;
+        let gfab_version = util::check_gfab_schema(&db, "input.")?;
+        util::check_gfab_version(&gfab_version)?;
+        {
+            let txn = db.transaction()?;
+            merge_input(&txn, input_gfab, opts)?;
+            txn.commit()?;
+        }
+        db.execute_batch("DETACH input")?;
+    }
+
+    let segment_count: i64;
+    {
+        let txn = db.transaction()?;
+        txn.execute(
+            "UPDATE gfa1_header SET tags_json = json_set(tags_json, '$.PG:Z', ?) WHERE _rowid_ = 1",
+            params![format!("gfabase-v{}", env!("CARGO_PKG_VERSION"))],
+        )?;
+        segment_count = txn.query_row("SELECT count(1) FROM gfa1_segment_meta", [], |row| {
+            row.get(0)
+        })?;
+        let inputs: Vec<&str> = opts.input_gfabs.iter().map(|s| s.as_str()).collect();
+        util::record_provenance(&txn, &inputs, None)?;
+        load::create_indexes(&txn, !opts.no_connectivity)?;
+        debug!("flushing {} ...", &opts.output_gfab);
+        txn.commit()?;
+    }
+
+    if log_enabled!(log::Level::Debug) {
+        load::summary(&db)?;
+    }
+    db.close().map_err(|(_, e)| e)?;
+    output.commit()?;
+    if segment_count == 0 {
+        return Err(util::Error::EmptyGfab);
+    }
+    info!("🗹 done");
+    Ok(())
+}
+
+// Copy the attached input.* into the main db, remapping segment/path/walk IDs as needed to avoid
+// collisions with the contents already merged.
+fn merge_input(db: &rusqlite::Connection, input_gfab: &str, opts: &Opts) -> Result<()> {
+    // Map input segment IDs to output segment IDs:
+    // 1. named segments whose name is already present
+    // 2. unnamed segments whose ID is already present with identical length & sequence
+    // 3. remaining segments keep their ID, if it's not already taken
+    // 4. otherwise, allocate a new ID
+    db.execute_batch(
+        "DROP TABLE IF EXISTS temp.segment_map;
+         CREATE TABLE temp.segment_map(
+            old_id INTEGER PRIMARY KEY, new_id INTEGER NOT NULL, is_new INTEGER NOT NULL
+         );
+         INSERT INTO temp.segment_map(old_id, new_id, is_new)
+            SELECT i.segment_id, o.segment_id, 0
+            FROM input.gfa1_segment_meta AS i INNER JOIN main.gfa1_segment_meta AS o
+                ON i.name = o.name
+            WHERE i.name IS NOT NULL;
+         INSERT INTO temp.segment_map(old_id, new_id, is_new)
+            SELECT i.segment_id, i.segment_id, 0
+            FROM input.gfa1_segment_meta AS i INNER JOIN main.gfa1_segment_meta AS o
+                USING(segment_id)
+            WHERE i.name IS NULL AND o.name IS NULL AND i.sequence_length IS o.sequence_length
+                AND (SELECT sequence_twobit FROM input.gfa1_segment_sequence AS s
+                     WHERE s.segment_id = i.segment_id)
+                    IS (SELECT sequence_twobit FROM main.gfa1_segment_sequence AS s
+                        WHERE s.segment_id = i.segment_id);
+         INSERT INTO temp.segment_map(old_id, new_id, is_new)
+            SELECT segment_id, segment_id, 1 FROM input.gfa1_segment_meta
+            WHERE segment_id NOT IN (SELECT old_id FROM temp.segment_map)
+                AND segment_id NOT IN (SELECT segment_id FROM main.gfa1_segment_meta);
+         INSERT INTO temp.segment_map(old_id, new_id, is_new)
+            SELECT
+                segment_id,
+                (SELECT max(coalesce((SELECT max(segment_id) FROM main.gfa1_segment_meta), 0),
+                            coalesce((SELECT max(segment_id) FROM input.gfa1_segment_meta), 0)))
+                    + row_number() OVER (ORDER BY segment_id),
+                1
+            FROM input.gfa1_segment_meta
+            WHERE segment_id NOT IN (SELECT old_id FROM temp.segment_map)",
+    )?;
+
+    // deduplicated named segments should really be identical (in length, and in sequence if both
+    // have it)
+    if let Some((name, same_length)) = db
+        .query_row(
+            "SELECT i.name, i.sequence_length IS o.sequence_length
+             FROM temp.segment_map
+                INNER JOIN input.gfa1_segment_meta AS i ON old_id = i.segment_id
+                INNER JOIN main.gfa1_segment_meta AS o ON new_id = o.segment_id
+                LEFT JOIN input.gfa1_segment_sequence AS iseq ON old_id = iseq.segment_id
+                LEFT JOIN main.gfa1_segment_sequence AS oseq ON new_id = oseq.segment_id
+             WHERE NOT is_new
+                AND (i.sequence_length IS NOT o.sequence_length
+                     OR iseq.sequence_twobit != oseq.sequence_twobit)
+             LIMIT 1",
+            [],
+            |row| Ok((row.get::<usize, String>(0)?, row.get::<usize, bool>(1)?)),
+        )
+        .optional()?
+    {
+        bad_command!(
+            "segment {} in {} has {} inconsistent with same-named segment in previous input",
+            name,
+            input_gfab,
+            if same_length { "sequence" } else { "length" }
+        )
+    }
+    if opts.verbose {
+        let (deduped, remapped): (i64, i64) = db.query_row(
+            "SELECT sum(NOT is_new), sum(is_new AND old_id != new_id) FROM temp.segment_map",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        debug!(
+            "deduplicated {} segments and remapped {} segment IDs",
+            deduped, remapped
+        );
+    }
+
+    let mapping_columns = util::optional_columns(
+        db,
+        "input",
+        "gfa1_segment_mapping",
+        &["cigar", "mapping_set"],
+    )?;
+    let input_mapping_set =
+        if util::column_exists(db, "input", "gfa1_segment_mapping", "mapping_set")? {
+            "input.gfa1_segment_mapping.mapping_set"
+        } else {
+            "NULL"
+        };
+    db.execute_batch(&format!(
+        "INSERT INTO gfa1_segment_meta(segment_id, name, sequence_length, tags_json)
+            SELECT new_id, name, sequence_length, tags_json
+            FROM temp.segment_map INNER JOIN input.gfa1_segment_meta ON old_id = segment_id
+            WHERE is_new;
+         INSERT INTO gfa1_segment_mapping(segment_id, refseq_name, refseq_begin, refseq_end, tags_json, cigar, mapping_set)
+            SELECT new_id, refseq_name, refseq_begin, refseq_end, tags_json, {}
+            FROM input.gfa1_segment_mapping INNER JOIN temp.segment_map ON segment_id = old_id
+            WHERE is_new OR NOT EXISTS
+                (SELECT 1 FROM main.gfa1_segment_mapping AS m
+                 WHERE m.segment_id = new_id AND m.refseq_name = input.gfa1_segment_mapping.refseq_name
+                    AND m.refseq_begin = input.gfa1_segment_mapping.refseq_begin
+                    AND m.refseq_end = input.gfa1_segment_mapping.refseq_end
+                    AND m.mapping_set IS {})
+            ORDER BY new_id;
+         INSERT INTO gfa1_link(from_segment, from_reverse, to_segment, to_reverse, cigar, tags_json)
+            SELECT f.new_id, from_reverse, t.new_id, to_reverse, cigar, tags_json
+            FROM input.gfa1_link
+                INNER JOIN temp.segment_map AS f ON from_segment = f.old_id
+                INNER JOIN temp.segment_map AS t ON to_segment = t.old_id
+            WHERE f.is_new OR t.is_new OR NOT EXISTS
+                (SELECT 1 FROM main.gfa1_link AS l
+                 WHERE l.from_segment = f.new_id AND l.from_reverse = input.gfa1_link.from_reverse
+                    AND l.to_segment = t.new_id AND l.to_reverse = input.gfa1_link.to_reverse)
+            ORDER BY f.new_id, t.new_id",
+        mapping_columns, input_mapping_set
+    ))?;
+    if !opts.no_sequences {
+        // (sequence hashes are computed afterwards if the input .gfab predates them)
+        let input_hashes =
+            util::column_exists(db, "input", "gfa1_segment_sequence", "sequence_hash")?;
+        db.execute_batch(&format!(
+            "INSERT INTO gfa1_segment_sequence(segment_id, sequence_twobit, sequence_hash)
+                SELECT new_id, sequence_twobit, {}
+                FROM temp.segment_map INNER JOIN input.gfa1_segment_sequence ON old_id = segment_id
+                WHERE is_new",
+            if input_hashes {
+                "sequence_hash"
+            } else {
+                "NULL"
+            }
+        ))?;
+        if !input_hashes {
+            load::fill_sequence_hashes(db)?;
+        }
+    }
+
+    // paths: dedupe by name, otherwise keep ID if not already taken, otherwise allocate a new one
+    db.execute_batch(
+        "DROP TABLE IF EXISTS temp.path_map;
+         CREATE TABLE temp.path_map(
+            old_id INTEGER PRIMARY KEY, new_id INTEGER NOT NULL, is_new INTEGER NOT NULL
+         );
+         INSERT INTO temp.path_map(old_id, new_id, is_new)
+            SELECT i.path_id, o.path_id, 0
+            FROM input.gfa1_path AS i INNER JOIN main.gfa1_path AS o ON i.name = o.name
+            WHERE i.name IS NOT NULL;
+         INSERT INTO temp.path_map(old_id, new_id, is_new)
+            SELECT path_id, path_id, 1 FROM input.gfa1_path
+            WHERE path_id NOT IN (SELECT old_id FROM temp.path_map)
+                AND path_id NOT IN (SELECT path_id FROM main.gfa1_path);
+         INSERT INTO temp.path_map(old_id, new_id, is_new)
+            SELECT
+                path_id,
+                (SELECT max(coalesce((SELECT max(path_id) FROM main.gfa1_path), 0),
+                            coalesce((SELECT max(path_id) FROM input.gfa1_path), 0)))
+                    + row_number() OVER (ORDER BY path_id),
+                1
+            FROM input.gfa1_path
+            WHERE path_id NOT IN (SELECT old_id FROM temp.path_map)",
+    )?;
+    // deduplicated named paths must have identical elements
+    if let Some(name) = db
+        .query_row(
+            "SELECT name
+             FROM temp.path_map INNER JOIN input.gfa1_path ON old_id = path_id
+             WHERE NOT is_new AND
+                (SELECT group_concat(s.new_id || ',' || reverse || ',' || ifnull(cigar_vs_previous, ''), ';')
+                 FROM (SELECT * FROM input.gfa1_path_element AS e
+                       WHERE e.path_id = old_id ORDER BY ordinal)
+                    INNER JOIN temp.segment_map AS s ON segment_id = s.old_id)
+                IS NOT
+                (SELECT group_concat(segment_id || ',' || reverse || ',' || ifnull(cigar_vs_previous, ''), ';')
+                 FROM (SELECT * FROM main.gfa1_path_element AS e
+                       WHERE e.path_id = new_id ORDER BY ordinal))
+             LIMIT 1",
+            [],
+            |row| row.get::<usize, String>(0),
+        )
+        .optional()?
+    {
+        bad_command!(
+            "path {} in {} differs from same-named path in previous input",
+            name,
+            input_gfab
+        )
+    }
+    db.execute_batch(
+        "INSERT INTO gfa1_path(path_id, name, tags_json)
+            SELECT new_id, name, tags_json
+            FROM input.gfa1_path INNER JOIN temp.path_map ON path_id = old_id
+            WHERE is_new;
+         INSERT INTO gfa1_path_element(path_id, ordinal, segment_id, reverse, cigar_vs_previous)
+            SELECT p.new_id, ordinal, s.new_id, reverse, cigar_vs_previous
+            FROM input.gfa1_path_element
+                INNER JOIN temp.path_map AS p ON path_id = p.old_id
+                INNER JOIN temp.segment_map AS s ON segment_id = s.old_id
+            WHERE p.is_new",
+    )?;
+
+    merge_walks(db)?;
+
+    // header: keep the first input's
+    db.execute_batch(
+        "INSERT INTO gfa1_header(_rowid_, tags_json)
+            SELECT _rowid_, tags_json FROM input.gfa1_header
+            WHERE _rowid_ = 1 AND NOT EXISTS (SELECT 1 FROM main.gfa1_header)",
+    )?;
+    util::copy_provenance(db, "input.")?;
+    Ok(())
+}
+
+// Copy walks, re-encoding steps with the remapped segment IDs (skipping any walk identical to one
+// already present)
+fn merge_walks(db: &rusqlite::Connection) -> Result<()> {
+    let mut walks_query = db.prepare(
+        "SELECT walk_id, sample, hap_idx, refseq_name, refseq_begin, refseq_end, tags_json
+         FROM input.gfa1_walk ORDER BY walk_id",
+    )?;
+    let mut iter_walk_query = view::prepare_iter_walk_in(db, "input.")?;
+    let mut segment_map_query =
+        db.prepare("SELECT new_id FROM temp.segment_map WHERE old_id = ?")?;
+    let mut insert_walk = db.prepare(
+        "INSERT INTO gfa1_walk(sample, hap_idx, refseq_name, refseq_begin, refseq_end,
+                               min_segment_id, max_segment_id, tags_json)
+         VALUES(?,?,?,?,?,?,?,?)",
+    )?;
+    let mut existing_walk_query = db.prepare(
+        "SELECT 1 FROM main.gfa1_walk INNER JOIN main.gfa1_walk_steps USING(walk_id)
+         WHERE sample IS ? AND hap_idx = ? AND refseq_name = ? AND refseq_begin = ?
+            AND refseq_end = ? AND steps_jsarray = ?",
+    )?;
+    let mut insert_walk_steps =
+        db.prepare("INSERT INTO gfa1_walk_steps(walk_id, steps_jsarray) VALUES(?,?)")?;
+
+    let mut walks_cursor = walks_query.query([])?;
+    while let Some(row) = walks_cursor.next()? {
+        let walk_id: i64 = row.get(0)?;
+        let mut encoder = load::WalkStepsEncoder::new();
+        view::iter_walk(&mut iter_walk_query, walk_id, |segment_id, reverse| {
+            let new_id: i64 = segment_map_query.query_row(params![segment_id], |row| row.get(0))?;
+            encoder.push(new_id, reverse);
+            Ok(true)
+        })?;
+        if encoder.is_empty() {
+            continue;
+        }
+        let sample: Option<String> = row.get(1)?;
+        let hap_idx: i64 = row.get(2)?;
+        let refseq_name: String = row.get(3)?;
+        let refseq_begin: i64 = row.get(4)?;
+        let refseq_end: i64 = row.get(5)?;
+        let tags_json: Option<String> = row.get(6)?;
+        let (min_segment_id, max_segment_id) = (encoder.min_segment_id, encoder.max_segment_id);
+        let steps_jsarray = encoder.finish();
+        if existing_walk_query.exists(params![
+            sample,
+            hap_idx,
+            refseq_name,
+            refseq_begin,
+            refseq_end,
+            steps_jsarray
+        ])? {
+            continue;
+        }
+        insert_walk.execute(params![
+            sample,
+            hap_idx,
+            refseq_name,
+            refseq_begin,
+            refseq_end,
+            min_segment_id,
+            max_segment_id,
+            tags_json
+        ])?;
+        let new_walk_id = db.last_insert_rowid();
+        insert_walk_steps.execute(params![new_walk_id, steps_jsarray])?;
+    }
+    Ok(())
+}
@@ -6,13 +6,7 @@ INSERT INTO gfa1_segment_meta(segment_id, name, sequence_length, tags_json)
     SELECT segment_id, name, sequence_length, tags_json FROM input.gfa1_segment_meta
     WHERE segment_id IN temp.sub_segments;
 
--- gfa1_segment_sequences copied in code (if not --no-sequences)
-
-INSERT INTO gfa1_segment_mapping(segment_id, refseq_name, refseq_begin, refseq_end, tags_json)
-    SELECT segment_id, refseq_name, refseq_begin, refseq_end, tags_json
-    FROM input.gfa1_segment_mapping
-    WHERE segment_id IN temp.sub_segments
-    ORDER BY segment_id;
+-- gfa1_segment_sequences and gfa1_segment_mapping copied in code
 
 INSERT INTO gfa1_link(from_segment, from_reverse, to_segment, to_reverse, tags_json)
     SELECT from_segment, from_reverse, to_segment, to_reverse, tags_json FROM input.gfa1_link
@@ -0,0 +1,151 @@
+use clap::Clap;
+use log::{debug, info, warn};
+use rusqlite::{params, OpenFlags, OptionalExtension, ToSql};
+
+use crate::util::Result;
+use crate::{bad_command, load, util};
+
+#[derive(Clap)]
+pub struct Opts {
+    /// Assembly .gfab filename (to modify in-place; copy first if needed)
+    pub gfab: String,
+
+    /// Remove mappings onto this reference sequence (repeatable)
+    #[clap(long, name = "REFSEQ", number_of_values = 1)]
+    pub refseq: Vec<String>,
+
+    /// Remove mappings in this set (see add-mappings --set)
+    #[clap(long, name = "NAME")]
+    pub mapping_set: Option<String>,
+
+    /// Remove mappings in the unnamed set
+    #[clap(long)]
+    pub unnamed_set: bool,
+
+    /// Remove mappings of this segment (repeatable)
+    #[clap(long, name = "SEGMENT", number_of_values = 1)]
+    pub segment: Vec<String>,
+
+    /// Remove mappings with lower quality score (mq:i; mappings lacking it are kept)
+    #[clap(long, name = "Q")]
+    pub quality: Option<u64>,
+
+    /// Remove all mappings (if no other criteria are given)
+    #[clap(long)]
+    pub all: bool,
+
+    /// Treat SEGMENTs as text names even if they look like integer IDs
+    #[clap(long)]
+    pub always_names: bool,
+
+    /// log extra progress reports
+    #[clap(short, long)]
+    pub verbose: bool,
+
+    /// log errors only
+    #[clap(short, long)]
+    pub quiet: bool,
+}
+
+pub fn main(opts: &Opts) -> Result<()> {
+    if opts.mapping_set.is_some() && opts.unnamed_set {
+        bad_command!("--mapping-set and --unnamed-set are mutually exclusive")
+    }
+
+    let mut dbopts = json::object::Object::new();
+    dbopts.insert("unsafe_load", json::JsonValue::from(true));
+    let (_gfab_version, mut db) = util::open_gfab(
+        &opts.gfab,
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        &dbopts,
+    )?;
+
+    {
+        let txn = db.transaction()?;
+        remove_mappings(&txn, opts)?;
+        debug!("flushing {} ...", &opts.gfab);
+        txn.commit()?;
+    }
+
+    Ok(())
+}
+
+fn remove_mappings(db: &rusqlite::Connection, opts: &Opts) -> Result<()> {
+    // formulate WHERE clause from the given criteria (all of which must be met)
+    let mut conditions = Vec::new();
+    let mut args: Vec<Box<dyn ToSql>> = Vec::new();
+    if !opts.refseq.is_empty() {
+        conditions.push(format!(
+            "refseq_name IN ({})",
+            vec!["?"; opts.refseq.len()].join(",")
+        ));
+        for refseq in &opts.refseq {
+            args.push(Box::new(refseq.clone()))
+        }
+    }
+    if let Some(mapping_set) = &opts.mapping_set {
+        util::check_mapping_set(db, "", mapping_set)?;
+        conditions.push(String::from("mapping_set = ?"));
+        args.push(Box::new(mapping_set.clone()))
+    }
+    if opts.unnamed_set {
+        if util::column_exists(db, "", "gfa1_segment_mapping", "mapping_set")? {
+            conditions.push(String::from("mapping_set IS NULL"))
+        } else {
+            // all mappings are in the unnamed set, so removing them needs --all (below)
+            warn!("the .gfab predates mapping sets, so --unnamed-set selects all mappings")
+        }
+    }
+    if !opts.segment.is_empty() {
+        let mut find_segment_by_name =
+            db.prepare("SELECT segment_id FROM gfa1_segment_meta WHERE name = ?")?;
+        for segment in &opts.segment {
+            let segment_id: i64 = match load::name_to_id(segment).filter(|_| !opts.always_names) {
+                Some(id) => id,
+                None => match find_segment_by_name
+                    .query_row(params![segment], |row| row.get(0))
+                    .optional()?
+                {
+                    Some(id) => id,
+                    None => bad_command!("unknown segment {}", segment),
+                },
+            };
+            args.push(Box::new(segment_id))
+        }
+        conditions.push(format!(
+            "segment_id IN ({})",
+            vec!["?"; opts.segment.len()].join(",")
+        ));
+    }
+    if let Some(quality) = opts.quality {
+        conditions.push(format!(
+            "coalesce(json_extract(tags_json, '$.\"mq:i\"'), 255) < {}",
+            quality
+        ));
+    }
+    if conditions.is_empty() && !opts.all {
+        bad_command!(
+            "specify mappings to remove with --refseq, --mapping-set, --unnamed-set, --segment, and/or --quality (or --all)"
+        )
+    }
+
+    let sql = format!(
+        "DELETE FROM gfa1_segment_mapping{}{}",
+        if conditions.is_empty() { "" } else { " WHERE " },
+        conditions.join(" AND ")
+    );
+    debug!("{}", sql);
+    let removed = db.execute(
+        &sql,
+        rusqlite::params_from_iter(args.iter().map(|arg| arg.as_ref())),
+    )?;
+
+    if removed > 0 {
+        debug!("\tANALYZE ...");
+        db.execute_batch("PRAGMA analysis_limit = 1000; ANALYZE main")?;
+    }
+
+    util::record_provenance(db, &[], Some(&format!("removed {} mappings", removed)))?;
+    info!("removed {} mappings", removed);
+    Ok(())
+}
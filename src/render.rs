@@ -0,0 +1,616 @@
+// Self-contained SVG rendering of small graphs, for reports where Bandage isn't available. The
+// layout is a simple layered one: within each connected component, segments are placed in columns
+// by (undirected) BFS distance from a peripheral segment (mirrored if that makes more links go
+// left-to-right), and ordered within each column by the barycenter of their neighbors in the
+// previous column. Segment widths scale with sequence length, and links attach to the segment ends
+// implied by their orientations.
+
+use clap::Clap;
+use json::JsonValue;
+use log::{info, warn};
+use rusqlite::{params, OpenFlags};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::{fs, io};
+
+use crate::util::Result;
+use crate::{bad_command, export, util, view};
+
+#[derive(Clap)]
+pub struct Opts {
+    /// gfab filename or http[s] URL
+    pub gfab: String,
+
+    /// output SVG filename [omit or - for standard output]
+    #[clap(short, default_value = "-")]
+    pub output_svg: String,
+
+    /// Color segments by the value of this tag (e.g. KC or dp:f) instead of guessed reference
+    /// range
+    #[clap(long, name = "TAG")]
+    pub color_by: Option<String>,
+
+    /// Show Paths as colored tracks
+    #[clap(long)]
+    pub paths: bool,
+
+    /// Show Walks as colored tracks
+    #[clap(long)]
+    pub walks: bool,
+
+    /// Refuse to render graphs with more segments than this (extract a subgraph with `gfabase sub`)
+    #[clap(long, default_value = "5000")]
+    pub max_segments: usize,
+
+    /// log extra progress reports
+    #[clap(short, long)]
+    pub verbose: bool,
+
+    /// log errors only
+    #[clap(short, long)]
+    pub quiet: bool,
+}
+
+// layout dimensions (px)
+const MARGIN: f64 = 20.0;
+const SEGMENT_HEIGHT: f64 = 16.0;
+const ROW_PITCH: f64 = 32.0;
+const COLUMN_GAP: f64 = 40.0;
+const COMPONENT_GAP: f64 = 30.0;
+const MIN_SEGMENT_WIDTH: f64 = 6.0;
+const MAX_SEGMENT_WIDTH: f64 = 600.0;
+const LEGEND_PITCH: f64 = 16.0;
+const MAX_LEGEND_ENTRIES: usize = 20;
+
+const PALETTE: &[&str] = &[
+    "#4e79a7", "#f28e2b", "#e15759", "#76b7b2", "#59a14f", "#edc948", "#b07aa1", "#ff9da7",
+    "#9c755f", "#bab0ac",
+];
+const DEFAULT_COLOR: &str = "#c6dbef";
+
+struct Node {
+    segment_id: i64,
+    name: String,
+    length: i64,
+    color: String,
+    tooltip: String,
+    x: f64,
+    y: f64,
+    width: f64,
+}
+
+struct Link {
+    from: usize,
+    from_reverse: bool,
+    to: usize,
+    to_reverse: bool,
+}
+
+// value of the segment by which to color it
+enum ColorValue {
+    Nothing,
+    Category(String),
+    Number(f64),
+}
+
+pub fn main(opts: &Opts) -> Result<()> {
+    let mut dbopts = json::object::Object::new();
+    dbopts.insert("immutable", json::JsonValue::from(true));
+    let (_gfab_version, mut db) = util::open_gfab(
+        &opts.gfab,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        &dbopts,
+    )?;
+    let txn = db.transaction()?;
+
+    let segment_count: i64 =
+        txn.query_row("SELECT count(*) FROM gfa1_segment_meta", [], |row| {
+            row.get(0)
+        })?;
+    if segment_count as usize > opts.max_segments {
+        bad_command!(
+            "graph has {} segments, exceeding --max-segments {}; extract a smaller subgraph with `gfabase sub`",
+            segment_count,
+            opts.max_segments
+        )
+    }
+
+    // load segments & their color values
+    let mut maybe_guesser = if opts.color_by.is_none() {
+        Some(view::SegmentRangeGuesser::new(&txn, "", None, None)?)
+    } else {
+        None
+    };
+    let color_by: Vec<&str> = opts.color_by.iter().map(|tag| tag.as_str()).collect();
+    let mut nodes = Vec::new();
+    let mut color_values = Vec::new();
+    let mut node_index = HashMap::new();
+    {
+        let mut segments_query = txn.prepare(
+            "SELECT segment_id, coalesce(name, cast(segment_id AS TEXT)),
+                    coalesce(sequence_length, 0), coalesce(tags_json, '{}')
+             FROM gfa1_segment_meta ORDER BY segment_id",
+        )?;
+        let mut segments_cursor = segments_query.query([])?;
+        while let Some(segrow) = segments_cursor.next()? {
+            let segment_id: i64 = segrow.get(0)?;
+            let name: String = segrow.get(1)?;
+            let length: i64 = segrow.get(2)?;
+            let mut tooltip = format!("{} ({} nt)", name, length);
+            let color_value = if let Some(ref mut guesser) = maybe_guesser {
+                match guesser.get(segment_id)? {
+                    Some(gr) => {
+                        tooltip += &format!("\n{}", gr);
+                        // color by the reference sequence name
+                        let refseq = gr.trim_start_matches('~');
+                        ColorValue::Category(String::from(
+                            refseq.rsplit_once(':').map_or(refseq, |(name, _)| name),
+                        ))
+                    }
+                    None => ColorValue::Nothing,
+                }
+            } else {
+                let tags_json: String = segrow.get(3)?;
+                let tags = json::parse(&tags_json).map_err(|_| util::Error::InvalidGfab {
+                    message: String::from("invalid tags_json"),
+                    table: String::from("gfa1_segment_meta"),
+                    rowid: segment_id,
+                })?;
+                match view::select_tags(&color_by, Some(length), &tags)
+                    .entries()
+                    .next()
+                {
+                    Some((k, v)) => {
+                        tooltip += &format!("\n{}:{}", k, v);
+                        match v {
+                            JsonValue::Number(_) => ColorValue::Number(v.as_f64().unwrap()),
+                            _ => ColorValue::Category(
+                                v.as_str().map(String::from).unwrap_or_else(|| v.dump()),
+                            ),
+                        }
+                    }
+                    None => ColorValue::Nothing,
+                }
+            };
+            node_index.insert(segment_id, nodes.len());
+            color_values.push(color_value);
+            nodes.push(Node {
+                segment_id,
+                name,
+                length,
+                color: String::from(DEFAULT_COLOR),
+                tooltip,
+                x: 0.0,
+                y: 0.0,
+                width: 0.0,
+            })
+        }
+    }
+    std::mem::drop(maybe_guesser);
+    let legend = assign_colors(&mut nodes, &color_values);
+
+    let mut links = Vec::new();
+    {
+        let mut links_query = txn
+            .prepare("SELECT from_segment, from_reverse, to_segment, to_reverse FROM gfa1_link")?;
+        let mut links_cursor = links_query.query([])?;
+        while let Some(linkrow) = links_cursor.next()? {
+            let from: i64 = linkrow.get(0)?;
+            let to: i64 = linkrow.get(2)?;
+            if let (Some(&from), Some(&to)) = (node_index.get(&from), node_index.get(&to)) {
+                links.push(Link {
+                    from,
+                    from_reverse: linkrow.get::<_, i64>(1)? != 0,
+                    to,
+                    to_reverse: linkrow.get::<_, i64>(3)? != 0,
+                })
+            }
+        }
+    }
+
+    let (width, height) = layout(&mut nodes, &links);
+    let tracks = load_tracks(&txn, opts, &node_index)?;
+    info!(
+        "rendering {} segments, {} links, {} tracks",
+        nodes.len(),
+        links.len(),
+        tracks.len()
+    );
+
+    let mut writer_box = writer(&opts.output_svg)?;
+    write_svg(
+        &nodes,
+        &links,
+        &tracks,
+        &legend,
+        width,
+        height,
+        &mut *writer_box,
+    )?;
+    writer_box.flush()?;
+    Ok(())
+}
+
+fn writer(filename: &str) -> Result<Box<dyn io::Write>> {
+    if filename.is_empty() || filename == "-" {
+        return Ok(Box::new(io::BufWriter::new(io::stdout())));
+    }
+    if !filename.ends_with(".svg") {
+        warn!("output filename should end with .svg")
+    }
+    Ok(Box::new(io::BufWriter::new(fs::File::create(filename)?)))
+}
+
+// set node colors from the values, either categorical or a gradient for numbers. Returns legend
+// entries (label, color).
+fn assign_colors(nodes: &mut [Node], values: &[ColorValue]) -> Vec<(String, String)> {
+    let numbers: Vec<f64> = values
+        .iter()
+        .filter_map(|v| match v {
+            ColorValue::Number(x) => Some(*x),
+            _ => None,
+        })
+        .collect();
+    let any_category = values.iter().any(|v| matches!(v, ColorValue::Category(_)));
+    if !numbers.is_empty() && !any_category {
+        let lo = numbers.iter().cloned().fold(f64::INFINITY, f64::min);
+        let hi = numbers.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+        for (node, value) in nodes.iter_mut().zip(values) {
+            if let ColorValue::Number(x) = value {
+                let t = if hi > lo { (x - lo) / (hi - lo) } else { 0.5 };
+                node.color = gradient(t)
+            }
+        }
+        return vec![
+            (format!("{}", lo), gradient(0.0)),
+            (format!("{}", hi), gradient(1.0)),
+        ];
+    }
+
+    // categories, most frequent first
+    let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+    for value in values {
+        match value {
+            ColorValue::Category(c) => *counts.entry(c.clone()).or_default() += 1,
+            ColorValue::Number(x) => *counts.entry(x.to_string()).or_default() += 1,
+            ColorValue::Nothing => (),
+        }
+    }
+    let mut categories: Vec<(String, usize)> = counts.into_iter().collect();
+    categories.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    let category_colors: HashMap<&str, &str> = categories
+        .iter()
+        .enumerate()
+        .map(|(i, (c, _))| (c.as_str(), PALETTE[i % PALETTE.len()]))
+        .collect();
+    for (node, value) in nodes.iter_mut().zip(values) {
+        let category = match value {
+            ColorValue::Category(c) => c.clone(),
+            ColorValue::Number(x) => x.to_string(),
+            ColorValue::Nothing => continue,
+        };
+        node.color = String::from(category_colors[category.as_str()])
+    }
+    if categories.len() > PALETTE.len() {
+        warn!(
+            "{} distinct colors requested, reusing palette of {}",
+            categories.len(),
+            PALETTE.len()
+        )
+    }
+    categories
+        .iter()
+        .take(MAX_LEGEND_ENTRIES)
+        .map(|(c, _)| (c.clone(), String::from(category_colors[c.as_str()])))
+        .collect()
+}
+
+// blue-to-red gradient for t in [0, 1]
+fn gradient(t: f64) -> String {
+    let (lo, hi) = ([0x2c, 0x7b, 0xb6], [0xd7, 0x19, 0x1c]);
+    let mix = |i: usize| (lo[i] as f64 + t * (hi[i] as f64 - lo[i] as f64)).round() as u8;
+    format!("#{:02x}{:02x}{:02x}", mix(0), mix(1), mix(2))
+}
+
+// BFS over the undirected graph from start, setting distances (which should be usize::MAX for all
+// nodes in start's component beforehand). Returns the nodes in visiting order.
+fn bfs(adjacency: &[Vec<usize>], start: usize, distance: &mut [usize]) -> Vec<usize> {
+    let mut order = vec![start];
+    let mut queue = VecDeque::from(vec![start]);
+    distance[start] = 0;
+    while let Some(v) = queue.pop_front() {
+        for &u in &adjacency[v] {
+            if distance[u] == usize::MAX {
+                distance[u] = distance[v] + 1;
+                order.push(u);
+                queue.push_back(u)
+            }
+        }
+    }
+    order
+}
+
+// set node positions & widths, returning the overall (width, height)
+fn layout(nodes: &mut [Node], links: &[Link]) -> (f64, f64) {
+    let mut adjacency = vec![Vec::new(); nodes.len()];
+    for link in links {
+        if link.from != link.to {
+            adjacency[link.from].push(link.to);
+            adjacency[link.to].push(link.from);
+        }
+    }
+
+    // segment widths scale with sequence length, so that the longest is MAX_SEGMENT_WIDTH
+    let max_length = nodes.iter().map(|node| node.length).max().unwrap_or(0);
+    let nt_per_px = f64::max(1.0, max_length as f64 / MAX_SEGMENT_WIDTH);
+    for node in nodes.iter_mut() {
+        node.width = (node.length as f64 / nt_per_px).max(MIN_SEGMENT_WIDTH)
+    }
+
+    let mut seen = vec![usize::MAX; nodes.len()];
+    let mut column_of = vec![usize::MAX; nodes.len()];
+    let mut component_of = vec![usize::MAX; nodes.len()];
+    let mut row_of = vec![0.0; nodes.len()];
+    let (mut width, mut y) = (0.0, MARGIN);
+    for start in 0..nodes.len() {
+        if seen[start] != usize::MAX {
+            continue;
+        }
+        // the last node visited by BFS is (roughly) peripheral; lay out columns by distance from it
+        let component = bfs(&adjacency, start, &mut seen);
+        let order = bfs(&adjacency, *component.last().unwrap(), &mut column_of);
+        let mut columns: Vec<Vec<usize>> = Vec::new();
+        for v in order {
+            let c = column_of[v];
+            if columns.len() <= c {
+                columns.resize(c + 1, Vec::new())
+            }
+            columns[c].push(v)
+        }
+        // mirror the columns if that makes more links (between forward or between reverse
+        // segments) go left-to-right
+        for &v in &component {
+            component_of[v] = start
+        }
+        let mut votes = 0;
+        for link in links {
+            if component_of[link.from] == start && link.from_reverse == link.to_reverse {
+                let (from, to) = (column_of[link.from], column_of[link.to]);
+                if (from < to) != link.from_reverse && from != to {
+                    votes += 1
+                } else if from != to {
+                    votes -= 1
+                }
+            }
+        }
+        if votes < 0 {
+            columns.reverse();
+            for (c, column) in columns.iter().enumerate() {
+                for &v in column {
+                    column_of[v] = c
+                }
+            }
+        }
+        // order each column by the barycenter of neighbors in the previous column
+        for (c, column) in columns.iter_mut().enumerate() {
+            if c > 0 {
+                let barycenter = |v: usize, fallback: f64| {
+                    let prev: Vec<f64> = adjacency[v]
+                        .iter()
+                        .filter(|&&u| column_of[u] + 1 == c)
+                        .map(|&u| row_of[u])
+                        .collect();
+                    if prev.is_empty() {
+                        fallback
+                    } else {
+                        prev.iter().sum::<f64>() / prev.len() as f64
+                    }
+                };
+                let mut keyed: Vec<(f64, usize)> = column
+                    .iter()
+                    .enumerate()
+                    .map(|(r, &v)| (barycenter(v, r as f64), v))
+                    .collect();
+                keyed.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap().then(a.1.cmp(&b.1)));
+                *column = keyed.into_iter().map(|(_, v)| v).collect();
+            }
+            for (r, &v) in column.iter().enumerate() {
+                row_of[v] = r as f64
+            }
+        }
+        // assign coordinates
+        let mut x = MARGIN;
+        for column in &columns {
+            let mut column_width: f64 = 0.0;
+            for &v in column {
+                nodes[v].x = x;
+                nodes[v].y = y + row_of[v] * ROW_PITCH;
+                column_width = column_width.max(nodes[v].width)
+            }
+            x += column_width + COLUMN_GAP
+        }
+        width = f64::max(width, x - COLUMN_GAP + MARGIN);
+        let rows = columns.iter().map(|column| column.len()).max().unwrap_or(1);
+        y += (rows - 1) as f64 * ROW_PITCH + SEGMENT_HEIGHT + COMPONENT_GAP
+    }
+    (width, y - COMPONENT_GAP + MARGIN)
+}
+
+// (name, node indices) of each Path and/or Walk to show
+fn load_tracks(
+    db: &rusqlite::Connection,
+    opts: &Opts,
+    node_index: &HashMap<i64, usize>,
+) -> Result<Vec<(String, Vec<usize>)>> {
+    let mut tracks = Vec::new();
+    if opts.paths {
+        let mut paths_query = db.prepare(
+            "SELECT path_id, coalesce(name, cast(path_id AS TEXT)) FROM gfa1_path ORDER BY path_id",
+        )?;
+        let mut elements_query = db.prepare(
+            "SELECT segment_id FROM gfa1_path_element WHERE path_id = ? ORDER BY ordinal",
+        )?;
+        let mut paths_cursor = paths_query.query([])?;
+        while let Some(pathrow) = paths_cursor.next()? {
+            let path_id: i64 = pathrow.get(0)?;
+            let mut elements = Vec::new();
+            let mut elements_cursor = elements_query.query(params![path_id])?;
+            while let Some(elrow) = elements_cursor.next()? {
+                if let Some(&v) = node_index.get(&elrow.get::<_, i64>(0)?) {
+                    elements.push(v)
+                }
+            }
+            tracks.push((pathrow.get(1)?, elements))
+        }
+    }
+    if opts.walks {
+        let mut walks_query = db.prepare(
+            "SELECT walk_id, sample, hap_idx, refseq_name, refseq_begin, refseq_end
+             FROM gfa1_walk ORDER BY walk_id",
+        )?;
+        let mut iter_walk_query = view::prepare_iter_walk(db)?;
+        let mut walks_cursor = walks_query.query([])?;
+        while let Some(walkrow) = walks_cursor.next()? {
+            let walk_id: i64 = walkrow.get(0)?;
+            let name = format!(
+                "{}#{}#{}:{}-{}",
+                walkrow.get::<_, String>(1)?,
+                walkrow.get::<_, i64>(2)?,
+                walkrow.get::<_, String>(3)?,
+                walkrow.get::<_, i64>(4)? + 1,
+                walkrow.get::<_, i64>(5)?
+            );
+            let mut steps = Vec::new();
+            view::iter_walk(&mut iter_walk_query, walk_id, |segment_id, _| {
+                if let Some(&v) = node_index.get(&segment_id) {
+                    steps.push(v)
+                }
+                Ok(true)
+            })?;
+            tracks.push((name, steps))
+        }
+    }
+    Ok(tracks)
+}
+
+fn write_svg(
+    nodes: &[Node],
+    links: &[Link],
+    tracks: &[(String, Vec<usize>)],
+    legend: &[(String, String)],
+    width: f64,
+    height: f64,
+    writer: &mut dyn io::Write,
+) -> Result<()> {
+    let track_legend: Vec<(String, String)> = tracks
+        .iter()
+        .enumerate()
+        .take(MAX_LEGEND_ENTRIES)
+        .map(|(i, (name, _))| (name.clone(), String::from(PALETTE[i % PALETTE.len()])))
+        .collect();
+    let legend_height = (legend.len() + track_legend.len()) as f64 * LEGEND_PITCH;
+    writer.write_fmt(format_args!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w:.0}\" height=\"{h:.0}\" \
+         viewBox=\"0 0 {w:.0} {h:.0}\" font-family=\"sans-serif\" font-size=\"10\">\n",
+        w = f64::max(width, 300.0),
+        h = height + legend_height
+    ))?;
+
+    // links, as curves leaving the end of the from segment and entering the start of the to
+    // segment, w.r.t. their orientations
+    writer.write_all(b"<g fill=\"none\" stroke=\"#888\" stroke-width=\"1\">\n")?;
+    for link in links {
+        let (from, to) = (&nodes[link.from], &nodes[link.to]);
+        let (x1, dx1) = if link.from_reverse {
+            (from.x, -1.0)
+        } else {
+            (from.x + from.width, 1.0)
+        };
+        let (x2, dx2) = if link.to_reverse {
+            (to.x + to.width, 1.0)
+        } else {
+            (to.x, -1.0)
+        };
+        let (y1, y2) = (from.y + SEGMENT_HEIGHT / 2.0, to.y + SEGMENT_HEIGHT / 2.0);
+        let bend = COLUMN_GAP / 2.0;
+        writer.write_fmt(format_args!(
+            "<path d=\"M{:.1},{:.1} C{:.1},{:.1} {:.1},{:.1} {:.1},{:.1}\"/>\n",
+            x1,
+            y1,
+            x1 + dx1 * bend,
+            y1,
+            x2 + dx2 * bend,
+            y2,
+            x2,
+            y2
+        ))?;
+    }
+    writer.write_all(b"</g>\n")?;
+
+    // segments, labeled if the name fits
+    writer.write_all(b"<g stroke=\"#444\" stroke-width=\"0.5\">\n")?;
+    for node in nodes {
+        writer.write_fmt(format_args!(
+            "<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{}\" rx=\"2\" fill=\"{}\" \
+             data-segment-id=\"{}\"><title>{}</title></rect>\n",
+            node.x,
+            node.y,
+            node.width,
+            SEGMENT_HEIGHT,
+            node.color,
+            node.segment_id,
+            export::xml_escape(&node.tooltip)
+        ))?;
+    }
+    writer.write_all(b"</g>\n<g text-anchor=\"middle\" pointer-events=\"none\">\n")?;
+    for node in nodes {
+        if node.width >= 6.0 * node.name.chars().count() as f64 + 4.0 {
+            writer.write_fmt(format_args!(
+                "<text x=\"{:.1}\" y=\"{:.1}\">{}</text>\n",
+                node.x + node.width / 2.0,
+                node.y + SEGMENT_HEIGHT / 2.0 + 3.5,
+                export::xml_escape(&node.name)
+            ))?;
+        }
+    }
+    writer.write_all(b"</g>\n")?;
+
+    // tracks, as polylines through the segments, offset to reduce overlapping
+    for (i, (name, steps)) in tracks.iter().enumerate() {
+        let offset = 3.0 + (i % 4) as f64 * 3.0;
+        let points: Vec<String> = steps
+            .iter()
+            .map(|&v| {
+                format!(
+                    "{:.1},{:.1}",
+                    nodes[v].x + nodes[v].width / 2.0,
+                    nodes[v].y + offset
+                )
+            })
+            .collect();
+        writer.write_fmt(format_args!(
+            "<polyline points=\"{}\" fill=\"none\" stroke=\"{}\" stroke-width=\"2\" \
+             stroke-opacity=\"0.7\"><title>{}</title></polyline>\n",
+            points.join(" "),
+            PALETTE[i % PALETTE.len()],
+            export::xml_escape(name)
+        ))?;
+    }
+
+    // legend
+    let mut y = height;
+    for (label, color) in legend.iter().chain(track_legend.iter()) {
+        writer.write_fmt(format_args!(
+            "<rect x=\"{:.1}\" y=\"{:.1}\" width=\"10\" height=\"10\" fill=\"{}\"/>\
+             <text x=\"{:.1}\" y=\"{:.1}\">{}</text>\n",
+            MARGIN,
+            y,
+            color,
+            MARGIN + 14.0,
+            y + 9.0,
+            export::xml_escape(label)
+        ))?;
+        y += LEGEND_PITCH
+    }
+    writer.write_all(b"</svg>\n")?;
+    Ok(())
+}
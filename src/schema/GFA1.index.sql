@@ -1,6 +1,9 @@
 CREATE UNIQUE INDEX gfa1_segment_name ON
     gfa1_segment_meta(name) WHERE name IS NOT NULL;
 
+CREATE INDEX gfa1_segment_sequence_hash ON
+    gfa1_segment_sequence(sequence_hash);
+
 CREATE INDEX gfa1_segment_mapping_segment ON
     gfa1_segment_mapping(segment_id);
 
@@ -0,0 +1,16 @@
+-- gfabase extension: k-mer index of segment sequences for approximate sequence search. Rather than
+-- every k-mer, we keep the (w,k)-minimizers: the k-mer with the smallest canonical (orientation-
+-- independent) hash among each w consecutive k-mers of the segment sequence.
+CREATE TABLE gfa1_segment_kmer(
+    kmer_hash INTEGER NOT NULL,  -- see kmers::minimizers
+    segment_id INTEGER NOT NULL
+        REFERENCES gfa1_segment_meta(segment_id),
+    position INTEGER NOT NULL,   -- zero-based offset of the k-mer in the segment sequence
+    PRIMARY KEY (kmer_hash, segment_id, position)
+) WITHOUT ROWID;
+
+-- parameters of the above
+CREATE TABLE gfa1_segment_kmer_params(
+    k INTEGER NOT NULL,
+    w INTEGER NOT NULL
+);
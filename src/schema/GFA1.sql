@@ -10,7 +10,9 @@ CREATE TABLE gfa1_segment_meta(
 CREATE TABLE gfa1_segment_sequence(
     segment_id INTEGER NOT NULL PRIMARY KEY
         REFERENCES gfa1_segment_meta(segment_id),
-    sequence_twobit BLOB NOT NULL          -- not null: omit row if no sequence available
+    sequence_twobit BLOB NOT NULL,         -- not null: omit row if no sequence available
+    sequence_hash INTEGER                  -- orientation-independent hash for lookup by content
+                                           -- (see load::sequence_hash)
 );
 
 -- Convenience: view joining segment_meta & segment_sequence
@@ -30,7 +32,23 @@ CREATE TABLE gfa1_segment_mapping(
     refseq_name TEXT NOT NULL COLLATE UINT,  -- associated reference sequence (e.g. chromosome name)
     refseq_begin INTEGER NOT NULL,           -- zero-based begin of associated range
     refseq_end INTEGER NOT NULL,             -- end (exclusive) of associated range
-    tags_json TEXT                           -- extra info e.g. orientation, segment begin/end
+    tags_json TEXT,                          -- extra info e.g. orientation, segment begin/end
+    cigar TEXT,                              -- alignment of segment range (oriented) to refseq range,
+                                             -- as M/I/D operations only (if available)
+    mapping_set TEXT                         -- name of the set of mappings (e.g. to one reference
+                                             -- assembly) added together; NULL for the unnamed set
+);
+
+-- gfabase extension: log of the gfabase commands that built & modified the .gfab, in order (see
+-- util::record_provenance)
+CREATE TABLE gfa1_provenance(
+    provenance_id INTEGER PRIMARY KEY,
+    timestamp TEXT NOT NULL,        -- UTC, RFC 3339
+    gfabase_version TEXT NOT NULL,
+    command_line TEXT NOT NULL,
+    inputs_json TEXT,               -- input files as [{"name":"in.gfa","size":1234},...] (size
+                                    -- omitted for standard input & URLs)
+    notes TEXT                      -- e.g. summary of changes made in-place
 );
 
 -- Link
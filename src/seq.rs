@@ -0,0 +1,279 @@
+// Retrieval of segment sub-sequences. Rather than decoding the whole sequence_twobit value (which
+// for a long contig segment, may be many megabytes, and over HTTP would all be downloaded), use
+// SQLite incremental blob I/O to read only the bytes covering the desired range.
+//
+// GenomicSQLite two-bit encoding: the first byte holds the number of unused two-bit slots at the
+// end of the last byte (0-3), followed by four nucleotides per byte, most significant bits first,
+// with T=0 C=1 A=2 G=3. As a special case, a single nucleotide is encoded as one byte holding its
+// code. Sequences with other characters are stored as text instead.
+
+use clap::Clap;
+use log::warn;
+use regex::Regex;
+use rusqlite::functions::FunctionFlags;
+use rusqlite::{params, DatabaseName, OpenFlags, OptionalExtension};
+use std::{cmp, fs, io};
+
+use crate::util::Result;
+use crate::{bad_command, load, util};
+
+#[derive(Clap)]
+pub struct Opts {
+    /// gfab filename or http[s] URL
+    pub gfab: String,
+
+    /// Desired segment sequence(s), optionally with a range (one-based, inclusive) and/or +/-
+    /// suffix for orientation, e.g. 12345:1001-2000-
+    #[clap(name = "SEGMENT:BEGIN-END[+/-]", required = true)]
+    pub ranges: Vec<String>,
+
+    /// output FASTA filename [omit or - for standard output]
+    #[clap(short, default_value = "-")]
+    pub output_fasta: String,
+
+    /// Treat SEGMENTs as text names even if they look like integer IDs
+    #[clap(long)]
+    pub always_names: bool,
+
+    /// FASTA line width (0 for no line breaks)
+    #[clap(long, default_value = "60")]
+    pub line_width: usize,
+
+    /// log extra progress reports
+    #[clap(short, long)]
+    pub verbose: bool,
+
+    /// log errors only
+    #[clap(short, long)]
+    pub quiet: bool,
+}
+
+pub fn main(opts: &Opts) -> Result<()> {
+    let mut dbopts = json::object::Object::new();
+    dbopts.insert("immutable", json::JsonValue::from(true));
+    let (_gfab_version, db) = util::open_gfab(
+        &opts.gfab,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        &dbopts,
+    )?;
+
+    let range_re = Regex::new(r"^(.+?)(:([0-9,]+)-([0-9,]+))?([+-])?$").unwrap();
+    let mut find_segment_by_name =
+        db.prepare("SELECT segment_id FROM gfa1_segment_meta WHERE name = ?")?;
+    let mut get_segment_dna = db.prepare("SELECT segment_dna(?, ?, ?, ?)")?;
+    let mut writer = fasta_writer(&opts.output_fasta)?;
+    for range in &opts.ranges {
+        let caps = match range_re.captures(range) {
+            Some(caps) => caps,
+            None => bad_command!("invalid segment range: {}", range),
+        };
+        let segment = caps.get(1).unwrap().as_str();
+        let reverse = caps.get(5).map(|strand| strand.as_str()) == Some("-");
+
+        let segment_id = match load::name_to_id(segment).filter(|_| !opts.always_names) {
+            Some(id) => id,
+            None => match find_segment_by_name
+                .query_row(params![segment], |row| row.get(0))
+                .optional()?
+            {
+                Some(id) => id,
+                None => bad_command!("unknown segment {}", segment),
+            },
+        };
+        let length = match segment_sequence_length(&db, "", segment_id)? {
+            Some(length) => length,
+            None => bad_command!("no sequence available for segment {}", segment),
+        };
+        let (begin, end) = match (caps.get(3), caps.get(4)) {
+            (Some(begin), Some(end)) => {
+                let parse = |s: &str| s.replace(",", "").parse::<i64>();
+                match (parse(begin.as_str()), parse(end.as_str())) {
+                    (Ok(begin), Ok(end)) if 1 <= begin && begin <= end && end <= length => {
+                        (begin - 1, end)
+                    }
+                    _ => bad_command!("invalid range {} for segment of length {}", range, length),
+                }
+            }
+            _ => (0, length),
+        };
+
+        let dna: Option<String> = get_segment_dna
+            .query_row(params![segment_id, begin, end, reverse as i64], |row| {
+                row.get(0)
+            })?;
+        let dna = dna.unwrap_or_default();
+        write_fasta(&mut writer, range, &dna, opts.line_width)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn fasta_writer(filename: &str) -> Result<Box<dyn io::Write>> {
+    if filename.is_empty() || filename == "-" {
+        return Ok(Box::new(io::BufWriter::new(io::stdout())));
+    }
+    if !filename.ends_with(".fa") && !filename.ends_with(".fasta") {
+        warn!("output filename should end with .fa or .fasta")
+    }
+    Ok(Box::new(io::BufWriter::new(fs::File::create(filename)?)))
+}
+
+pub fn write_fasta(
+    writer: &mut dyn io::Write,
+    name: &str,
+    sequence: &str,
+    line_width: usize,
+) -> Result<()> {
+    writer.write_fmt(format_args!(">{}\n", name))?;
+    if line_width == 0 || sequence.is_empty() {
+        writer.write_fmt(format_args!("{}\n", sequence))?;
+    } else {
+        for line in sequence.as_bytes().chunks(line_width) {
+            writer.write_all(line)?;
+            writer.write_all(b"\n")?;
+        }
+    }
+    Ok(())
+}
+
+pub fn dna_revcomp(dna: &str) -> String {
+    dna.chars()
+        .rev()
+        .map(|ch| match ch {
+            'A' => 'T',
+            'C' => 'G',
+            'G' => 'C',
+            'T' => 'A',
+            'a' => 't',
+            'c' => 'g',
+            'g' => 'c',
+            't' => 'a',
+            other => other,
+        })
+        .collect()
+}
+
+// Open the segment's sequence_twobit blob, also indicating whether it's two-bit encoded (as opposed
+// to text). None if the segment has no sequence.
+fn open_sequence<'a>(
+    db: &'a rusqlite::Connection,
+    schema: &str,
+    segment_id: i64,
+) -> Result<Option<(rusqlite::blob::Blob<'a>, bool)>> {
+    // typeof() doesn't need to read the blob
+    let maybe_type: Option<String> = db
+        .query_row(
+            &format!(
+                "SELECT typeof(sequence_twobit) FROM {}gfa1_segment_sequence WHERE segment_id = ?",
+                schema
+            ),
+            params![segment_id],
+            |row| row.get(0),
+        )
+        .optional()?;
+    let twobit = match maybe_type.as_deref() {
+        None | Some("null") => return Ok(None),
+        Some(ty) => ty == "blob",
+    };
+    let dbname = match schema.trim_end_matches('.') {
+        "" => DatabaseName::Main,
+        s => DatabaseName::Attached(s),
+    };
+    let blob = db.blob_open(
+        dbname,
+        "gfa1_segment_sequence",
+        "sequence_twobit",
+        segment_id,
+        true,
+    )?;
+    Ok(Some((blob, twobit)))
+}
+
+fn twobit_length(blob: &rusqlite::blob::Blob) -> Result<i64> {
+    if blob.len() <= 1 {
+        return Ok(blob.len() as i64);
+    }
+    let mut header = [0u8; 1];
+    blob.read_at_exact(&mut header, 0)?;
+    Ok(4 * (blob.len() as i64 - 1) - header[0] as i64)
+}
+
+pub fn segment_sequence_length(
+    db: &rusqlite::Connection,
+    schema: &str,
+    segment_id: i64,
+) -> Result<Option<i64>> {
+    Ok(match open_sequence(db, schema, segment_id)? {
+        Some((blob, true)) => Some(twobit_length(&blob)?),
+        Some((blob, false)) => Some(blob.len() as i64),
+        None => None,
+    })
+}
+
+// Get the segment's sequence in the zero-based, half-open range [begin, end) (clipped to the
+// sequence length), reverse-complemented if desired. None if the segment has no sequence.
+pub fn segment_dna(
+    db: &rusqlite::Connection,
+    schema: &str,
+    segment_id: i64,
+    begin: i64,
+    end: i64,
+    reverse: bool,
+) -> Result<Option<String>> {
+    let (blob, twobit) = match open_sequence(db, schema, segment_id)? {
+        Some(it) => it,
+        None => return Ok(None),
+    };
+    let length = if twobit {
+        twobit_length(&blob)?
+    } else {
+        blob.len() as i64
+    };
+    let begin = cmp::max(0, begin) as usize;
+    let end = cmp::min(length, end) as usize;
+    if begin >= end {
+        return Ok(Some(String::new()));
+    }
+
+    let dna = if !twobit {
+        let mut buf = vec![0u8; end - begin];
+        blob.read_at_exact(&mut buf, begin)?;
+        String::from_utf8(buf).map_err(|e| e.utf8_error())?
+    } else if blob.len() == 1 {
+        let mut buf = [0u8; 1];
+        blob.read_at_exact(&mut buf, 0)?;
+        String::from(TWOBIT_NUCLEOTIDES[(buf[0] & 3) as usize] as char)
+    } else {
+        let first_byte = 1 + begin / 4;
+        let mut buf = vec![0u8; 1 + (end - 1) / 4 - first_byte + 1];
+        blob.read_at_exact(&mut buf, first_byte)?;
+        (begin..end)
+            .map(|i| {
+                let byte = buf[1 + i / 4 - first_byte];
+                TWOBIT_NUCLEOTIDES[((byte >> (6 - 2 * (i % 4))) & 3) as usize] as char
+            })
+            .collect()
+    };
+    Ok(Some(if reverse { dna_revcomp(&dna) } else { dna }))
+}
+
+const TWOBIT_NUCLEOTIDES: &[u8; 4] = b"TCAG";
+
+// Register SQL function segment_dna(segment_id, begin, end[, reverse]) on the connection, with the
+// arguments of the above (for the main database)
+pub fn register_sql_functions(db: &rusqlite::Connection) -> Result<()> {
+    for n_arg in [3, 4].iter() {
+        db.create_scalar_function("segment_dna", *n_arg, FunctionFlags::SQLITE_UTF8, |ctx| {
+            let segment_id: i64 = ctx.get(0)?;
+            let begin: i64 = ctx.get(1)?;
+            let end: i64 = ctx.get(2)?;
+            let reverse = ctx.len() > 3 && ctx.get::<i64>(3)? != 0;
+            // safety: the borrowed connection is the one invoking us, which outlives this call; we
+            // only run read queries and open a read-only blob on it, all finished before returning
+            let db = unsafe { ctx.get_connection()? };
+            segment_dna(&db, "", segment_id, begin, end, reverse)
+                .map_err(|e| rusqlite::Error::UserFunctionError(Box::new(e)))
+        })?;
+    }
+    Ok(())
+}
@@ -0,0 +1,322 @@
+use clap::Clap;
+use log::warn;
+use rusqlite::{params, OpenFlags, OptionalExtension};
+
+use crate::util::Result;
+use crate::{bad_command, load, seq, util, validate, view};
+
+#[derive(Clap)]
+pub struct Opts {
+    /// gfab filename or http[s] URL
+    pub gfab: String,
+
+    /// Spell the Path with this name
+    #[clap(long, name = "NAME")]
+    pub path: Option<String>,
+
+    /// Spell the Walk(s) of this haplotype sequence
+    #[clap(long, name = "SAMPLE#HAP#CONTIG")]
+    pub walk: Option<String>,
+
+    /// Only spell this range (one-based, inclusive) of the path sequence, or of the walk contig
+    /// coordinates
+    #[clap(long, name = "B-E")]
+    pub range: Option<String>,
+
+    /// output FASTA filename [omit or - for standard output]
+    #[clap(short, default_value = "-")]
+    pub output_fasta: String,
+
+    /// FASTA line width (0 for no line breaks)
+    #[clap(long, default_value = "60")]
+    pub line_width: usize,
+
+    /// log extra progress reports
+    #[clap(short, long)]
+    pub verbose: bool,
+
+    /// log errors only
+    #[clap(short, long)]
+    pub quiet: bool,
+}
+
+pub fn main(opts: &Opts) -> Result<()> {
+    if opts.path.is_some() == opts.walk.is_some() {
+        bad_command!("specify either --path or --walk");
+    }
+    // desired range, zero-based & half-open
+    let range = match &opts.range {
+        Some(range) => {
+            let parse = |s: &str| s.replace(",", "").parse::<i64>().ok();
+            let mut parts = range.split('-');
+            match (parts.next().and_then(parse), parts.next().and_then(parse)) {
+                (Some(begin), Some(end))
+                    if parts.next().is_none() && 1 <= begin && begin <= end =>
+                {
+                    Some((begin - 1, end))
+                }
+                _ => bad_command!("invalid range {} (expected e.g. 1,001-2,000)", range),
+            }
+        }
+        None => None,
+    };
+
+    let mut dbopts = json::object::Object::new();
+    dbopts.insert("immutable", json::JsonValue::from(true));
+    let (_gfab_version, db) = util::open_gfab(
+        &opts.gfab,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        &dbopts,
+    )?;
+
+    let mut writer = seq::fasta_writer(&opts.output_fasta)?;
+    if let Some(path) = &opts.path {
+        let pieces = path_pieces(&db, path)?;
+        let (begin, end) = range.unwrap_or((0, i64::MAX));
+        let (dna, spelled_end) = spell(&db, &pieces, begin, end)?;
+        if range.is_some() && end > spelled_end {
+            bad_command!(
+                "range exceeds path {} sequence length {}",
+                path,
+                spelled_end
+            )
+        }
+        let name = match range {
+            Some((begin, end)) => format!("{}:{}-{}", path, begin + 1, end),
+            None => path.clone(),
+        };
+        seq::write_fasta(&mut writer, &name, &dna, opts.line_width)?;
+    } else if let Some(walk) = &opts.walk {
+        let fields: Vec<&str> = walk.split('#').collect();
+        let hap_idx = fields.get(1).and_then(|h| h.parse::<i64>().ok());
+        if fields.len() != 3 || hap_idx.is_none() {
+            bad_command!("invalid walk {} (expected SAMPLE#HAP#CONTIG)", walk)
+        }
+        let mut walks_query = db.prepare(
+            "SELECT walk_id, refseq_begin, refseq_end FROM gfa1_walk
+             WHERE sample = ? AND hap_idx = ? AND refseq_name = ? ORDER BY refseq_begin",
+        )?;
+        let mut walks_cursor = walks_query.query(params![fields[0], hap_idx, fields[2]])?;
+        let mut found = false;
+        while let Some(row) = walks_cursor.next()? {
+            let walk_id: i64 = row.get(0)?;
+            let refseq_begin: i64 = row.get(1)?;
+            let refseq_end: i64 = row.get(2)?;
+            found = true;
+            // walk sequence offsets of the desired range in contig coordinates
+            let (begin, end) = match range {
+                Some((begin, end)) if begin < refseq_end && end > refseq_begin => {
+                    (std::cmp::max(0, begin - refseq_begin), end - refseq_begin)
+                }
+                Some(_) => continue,
+                None => (0, i64::MAX),
+            };
+            let pieces = walk_pieces(&db, walk_id)?;
+            let (dna, spelled_end) = spell(&db, &pieces, begin, end)?;
+            if refseq_end - refseq_begin != spelled_end {
+                warn!(
+                    "walk {}:{}-{} spells {} nt, inconsistent with its contig coordinates",
+                    walk,
+                    refseq_begin + 1,
+                    refseq_end,
+                    spelled_end
+                )
+            }
+            let spelled_begin = refseq_begin + std::cmp::min(begin, spelled_end);
+            let name = format!(
+                "{}:{}-{}",
+                walk,
+                spelled_begin + 1,
+                spelled_begin + dna.len() as i64
+            );
+            seq::write_fasta(&mut writer, &name, &dna, opts.line_width)?;
+        }
+        if !found {
+            bad_command!("unknown walk {}", walk)
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+// One oriented segment of a path or walk, trimming its overlap with the preceding one
+pub struct Piece {
+    pub segment_id: i64,
+    pub reverse: bool,
+    pub length: i64,
+    pub trim: i64,
+}
+
+// length of the overlap in the second segment, as described by the CIGAR (in which the first
+// segment is the reference)
+fn overlap_length(cigar: &str) -> Result<i64> {
+    let mut ans = 0;
+    let mut num = String::new();
+    for ch in cigar.chars() {
+        if ch.is_ascii_digit() {
+            num.push(ch)
+        } else {
+            let n: i64 = match num.parse() {
+                Ok(n) => n,
+                Err(_) => bad_command!("unsupported overlap CIGAR {}", cigar),
+            };
+            match ch {
+                'M' | 'I' | 'S' | '=' | 'X' => ans += n,
+                'D' | 'N' | 'H' | 'P' => (),
+                _ => bad_command!("unsupported overlap CIGAR {}", cigar),
+            }
+            num.clear()
+        }
+    }
+    Ok(ans)
+}
+
+fn prepare_length_query<'a>(db: &'a rusqlite::Connection) -> Result<rusqlite::Statement<'a>> {
+    Ok(db.prepare(
+        "SELECT coalesce(name, cast(segment_id AS TEXT)), sequence_length
+         FROM gfa1_segment_meta WHERE segment_id = ?",
+    )?)
+}
+
+fn segment_length(length_query: &mut rusqlite::Statement, segment_id: i64) -> Result<i64> {
+    let (name, maybe_length): (String, Option<i64>) = match length_query
+        .query_row(params![segment_id], |row| Ok((row.get(0)?, row.get(1)?)))
+        .optional()?
+    {
+        Some(it) => it,
+        None => bad_command!("unknown segment ID {}", segment_id),
+    };
+    match maybe_length {
+        Some(length) => Ok(length),
+        None => bad_command!("segment {} lacks sequence", name),
+    }
+}
+
+fn path_pieces(db: &rusqlite::Connection, path: &str) -> Result<Vec<Piece>> {
+    // look up the path by name, or else by integer ID (stored in lieu of such a name)
+    let mut path_id: Option<i64> = db
+        .query_row(
+            "SELECT path_id FROM gfa1_path WHERE name = ?",
+            params![path],
+            |row| row.get(0),
+        )
+        .optional()?;
+    if path_id.is_none() {
+        if let Some(id) = load::name_to_id(path) {
+            path_id = db
+                .query_row(
+                    "SELECT path_id FROM gfa1_path WHERE path_id = ? AND name IS NULL",
+                    params![id],
+                    |row| row.get(0),
+                )
+                .optional()?;
+        }
+    }
+    let path_id = match path_id {
+        Some(path_id) => path_id,
+        None => bad_command!("unknown path {}", path),
+    };
+    path_id_pieces(db, path_id)
+}
+
+pub fn path_id_pieces(db: &rusqlite::Connection, path_id: i64) -> Result<Vec<Piece>> {
+    let mut length_query = prepare_length_query(db)?;
+    let mut elements_query = db.prepare(
+        "SELECT segment_id, reverse, cigar_vs_previous FROM gfa1_path_element
+         WHERE path_id = ? ORDER BY ordinal",
+    )?;
+    let mut elements_cursor = elements_query.query(params![path_id])?;
+    let mut ans = Vec::new();
+    while let Some(row) = elements_cursor.next()? {
+        let segment_id: i64 = row.get(0)?;
+        let maybe_cigar: Option<String> = row.get(2)?;
+        ans.push(Piece {
+            segment_id,
+            reverse: row.get(1)?,
+            length: segment_length(&mut length_query, segment_id)?,
+            trim: match maybe_cigar.as_deref() {
+                None | Some("*") => 0,
+                Some(cigar) => overlap_length(cigar)?,
+            },
+        })
+    }
+    Ok(ans)
+}
+
+pub fn walk_pieces(db: &rusqlite::Connection, walk_id: i64) -> Result<Vec<Piece>> {
+    let mut steps = Vec::new();
+    let mut iter_walk_query = view::prepare_iter_walk(db)?;
+    view::iter_walk(&mut iter_walk_query, walk_id, |segment_id, reverse| {
+        steps.push((segment_id, reverse));
+        Ok(true)
+    })?;
+
+    let mut length_query = prepare_length_query(db)?;
+    let mut link_query = validate::prepare_link_query(db)?;
+    let mut ans: Vec<Piece> = Vec::new();
+    let mut missing_link_warning = false;
+    for (segment_id, reverse) in steps {
+        let mut trim = 0;
+        if let Some(prev) = ans.last() {
+            // take the overlap from the link
+            match validate::find_link(
+                &mut link_query,
+                (prev.segment_id, prev.reverse),
+                (segment_id, reverse),
+            )? {
+                Some(cigars) => {
+                    if let Some(cigar) = cigars.iter().flatten().find(|c| c.as_str() != "*") {
+                        trim = overlap_length(cigar)?
+                    }
+                }
+                None if !missing_link_warning => {
+                    warn!("walk steps lack link(s), assumed to have no overlap (see `gfabase validate`)");
+                    missing_link_warning = true
+                }
+                None => (),
+            }
+        }
+        ans.push(Piece {
+            segment_id,
+            reverse,
+            length: segment_length(&mut length_query, segment_id)?,
+            trim,
+        })
+    }
+    Ok(ans)
+}
+
+// Spell the range [begin, end) of the sequence of pieces, fetching only the needed parts of the
+// segment sequences. Also returns the end position of the whole spelled sequence.
+fn spell(
+    db: &rusqlite::Connection,
+    pieces: &[Piece],
+    begin: i64,
+    end: i64,
+) -> Result<(String, i64)> {
+    let mut ans = String::new();
+    let mut pos = 0;
+    for piece in pieces {
+        let trim = std::cmp::min(piece.trim, piece.length);
+        let piece_begin = pos;
+        pos += piece.length - trim;
+        // intersect [piece_begin, pos) with [begin, end), and convert to offsets within the
+        // forward segment sequence
+        let lo = std::cmp::max(piece_begin, begin);
+        let hi = std::cmp::min(pos, end);
+        if lo >= hi {
+            continue;
+        }
+        let (oriented_lo, oriented_hi) = (trim + lo - piece_begin, trim + hi - piece_begin);
+        let (fwd_lo, fwd_hi) = if piece.reverse {
+            (piece.length - oriented_hi, piece.length - oriented_lo)
+        } else {
+            (oriented_lo, oriented_hi)
+        };
+        match seq::segment_dna(db, "", piece.segment_id, fwd_lo, fwd_hi, piece.reverse)? {
+            Some(dna) => ans.push_str(&dna),
+            None => bad_command!("segment ID {} lacks sequence", piece.segment_id),
+        }
+    }
+    Ok((ans, pos))
+}
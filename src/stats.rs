@@ -0,0 +1,370 @@
+use clap::Clap;
+use json::{object, JsonValue};
+use log::info;
+use num_format::{Locale, ToFormattedString};
+use rusqlite::{params, OpenFlags, OptionalExtension};
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::util::Result;
+use crate::{connectivity, util, view};
+
+#[derive(Clap)]
+pub struct Opts {
+    /// gfab filename or http[s] URL
+    pub gfab: String,
+
+    /// Write JSON instead of human-readable text
+    #[clap(long)]
+    pub json: bool,
+
+    /// Skip per-sample Walk lengths (which requires decoding all Walks)
+    #[clap(long)]
+    pub no_walks: bool,
+
+    /// log extra progress reports
+    #[clap(short, long)]
+    pub verbose: bool,
+
+    /// log errors only
+    #[clap(short, long)]
+    pub quiet: bool,
+}
+
+pub fn main(opts: &Opts) -> Result<()> {
+    let mut dbopts = json::object::Object::new();
+    dbopts.insert("immutable", json::JsonValue::from(true));
+
+    let (_gfab_version, db) = util::open_gfab(
+        &opts.gfab,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        &dbopts,
+    )?;
+
+    let mut stats = object! {
+        segments: segment_stats(&db)?,
+        links: link_stats(&db)?,
+        paths: path_stats(&db)?,
+    };
+    let connectivity_table = if connectivity::has_index(&db, "")? {
+        "gfa1_connectivity"
+    } else {
+        // traverse the whole graph on the fly
+        info!("computing connectivity (for speed, see `gfabase index --connectivity`) ...");
+        db.execute_batch(
+            "CREATE TABLE temp.start_segments(segment_id INTEGER PRIMARY KEY);
+             INSERT INTO temp.start_segments(segment_id) SELECT segment_id FROM gfa1_segment_meta",
+        )?;
+        connectivity::index_reachable(&db, "")?;
+        "temp.reachable_connectivity"
+    };
+    stats["components"] = component_stats(&db, connectivity_table)?;
+    if !opts.no_walks {
+        stats["walks"] = walk_stats(&db)?;
+    }
+
+    if opts.json {
+        println!("{}", stats.pretty(2));
+    } else {
+        print_text(&stats);
+    }
+    Ok(())
+}
+
+fn count(db: &rusqlite::Connection, table: &str) -> Result<i64> {
+    Ok(
+        db.query_row(&format!("SELECT count(1) FROM {}", table), [], |row| {
+            row.get(0)
+        })?,
+    )
+}
+
+// N50 of the given lengths, which must be sorted in descending order
+fn n50(lengths_desc: &[i64]) -> i64 {
+    let total: i64 = lengths_desc.iter().sum();
+    let mut cumulative = 0;
+    for len in lengths_desc {
+        cumulative += len;
+        if 2 * cumulative >= total {
+            return *len;
+        }
+    }
+    0
+}
+
+fn segment_stats(db: &rusqlite::Connection) -> Result<JsonValue> {
+    let mut lengths = Vec::new();
+    let mut lengths_query = db.prepare(
+        "SELECT sequence_length FROM gfa1_segment_meta
+         WHERE sequence_length IS NOT NULL ORDER BY sequence_length DESC",
+    )?;
+    let mut lengths_cursor = lengths_query.query([])?;
+    while let Some(row) = lengths_cursor.next()? {
+        lengths.push(row.get(0)?);
+    }
+    Ok(object! {
+        count: count(db, "gfa1_segment_meta")?,
+        with_sequence: count(db, "gfa1_segment_sequence")?,
+        bp: lengths.iter().sum::<i64>(),
+        n50: n50(&lengths),
+        max_length: lengths.first().copied().unwrap_or(0),
+    })
+}
+
+fn link_stats(db: &rusqlite::Connection) -> Result<JsonValue> {
+    // distribution of segment degree (# links touching either side of the segment)
+    let mut degrees = json::object::Object::new();
+    let mut degrees_query = db.prepare(
+        "SELECT degree, count(1) FROM
+            (SELECT segment_id,
+                (SELECT count(1) FROM gfa1_link WHERE from_segment = segment_id)
+                + (SELECT count(1) FROM gfa1_link WHERE to_segment = segment_id) AS degree
+             FROM gfa1_segment_meta)
+         GROUP BY degree ORDER BY degree",
+    )?;
+    let mut degrees_cursor = degrees_query.query([])?;
+    while let Some(row) = degrees_cursor.next()? {
+        let degree: i64 = row.get(0)?;
+        let segments: i64 = row.get(1)?;
+        degrees.insert(&degree.to_string(), JsonValue::from(segments));
+    }
+    Ok(object! {
+        count: count(db, "gfa1_link")?,
+        degree_distribution: degrees,
+    })
+}
+
+fn path_stats(db: &rusqlite::Connection) -> Result<JsonValue> {
+    // path length = sum of its segments' lengths, disregarding overlaps
+    let mut lengths = Vec::new();
+    let mut lengths_query = db.prepare(
+        "SELECT sum(sequence_length) AS path_length
+         FROM gfa1_path_element LEFT JOIN gfa1_segment_meta USING(segment_id)
+         GROUP BY path_id ORDER BY path_length DESC",
+    )?;
+    let mut lengths_cursor = lengths_query.query([])?;
+    while let Some(row) = lengths_cursor.next()? {
+        let maybe_length: Option<i64> = row.get(0)?;
+        lengths.push(maybe_length.unwrap_or(0));
+    }
+    Ok(object! {
+        count: count(db, "gfa1_path")?,
+        bp: lengths.iter().sum::<i64>(),
+        n50: n50(&lengths),
+        max_length: lengths.first().copied().unwrap_or(0),
+    })
+}
+
+// power-of-ten bucket for a size, e.g. 1, 2-9, 10-99; returns (sort key, label)
+fn size_bucket(size: i64) -> (i64, String) {
+    if size <= 1 {
+        return (1, String::from("1"));
+    }
+    let mut lo = 1;
+    while lo * 10 <= size {
+        lo *= 10;
+    }
+    let label = format!("{}-{}", if lo == 1 { 2 } else { lo }, lo * 10 - 1);
+    (lo * 10 - 1, label)
+}
+
+fn add_to_bucket(buckets: &mut BTreeMap<i64, (String, i64)>, size: i64, n: i64) {
+    let (key, label) = size_bucket(size);
+    buckets.entry(key).or_insert((label, 0)).1 += n;
+}
+
+// (connectivity_table is gfa1_connectivity or a table with the same columns)
+fn component_stats(db: &rusqlite::Connection, connectivity_table: &str) -> Result<JsonValue> {
+    let mut sizes: BTreeMap<i64, (String, i64)> = BTreeMap::new();
+    let mut components = 0;
+    let mut largest_size = 0;
+    let mut largest_bp = 0;
+    let mut components_query = db.prepare(&format!(
+        "SELECT count(segment_id), coalesce(sum(sequence_length), 0)
+         FROM {} INNER JOIN gfa1_segment_meta USING(segment_id)
+         GROUP BY component_id",
+        connectivity_table
+    ))?;
+    let mut components_cursor = components_query.query([])?;
+    while let Some(row) = components_cursor.next()? {
+        let size: i64 = row.get(0)?;
+        let bp: i64 = row.get(1)?;
+        components += 1;
+        if size > largest_size {
+            largest_size = size;
+            largest_bp = bp;
+        }
+        add_to_bucket(&mut sizes, size, 1);
+    }
+    // disconnected segments are omitted from gfa1_connectivity
+    let (isolated, isolated_max_bp): (i64, i64) = db.query_row(
+        &format!(
+            "SELECT count(1), coalesce(max(sequence_length), 0) FROM gfa1_segment_meta
+             WHERE segment_id NOT IN (SELECT segment_id FROM {})",
+            connectivity_table
+        ),
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    if isolated > 0 {
+        add_to_bucket(&mut sizes, 1, isolated);
+        // each isolated segment is a component of one; the longest wins if no larger component
+        if largest_size <= 1 {
+            largest_size = 1;
+            largest_bp = cmp::max(largest_bp, isolated_max_bp);
+        }
+    }
+    let (cutpoints, cutpoints_bp): (i64, i64) = db.query_row(
+        &format!(
+            "SELECT count(1), coalesce(sum(sequence_length), 0)
+             FROM {} INNER JOIN gfa1_segment_meta USING(segment_id)
+             WHERE is_cutpoint",
+            connectivity_table
+        ),
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    let mut size_distribution = json::object::Object::new();
+    for (label, n) in sizes.values() {
+        size_distribution.insert(label, JsonValue::from(*n));
+    }
+    Ok(object! {
+        count: components + isolated,
+        isolated_segments: isolated,
+        largest_segments: largest_size,
+        largest_bp: largest_bp,
+        size_distribution: size_distribution,
+        cutpoints: cutpoints,
+        cutpoints_bp: cutpoints_bp,
+    })
+}
+
+fn walk_stats(db: &rusqlite::Connection) -> Result<JsonValue> {
+    let mut segment_length_query =
+        db.prepare("SELECT sequence_length FROM gfa1_segment_meta WHERE segment_id = ?")?;
+    let mut segment_lengths: HashMap<i64, i64> = HashMap::new();
+    let mut iter_walk_query = view::prepare_iter_walk(db)?;
+
+    // sample -> (walks, steps, bp, min bp, max bp)
+    let mut samples: BTreeMap<String, (i64, i64, i64, i64, i64)> = BTreeMap::new();
+    let mut walks_query =
+        db.prepare("SELECT walk_id, coalesce(sample, '') FROM gfa1_walk ORDER BY sample")?;
+    let mut walks_cursor = walks_query.query([])?;
+    let mut walks = 0;
+    while let Some(row) = walks_cursor.next()? {
+        let walk_id: i64 = row.get(0)?;
+        let sample: String = row.get(1)?;
+        let mut steps = 0;
+        let mut bp = 0;
+        view::iter_walk(&mut iter_walk_query, walk_id, |segment_id, _| {
+            steps += 1;
+            bp += if let Some(len) = segment_lengths.get(&segment_id) {
+                *len
+            } else {
+                let len: i64 = segment_length_query
+                    .query_row(params![segment_id], |row| row.get(0))
+                    .optional()?
+                    .flatten()
+                    .unwrap_or(0);
+                segment_lengths.insert(segment_id, len);
+                len
+            };
+            Ok(true)
+        })?;
+        walks += 1;
+        let entry = samples
+            .entry(sample)
+            .or_insert((0, 0, 0, i64::MAX, i64::MIN));
+        entry.0 += 1;
+        entry.1 += steps;
+        entry.2 += bp;
+        entry.3 = entry.3.min(bp);
+        entry.4 = entry.4.max(bp);
+    }
+
+    let mut per_sample = json::object::Object::new();
+    for (sample, (n, steps, bp, min_bp, max_bp)) in samples {
+        per_sample.insert(
+            &sample,
+            object! {
+                walks: n,
+                steps: steps,
+                bp: bp,
+                min_bp: min_bp,
+                max_bp: max_bp,
+            },
+        );
+    }
+    Ok(object! {
+        count: walks,
+        samples: per_sample,
+    })
+}
+
+fn fmt(v: &JsonValue) -> String {
+    v.as_i64()
+        .map(|i| i.to_formatted_string(&Locale::en))
+        .unwrap_or_else(|| v.to_string())
+}
+
+fn print_text(stats: &JsonValue) {
+    let s = &stats["segments"];
+    println!(
+        "segments\t{}\t({} with sequence)",
+        fmt(&s["count"]),
+        fmt(&s["with_sequence"])
+    );
+    println!("segments bp\t{}", fmt(&s["bp"]));
+    println!("segments N50\t{}", fmt(&s["n50"]));
+    println!("segments max length\t{}", fmt(&s["max_length"]));
+
+    let l = &stats["links"];
+    println!("links\t{}", fmt(&l["count"]));
+    for (degree, n) in l["degree_distribution"].entries() {
+        println!("segments with degree {}\t{}", degree, fmt(n));
+    }
+
+    let p = &stats["paths"];
+    println!("paths\t{}", fmt(&p["count"]));
+    println!("paths bp\t{}", fmt(&p["bp"]));
+    println!("paths N50\t{}", fmt(&p["n50"]));
+    println!("paths max length\t{}", fmt(&p["max_length"]));
+
+    let c = &stats["components"];
+    if !c.is_null() {
+        println!(
+            "connected components\t{}\t({} isolated segments)",
+            fmt(&c["count"]),
+            fmt(&c["isolated_segments"])
+        );
+        println!(
+            "largest component\t{} segments\t{} bp",
+            fmt(&c["largest_segments"]),
+            fmt(&c["largest_bp"])
+        );
+        for (bucket, n) in c["size_distribution"].entries() {
+            println!("components with {} segments\t{}", bucket, fmt(n));
+        }
+        println!(
+            "cutpoint segments\t{}\t{} bp",
+            fmt(&c["cutpoints"]),
+            fmt(&c["cutpoints_bp"])
+        );
+    }
+
+    let w = &stats["walks"];
+    if !w.is_null() {
+        println!("walks\t{}", fmt(&w["count"]));
+        for (sample, ws) in w["samples"].entries() {
+            println!(
+                "sample {}\t{} walks\t{} steps\t{} bp (min {}, max {})",
+                sample,
+                fmt(&ws["walks"]),
+                fmt(&ws["steps"]),
+                fmt(&ws["bp"]),
+                fmt(&ws["min_bp"]),
+                fmt(&ws["max_bp"])
+            );
+        }
+    }
+}
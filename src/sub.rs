@@ -3,12 +3,13 @@ use genomicsqlite::ConnectionMethods;
 use log::{debug, info, log_enabled, warn};
 use rusqlite::{params, OpenFlags, OptionalExtension};
 use std::collections::{BinaryHeap, HashMap};
-use std::{cmp, io};
+use std::io::BufRead;
+use std::{cmp, fs, io};
 
 use crate::util::Result;
-use crate::{bad_command, connectivity, load, util, view};
+use crate::{bad_command, connectivity, export, kmers, load, seq, util, view};
 
-#[derive(Clap)]
+#[derive(Clap, Clone)]
 pub struct Opts {
     /// gfab filename or http[s] URL
     pub gfab: String,
@@ -28,6 +29,42 @@ pub struct Opts {
     /// SEGMENTs are reference sequence ranges like chr7:1,234-5,678 to locate in segment mappings
     #[clap(long)]
     pub range: bool,
+
+    /// Modifies --range to ignore mappings with lower quality score (mq:i tag from add-mappings)
+    #[clap(long, name = "Q", default_value = "0")]
+    pub quality: u64,
+
+    /// Modifies --range to ignore mappings with lower identity (id:f tag from add-mappings)
+    #[clap(long, name = "F", default_value = "0")]
+    pub min_identity: f64,
+
+    /// Modifies --range to ignore secondary mappings (tp:A tag from add-mappings)
+    #[clap(long)]
+    pub primary_only: bool,
+
+    /// SEGMENTs are nucleotide sequences to find segments with exactly that content (in either
+    /// orientation)
+    #[clap(long)]
+    pub sequence: bool,
+
+    /// Find segments with exactly the content of each sequence in this FASTA file (implies
+    /// --sequence)
+    #[clap(long, name = "FASTA")]
+    pub sequence_file: Option<String>,
+
+    /// SEGMENTs are query sequences to search for approximately in the k-mer index (see `gfabase
+    /// index --kmers`), selecting the matching segments and their immediate neighbors
+    #[clap(long)]
+    pub query: bool,
+
+    /// Search for each sequence in this FASTA file (implies --query)
+    #[clap(long, name = "QUERY_FASTA")]
+    pub query_fasta: Option<String>,
+
+    /// Modifies --query to require at least N k-mer seed hits (or all of a short query's)
+    #[clap(long, name = "N", default_value = "2")]
+    pub query_min_seeds: usize,
+
     /// Treat SEGMENTs as text names even if they look like integer IDs
     #[clap(long)]
     pub always_names: bool,
@@ -57,6 +94,35 @@ pub struct Opts {
     #[clap(long)]
     pub guess_ranges: bool,
 
+    /// Like --guess-ranges, but cluster each segment's mappings into loci and set gr:Z to up to
+    /// MAX_LOCI of them (;-separated, by covered fraction of the segment), with gs:Z strands and
+    /// gf:Z covered fractions (implies --view)
+    #[clap(long, name = "MAX_LOCI")]
+    pub guess_loci: Option<usize>,
+
+    /// Modifies --range and --guess-ranges to use only the mappings in this set (see add-mappings
+    /// --set) instead of the unnamed set
+    #[clap(long, name = "NAME")]
+    pub mapping_set: Option<String>,
+
+    /// Write subgraph segment sequences as FASTA instead of GFA (implies --view)
+    #[clap(long)]
+    pub fasta: bool,
+
+    /// Output format: gfa, fasta (same as --fasta), or graph for visualization with other tools
+    /// (Graphviz dot, graphml, or Cytoscape.js cyjs); other than gfa implies --view
+    #[clap(long, name = "FORMAT", default_value = "gfa", possible_values = export::FORMATS)]
+    pub format: String,
+
+    /// Modifies --fasta or --format to include these segment tags in the FASTA header comments or
+    /// node attributes (comma-separated, e.g. LN,KC)
+    #[clap(long, name = "TAGS", alias = "fasta-tags")]
+    pub tags: Option<String>,
+
+    /// Modifies --fasta line width (0 for no line breaks)
+    #[clap(long, default_value = "60")]
+    pub line_width: usize,
+
     /// Include Walks only for these samples (comma-separated), instead of all (if taking subgraph, requires --connected)
     #[clap(long, name = "SAMPLE")]
     pub walk_samples: Option<String>,
@@ -93,16 +159,63 @@ pub struct Opts {
 // pending release of fix for https://github.com/clap-rs/clap/issues/2279
 
 pub fn main(opts: &Opts) -> Result<()> {
+    if let Some(sequence_file) = &opts.sequence_file {
+        let mut opts2 = opts.clone();
+        opts2
+            .segments
+            .append(&mut read_fasta_sequences(sequence_file)?);
+        opts2.sequence = true;
+        opts2.sequence_file = None;
+        return main(&opts2);
+    }
+    if let Some(query_fasta) = &opts.query_fasta {
+        let mut opts2 = opts.clone();
+        opts2
+            .segments
+            .append(&mut read_fasta_sequences(query_fasta)?);
+        opts2.query = true;
+        opts2.query_fasta = None;
+        return main(&opts2);
+    }
     if opts.segments.is_empty()
         && (opts.path
             || opts.range
+            || opts.sequence
+            || opts.query
             || opts.connected
             || opts.cutpoints > 0
             || opts.cutpoints_nt > 0)
     {
         bad_command!("specify one or more desired subgraph segments on the command line");
     }
-    if opts.view || opts.bandage || opts.guess_ranges || opts.outfile == "-" {
+    if (opts.sequence || opts.query) && (opts.path || opts.range) {
+        bad_command!("--sequence and --query are incompatible with --path and --range");
+    }
+    if opts.sequence && opts.query {
+        bad_command!("--sequence and --query are mutually exclusive");
+    }
+    if opts.fasta && opts.format != "gfa" && opts.format != "fasta" {
+        bad_command!("--fasta and --format are mutually exclusive")
+    }
+    if opts.fasta {
+        let mut opts2 = opts.clone();
+        opts2.format = String::from("fasta");
+        opts2.fasta = false;
+        return main(&opts2);
+    }
+    if opts.format != "gfa" && opts.bandage {
+        bad_command!("--bandage requires GFA output format")
+    }
+    if opts.format == "fasta" && opts.no_sequences {
+        bad_command!("--fasta is incompatible with --no-sequences")
+    }
+    if opts.view
+        || opts.bandage
+        || opts.guess_ranges
+        || opts.guess_loci.is_some()
+        || opts.format != "gfa"
+        || opts.outfile == "-"
+    {
         sub_gfa(opts)
     } else {
         sub_gfab(opts)
@@ -120,7 +233,12 @@ fn sub_gfab(opts: &Opts) -> Result<()> {
     util::url_or_extant_file(&opts.gfab)?;
 
     // create output database
-    let mut db = load::new_db(&opts.outfile, opts.compress, opts.memory_gbytes * 200)?;
+    let output = util::OutputFile::new(&opts.outfile)?;
+    let mut db = load::new_db(
+        &output.temp_filename,
+        opts.compress,
+        opts.memory_gbytes * 200,
+    )?;
 
     // attach input database
     let mut dbopts_in = json::object::Object::new();
@@ -152,12 +270,36 @@ fn sub_gfab(opts: &Opts) -> Result<()> {
                 sub_segment_count
             );
             if !opts.no_sequences {
-                txn.execute_batch(
-                    "INSERT INTO gfa1_segment_sequence(segment_id, sequence_twobit)
-                     SELECT segment_id, sequence_twobit FROM input.gfa1_segment_sequence
+                // (sequence hashes are computed afterwards if the input .gfab predates them)
+                let input_hashes =
+                    util::column_exists(&txn, "input", "gfa1_segment_sequence", "sequence_hash")?;
+                txn.execute_batch(&format!(
+                    "INSERT INTO gfa1_segment_sequence(segment_id, sequence_twobit, sequence_hash)
+                     SELECT segment_id, sequence_twobit, {} FROM input.gfa1_segment_sequence
                      WHERE segment_id IN temp.sub_segments",
-                )?;
+                    if input_hashes {
+                        "sequence_hash"
+                    } else {
+                        "NULL"
+                    }
+                ))?;
+                if !input_hashes {
+                    load::fill_sequence_hashes(&txn)?;
+                }
             }
+            txn.execute_batch(&format!(
+                "INSERT INTO gfa1_segment_mapping(segment_id, refseq_name, refseq_begin, refseq_end, tags_json, cigar, mapping_set)
+                 SELECT segment_id, refseq_name, refseq_begin, refseq_end, tags_json, {}
+                 FROM input.gfa1_segment_mapping
+                 WHERE segment_id IN temp.sub_segments
+                 ORDER BY segment_id",
+                util::optional_columns(
+                    &txn,
+                    "input",
+                    "gfa1_segment_mapping",
+                    &["cigar", "mapping_set"]
+                )?
+            ))?;
             txn.execute_batch(include_str!("query/sub.sql"))?;
         }
 
@@ -198,6 +340,9 @@ fn sub_gfab(opts: &Opts) -> Result<()> {
             }
         }
 
+        util::copy_provenance(&txn, "input.")?;
+        util::record_provenance(&txn, &[&opts.gfab], None)?;
+
         load::create_indexes(&txn, !opts.no_connectivity)?;
 
         debug!("flushing {} ...", &opts.outfile);
@@ -208,6 +353,7 @@ fn sub_gfab(opts: &Opts) -> Result<()> {
         load::summary(&db)?;
     }
     db.close().map_err(|(_, e)| e)?;
+    output.commit()?;
     if sub_segment_count == 0 {
         return Err(util::Error::EmptyGfab);
     }
@@ -278,10 +424,12 @@ fn sub_gfa(opts: &Opts) -> Result<()> {
         }
     }
 
-    let mut maybe_guesser = if opts.guess_ranges {
+    let mut maybe_guesser = if opts.guess_ranges || opts.guess_loci.is_some() {
         Some(view::SegmentRangeGuesser::new(
             &txn,
             "WHERE segment_id IN temp.sub_segments",
+            opts.mapping_set.as_deref(),
+            opts.guess_loci,
         )?)
     } else {
         None
@@ -289,9 +437,7 @@ fn sub_gfa(opts: &Opts) -> Result<()> {
 
     if opts.outfile == "-" && !opts.bandage && atty::is(atty::Stream::Stdout) {
         // interactive mode: pipe into less -S
-        view::less(|less_in| {
-            sub_gfa_write(&txn, &mut maybe_guesser, !opts.no_sequences, walks, less_in)
-        })?
+        view::less(|less_in| sub_gfa_write(&txn, &mut maybe_guesser, opts, walks, less_in))?
     } else {
         let mut output_gfa = String::from(&opts.outfile);
         if opts.bandage && output_gfa == "-" {
@@ -299,14 +445,12 @@ fn sub_gfa(opts: &Opts) -> Result<()> {
         }
 
         {
-            let mut writer_box = view::writer(&output_gfa)?;
-            sub_gfa_write(
-                &txn,
-                &mut maybe_guesser,
-                !opts.no_sequences,
-                walks,
-                &mut *writer_box,
-            )?;
+            let mut writer_box = match opts.format.as_str() {
+                "gfa" => view::writer(&output_gfa)?,
+                "fasta" => seq::fasta_writer(&output_gfa)?,
+                format => export::writer(&output_gfa, format)?,
+            };
+            sub_gfa_write(&txn, &mut maybe_guesser, opts, walks, &mut *writer_box)?;
         }
 
         if opts.bandage {
@@ -325,24 +469,47 @@ fn sub_gfa(opts: &Opts) -> Result<()> {
 fn sub_gfa_write(
     db: &rusqlite::Connection,
     maybe_guesser: &mut Option<view::SegmentRangeGuesser>,
-    sequences: bool,
+    opts: &Opts,
     walks: bool,
     out: &mut dyn io::Write,
 ) -> Result<()> {
     let mut tag_editor = |segment_id: i64, tags: &mut json::JsonValue| -> Result<()> {
         if let Some(ref mut guesser) = maybe_guesser {
-            if let Some(gr) = guesser.get(segment_id)? {
-                tags.insert("gr:Z", gr).unwrap()
-            }
+            guesser.insert_tags(segment_id, tags)?
         }
         Ok(())
     };
 
+    let tags = opts.tags.as_deref().unwrap_or("");
+    match opts.format.as_str() {
+        "gfa" => (),
+        "fasta" => {
+            return view::write_segments_fasta(
+                db,
+                "WHERE segment_id IN temp.sub_segments",
+                tags,
+                &mut tag_editor,
+                opts.line_width,
+                out,
+            )
+        }
+        format => {
+            return export::write_graph(
+                db,
+                format,
+                "WHERE segment_id IN temp.sub_segments",
+                "WHERE +from_segment IN temp.sub_segments AND to_segment IN temp.sub_segments",
+                tags,
+                &mut tag_editor,
+                out,
+            )
+        }
+    }
     view::write_header(db, out)?;
     view::write_segments(
         db,
         "WHERE segment_id IN temp.sub_segments",
-        sequences,
+        !opts.no_sequences,
         &mut tag_editor,
         out,
     )?;
@@ -405,10 +572,20 @@ fn compute_subgraph(db: &rusqlite::Connection, opts: &Opts, input_schema: &str)
         // optimization TODO: copying whole connected components, we can copy the relevant parts
         // of gfa1_connectivity instead of reanalyzing through connectivity::index()
     } else if cutpoints > 0 {
-        if !connectivity::has_index(db, input_schema)? {
-            panic!("input .gfab lacks connectivity index required for --cutpoints")
-        }
-        expand_to_cutpoints(db, input_schema, cutpoints as i64, opts.cutpoints_nt as i64)?
+        let connectivity_table = if connectivity::has_index(db, input_schema)? {
+            format!("{}gfa1_connectivity", input_schema)
+        } else {
+            warn!("`gfabase sub --cutpoints` will run suboptimally because input .gfab lacks connectivity index");
+            connectivity::index_reachable(db, input_schema)?;
+            String::from("temp.reachable_connectivity")
+        };
+        expand_to_cutpoints(
+            db,
+            input_schema,
+            &connectivity_table,
+            cutpoints as i64,
+            opts.cutpoints_nt as i64,
+        )?
     } else {
         // sub_segments = start_segments
         db.execute_batch("ALTER TABLE temp.start_segments RENAME TO sub_segments")?;
@@ -428,7 +605,30 @@ fn compute_start_segments(
     db.execute_batch("CREATE TABLE temp.start_segments(segment_id INTEGER PRIMARY KEY)")?;
     if !opts.segments.is_empty() {
         let mut insert_segment = if opts.range {
-            // GRI query
+            // GRI query, with any filters on the mappings' tags (which mappings lacking them,
+            // e.g. from rGFA tags, pass)
+            let mut filters = String::new();
+            let set_condition =
+                util::mapping_set_condition(db, input_schema, opts.mapping_set.as_deref())?;
+            if !set_condition.is_empty() {
+                filters += " AND ";
+                filters += set_condition
+            }
+            if opts.quality > 0 {
+                filters += &format!(
+                    " AND coalesce(json_extract(tags_json, '$.\"mq:i\"'), 255) >= {}",
+                    opts.quality
+                )
+            }
+            if opts.min_identity > 0.0 {
+                filters += &format!(
+                    " AND coalesce(json_extract(tags_json, '$.\"id:f\"'), 1.0) >= {}",
+                    opts.min_identity
+                )
+            }
+            if opts.primary_only {
+                filters += " AND coalesce(json_extract(tags_json, '$.\"tp:A\"'), 'P') != 'S'"
+            }
             db.prepare(&format!(
                 "INSERT OR REPLACE INTO temp.start_segments(segment_id)
                      SELECT segment_id FROM {}gfa1_segment_mapping
@@ -436,8 +636,8 @@ fn compute_start_segments(
                             '{}gfa1_segment_mapping',
                             parse_genomic_range_sequence(?1),
                             parse_genomic_range_begin(?1),
-                            parse_genomic_range_end(?1))",
-                input_schema, input_schema
+                            parse_genomic_range_end(?1)){}",
+                input_schema, input_schema, filters
             ))?
         } else {
             db.prepare("INSERT OR REPLACE INTO temp.start_segments(segment_id) VALUES(?)")?
@@ -455,9 +655,77 @@ fn compute_start_segments(
              SELECT segment_id FROM {}gfa1_path_element WHERE path_id=?",
             input_schema
         ))?;
+        let mut find_segments_by_sequence = if !opts.sequence {
+            None
+        } else if util::column_exists(db, input_schema, "gfa1_segment_sequence", "sequence_hash")? {
+            Some(db.prepare(&format!(
+                "SELECT segment_id FROM {}gfa1_segment_sequence
+                 WHERE sequence_hash = ?1 AND upper(twobit_dna(sequence_twobit)) IN (?2, dna_revcomp(?2))",
+                input_schema
+            ))?)
+        } else {
+            warn!("`gfabase sub --sequence` will run suboptimally because input .gfab lacks sequence hashes (see `gfabase upgrade`)");
+            Some(db.prepare(&format!(
+                "SELECT segment_id FROM {}gfa1_segment_sequence
+                 WHERE ?1 IS NOT NULL AND upper(twobit_dna(sequence_twobit)) IN (?2, dna_revcomp(?2))",
+                input_schema
+            ))?)
+        };
+        let mut insert_neighbors = db.prepare(&format!(
+            "INSERT OR REPLACE INTO temp.start_segments(segment_id)
+                SELECT from_segment FROM {s}gfa1_link WHERE to_segment = ?1
+                UNION
+                SELECT to_segment FROM {s}gfa1_link WHERE from_segment = ?1",
+            s = input_schema
+        ))?;
+        if opts.query && !kmers::has_index(db, input_schema)? {
+            bad_command!(
+                "`gfabase sub --query` requires k-mer index (see `gfabase index --kmers`)"
+            );
+        }
         for segment in &opts.segments {
-            if opts.range {
-                if insert_segment.execute(params![segment])? < 1 {
+            if opts.query {
+                let found = kmers::search(db, input_schema, segment, opts.query_min_seeds)?;
+                if found.is_empty() {
+                    bad_command!(
+                        "no segments matching query sequence {}",
+                        if segment.chars().count() > 32 {
+                            format!("{}...", segment.chars().take(32).collect::<String>())
+                        } else {
+                            segment.clone()
+                        }
+                    )
+                }
+                for segment_id in found {
+                    insert_segment.execute(params![segment_id])?;
+                    insert_neighbors.execute(params![segment_id])?;
+                }
+            } else if let Some(ref mut find_by_sequence) = find_segments_by_sequence {
+                let sequence = segment.to_ascii_uppercase().replace('U', "T");
+                let mut found = false;
+                let mut cursor =
+                    find_by_sequence.query(params![load::sequence_hash(&sequence), sequence])?;
+                while let Some(row) = cursor.next()? {
+                    let segment_id: i64 = row.get(0)?;
+                    insert_segment.execute(params![segment_id])?;
+                    found = true;
+                }
+                if !found {
+                    bad_command!(
+                        "no segment with sequence {}",
+                        if sequence.chars().count() > 32 {
+                            format!("{}...", sequence.chars().take(32).collect::<String>())
+                        } else {
+                            sequence
+                        }
+                    )
+                }
+            } else if opts.range {
+                let inserted = match &opts.mapping_set {
+                    Some(mapping_set) => insert_segment.execute(params![segment, mapping_set])?,
+                    None => insert_segment.execute(params![segment])?,
+                };
+                if inserted < 1 {
                     bad_command!("no segments found overlapping {}", segment);
                 }
             } else if !opts.always_names && load::name_to_id(segment).is_some() {
@@ -529,17 +797,45 @@ fn compute_start_segments(
     Ok(())
 }
 
+// Read the sequences from a FASTA file (or, if it has no header lines, one sequence per line)
+fn read_fasta_sequences(filename: &str) -> Result<Vec<String>> {
+    let mut ans = Vec::new();
+    let mut record: Option<String> = None;
+    for line in io::BufReader::new(fs::File::open(filename)?).lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.starts_with('>') {
+            if let Some(sequence) = record.take().filter(|s| !s.is_empty()) {
+                ans.push(sequence)
+            }
+            record = Some(String::new())
+        } else if let Some(ref mut sequence) = record {
+            sequence.push_str(line)
+        } else if !line.is_empty() {
+            ans.push(String::from(line))
+        }
+    }
+    if let Some(sequence) = record.filter(|s| !s.is_empty()) {
+        ans.push(sequence)
+    }
+    if ans.is_empty() {
+        bad_command!("no sequences found in {}", filename)
+    }
+    Ok(ans)
+}
+
 // Compute all segments connected to a set of start segments without traversing a cutpoint segment
 // (generally: traverse up to R-1 cutpoint segments of at least L bp each, defaults R=1 L=0).
 // "Traversing a cutpoint segment" means arriving on one side and departing the other; so when we
 // "stop" at a cutpoint segment, we still follow other edges touching the side at which we arrived.
 // Otherwise, treat non-cutpoint segments as unsided and all edges as undirected.
 //
-//  IN: segment IDs in temp.start_segments
+//  IN: segment IDs in temp.start_segments; connectivity_table (gfa1_connectivity or equivalent)
 // OUT: segment IDs in temp.sub_segments
 fn expand_to_cutpoints(
     db: &rusqlite::Connection,
     input_schema: &str,
+    connectivity_table: &str,
     radius: i64,
     cutpoint_length: i64,
 ) -> Result<()> {
@@ -555,8 +851,8 @@ fn expand_to_cutpoints(
     let mut neighbors_query = db.prepare(&neighbors_sql)?;
 
     let mut is_cutpoint_query = db.prepare(&format!(
-        "SELECT is_cutpoint FROM {}gfa1_connectivity WHERE segment_id = ?",
-        input_schema
+        "SELECT is_cutpoint FROM {} WHERE segment_id = ?",
+        connectivity_table
     ))?;
     let mut sequence_length_query = db.prepare(&format!(
         "SELECT sequence_length FROM {}gfa1_segment_meta WHERE segment_id = ?",
@@ -0,0 +1,245 @@
+use clap::Clap;
+use log::{debug, info, log_enabled, warn};
+use rusqlite::{params, OpenFlags};
+use std::fs;
+
+use crate::util::Result;
+use crate::{bad_command, connectivity, index, load, util};
+
+#[derive(Clap)]
+pub struct Opts {
+    /// .gfab filename from an older gfabase version
+    pub old_gfab: String,
+
+    /// Destination .gfab filename
+    #[clap(short)]
+    pub output_gfab: String,
+
+    /// Don't add the graph connectivity index if it's missing
+    #[clap(long)]
+    pub no_connectivity: bool,
+
+    /// log extra progress reports
+    #[clap(short, long)]
+    pub verbose: bool,
+
+    /// log errors only
+    #[clap(short, long)]
+    pub quiet: bool,
+}
+
+// One schema migration step. Rather than keying steps to the gfabase version numbers that
+// introduced them, each step detects whether it's needed by inspecting the schema, so that they
+// can be applied in order to a .gfab from any older version.
+struct Migration {
+    description: &'static str,
+    needed: fn(&rusqlite::Connection, &Opts) -> Result<bool>,
+    apply: fn(&rusqlite::Connection) -> Result<()>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        description: "add segment mapping tags",
+        needed: |db, _| {
+            Ok(!util::column_exists(
+                db,
+                "",
+                "gfa1_segment_mapping",
+                "tags_json",
+            )?)
+        },
+        apply: |db| {
+            db.execute_batch("ALTER TABLE gfa1_segment_mapping ADD COLUMN tags_json TEXT")?;
+            Ok(())
+        },
+    },
+    Migration {
+        description: "add Walk tables",
+        needed: |db, _| Ok(!util::table_exists(db, "", "gfa1_walk")?),
+        apply: |db| {
+            db.execute_batch(&schema_ddl("gfa1_walk"))?;
+            db.execute_batch(&schema_ddl("gfa1_walk_steps"))?;
+            db.execute_batch(
+                "CREATE INDEX gfa1_walk_sample_refseq ON gfa1_walk(sample,refseq_name)",
+            )?;
+            load::create_gri(db, "gfa1_walk")
+        },
+    },
+    Migration {
+        description: "add Walk segment ID bounds",
+        needed: |db, _| Ok(!util::column_exists(db, "", "gfa1_walk", "min_segment_id")?),
+        apply: |db| {
+            db.execute_batch(
+                "ALTER TABLE gfa1_walk ADD COLUMN min_segment_id INTEGER NOT NULL DEFAULT 0;
+                 ALTER TABLE gfa1_walk ADD COLUMN max_segment_id INTEGER NOT NULL DEFAULT 0",
+            )?;
+            index::index_walk_steps(db)
+        },
+    },
+    Migration {
+        description: "add segment sequence hashes",
+        needed: |db, _| {
+            Ok(!util::column_exists(
+                db,
+                "",
+                "gfa1_segment_sequence",
+                "sequence_hash",
+            )?)
+        },
+        apply: |db| {
+            db.execute_batch("ALTER TABLE gfa1_segment_sequence ADD COLUMN sequence_hash INTEGER")?;
+            load::fill_sequence_hashes(db)?;
+            db.execute_batch(
+                "CREATE INDEX gfa1_segment_sequence_hash ON gfa1_segment_sequence(sequence_hash)",
+            )?;
+            Ok(())
+        },
+    },
+    Migration {
+        description: "add graph connectivity index",
+        needed: |db, opts| Ok(!opts.no_connectivity && !connectivity::has_index(db, "")?),
+        apply: connectivity::index,
+    },
+    Migration {
+        description: "add Walk connectivity index",
+        needed: |db, _| {
+            Ok(connectivity::has_index(db, "")?
+                && !util::table_exists(db, "", "gfa1_walk_connectivity")?)
+        },
+        apply: |db| {
+            db.execute_batch(&schema_ddl("gfa1_walk_connectivity"))?;
+            connectivity::index_walks(db)?;
+            db.execute_batch(
+                "CREATE INDEX gfa1_walk_connectivity_component ON gfa1_walk_connectivity(component_id)",
+            )?;
+            Ok(())
+        },
+    },
+    Migration {
+        description: "add segment mapping CIGARs",
+        needed: |db, _| {
+            Ok(!util::column_exists(
+                db,
+                "",
+                "gfa1_segment_mapping",
+                "cigar",
+            )?)
+        },
+        apply: |db| {
+            db.execute_batch("ALTER TABLE gfa1_segment_mapping ADD COLUMN cigar TEXT")?;
+            Ok(())
+        },
+    },
+    Migration {
+        description: "add segment mapping sets",
+        needed: |db, _| {
+            Ok(!util::column_exists(
+                db,
+                "",
+                "gfa1_segment_mapping",
+                "mapping_set",
+            )?)
+        },
+        apply: |db| {
+            db.execute_batch("ALTER TABLE gfa1_segment_mapping ADD COLUMN mapping_set TEXT")?;
+            Ok(())
+        },
+    },
+    Migration {
+        description: "add provenance log",
+        needed: |db, _| Ok(!util::table_exists(db, "", "gfa1_provenance")?),
+        apply: |db| {
+            db.execute_batch(&schema_ddl("gfa1_provenance"))?;
+            Ok(())
+        },
+    },
+];
+
+pub fn main(opts: &Opts) -> Result<()> {
+    if opts.old_gfab.starts_with("http:") || opts.old_gfab.starts_with("https:") {
+        bad_command!("download the .gfab to upgrade it")
+    }
+    if !opts.output_gfab.ends_with(".gfab") {
+        warn!("output filename should end in .gfab")
+    }
+    util::url_or_extant_file(&opts.old_gfab)?;
+
+    // copy the old file to the destination, then migrate it in-place
+    let output = util::OutputFile::new(&opts.output_gfab)?;
+    debug!(
+        "copying {} to {} ...",
+        &opts.old_gfab, &output.temp_filename
+    );
+    fs::copy(&opts.old_gfab, &output.temp_filename)?;
+
+    let mut dbopts = json::object::Object::new();
+    dbopts.insert("unsafe_load", json::JsonValue::from(true));
+    let mut db = genomicsqlite::open(
+        &output.temp_filename,
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        &dbopts,
+    )
+    .map_err(|_| util::Error::NotGfab)?;
+    // nb: deliberately skip util::check_gfab_version
+    let old_version = util::check_gfab_schema(&db, "")?;
+    info!("upgrading .gfab from gfabase v{}", old_version);
+
+    {
+        let txn = db.transaction()?;
+        let mut applied = 0;
+        for migration in MIGRATIONS {
+            if (migration.needed)(&txn, opts)? {
+                info!("{} ...", migration.description);
+                (migration.apply)(&txn)?;
+                applied += 1;
+            }
+        }
+        if applied == 0 {
+            info!("no schema migrations needed")
+        }
+
+        util::record_provenance(&txn, &[&opts.old_gfab], None)?;
+
+        // update header PG:Z, which gates future compatibility checks
+        txn.execute(
+            "UPDATE gfa1_header SET tags_json = json_set(tags_json, '$.PG:Z', ?) WHERE _rowid_ = 1",
+            params![format!("gfabase-v{}", env!("CARGO_PKG_VERSION"))],
+        )?;
+
+        debug!("\tANALYZE ...");
+        txn.execute_batch("PRAGMA analysis_limit = 1000; ANALYZE main")?;
+        debug!("flushing {} ...", &opts.output_gfab);
+        txn.commit()?;
+    }
+
+    util::check_gfab_version(&util::check_gfab_schema(&db, "")?)?;
+    if log_enabled!(log::Level::Debug) {
+        load::summary(&db)?;
+    }
+    db.close().map_err(|(_, e)| e)?;
+    output.commit()?;
+    info!("🗹 done");
+    Ok(())
+}
+
+// Extract the CREATE TABLE statement for the given table from schema/GFA1.sql or
+// schema/GFA1.connectivity.sql
+fn schema_ddl(table: &str) -> String {
+    let create = format!("CREATE TABLE {}(", table);
+    for ddl in &[
+        include_str!("schema/GFA1.sql"),
+        include_str!("schema/GFA1.connectivity.sql"),
+    ] {
+        // strip comments, which may contain semicolons
+        let ddl: Vec<&str> = ddl
+            .lines()
+            .map(|line| line.split("--").next().unwrap())
+            .collect();
+        for stmt in ddl.join("\n").split(';') {
+            if stmt.contains(&create) {
+                return String::from(stmt.trim());
+            }
+        }
+    }
+    panic!("missing schema for {}", table)
+}
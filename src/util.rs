@@ -1,6 +1,7 @@
 use crate::version::GFAB_VERSION_REQ;
 use io::BufRead;
 use log::{debug, warn};
+use rusqlite::{params, OptionalExtension};
 use std::path::Path;
 use std::{fs, io};
 use thiserror::Error;
@@ -36,11 +37,14 @@ pub enum Error {
     #[error("file isn't .gfab format (or corrupt)")]
     NotGfab,
 
-    #[error("[.gfab version incompatible] version: {version:?}, required: {required:?}")]
+    #[error("[.gfab version incompatible] version: {version:?}, required: {required:?} (see `gfabase upgrade`)")]
     IncompatibleGfab { version: String, required: String },
 
     #[error("empty .gfab")]
     EmptyGfab,
+
+    #[error("{0} validation issue(s) found")]
+    ValidationFailed(usize),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -60,7 +64,8 @@ macro_rules! bad_command {
 }
 
 /// Fold over tab-separated lines of the file, excluding lines starting with specified comment
-/// character, if any, e.g. `Some('#' as u8)`. Set `filename` empty to read standard input.
+/// character, if any, e.g. `Some('#' as u8)`. Set `filename` empty to read standard input. A
+/// filename ending in .gz is decompressed.
 pub fn fold_tsv_no_comments<F, X>(mut f: F, x0: X, filename: &str, comment: Option<u8>) -> Result<X>
 where
     F: FnMut(usize, X, &Vec<&str>) -> Result<X>,
@@ -68,6 +73,10 @@ where
     // https://stackoverflow.com/a/49964042/13393076
     let reader: Box<dyn io::BufRead> = if filename.is_empty() || filename == "-" {
         Box::new(io::BufReader::new(io::stdin()))
+    } else if filename.ends_with(".gz") {
+        Box::new(io::BufReader::new(flate2::read::MultiGzDecoder::new(
+            fs::File::open(filename)?,
+        )))
     } else {
         Box::new(io::BufReader::new(fs::File::open(filename)?))
     };
@@ -105,6 +114,46 @@ pub fn delete_existing_file(filename: &str) -> Result<()> {
     }
 }
 
+// An output file written under a temporary name in the same directory, then renamed into place
+// upon commit(), so that a failure midway doesn't leave a truncated file where a previous good one
+// was. If dropped without commit(), the temporary file is deleted. (An interrupted process may
+// leave one behind, named like out.gfab.tmp12345, which can be deleted.)
+pub struct OutputFile {
+    pub filename: String,
+    pub temp_filename: String,
+    committed: bool,
+}
+
+impl OutputFile {
+    pub fn new(filename: &str) -> Result<Self> {
+        let temp_filename = format!("{}.tmp{}", filename, std::process::id());
+        delete_existing_file(&temp_filename)?;
+        debug!("writing {} via {}", filename, temp_filename);
+        Ok(OutputFile {
+            filename: String::from(filename),
+            temp_filename,
+            committed: false,
+        })
+    }
+
+    pub fn commit(mut self) -> Result<()> {
+        if Path::new(&self.filename).exists() {
+            warn!("replacing existing file {}", &self.filename)
+        }
+        fs::rename(&self.temp_filename, &self.filename)?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Drop for OutputFile {
+    fn drop(&mut self) {
+        if !self.committed && fs::remove_file(&self.temp_filename).is_ok() {
+            debug!("deleted incomplete {}", &self.temp_filename)
+        }
+    }
+}
+
 pub fn check_gfab_schema(db: &rusqlite::Connection, schema: &str) -> Result<semver::Version> {
     let pg_result: rusqlite::Result<String> = db.query_row(
         &format!(
@@ -146,6 +195,126 @@ pub fn check_gfab_version(gfab_version: &semver::Version) -> Result<()> {
     })
 }
 
+pub fn table_exists(db: &rusqlite::Connection, schema: &str, table: &str) -> Result<bool> {
+    Ok(db
+        .query_row(
+            &format!(
+                "SELECT 1 FROM {}sqlite_master WHERE type='table' AND name=?",
+                schema
+            ),
+            params![table],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some())
+}
+
+pub fn column_exists(
+    db: &rusqlite::Connection,
+    schema: &str,
+    table: &str,
+    column: &str,
+) -> Result<bool> {
+    let schema_name = match schema.trim_end_matches('.') {
+        "" => "main",
+        s => s,
+    };
+    Ok(db
+        .query_row(
+            "SELECT 1 FROM pragma_table_xinfo(?1, ?2) WHERE name=?3",
+            params![table, schema_name, column],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some())
+}
+
+// Comma-separated SELECT list of the given optional columns (absent in .gfab from older gfabase
+// versions), with NULL in place of any the table lacks
+pub fn optional_columns(
+    db: &rusqlite::Connection,
+    schema: &str,
+    table: &str,
+    columns: &[&str],
+) -> Result<String> {
+    let mut ans = Vec::new();
+    for column in columns {
+        ans.push(if column_exists(db, schema, table, column)? {
+            *column
+        } else {
+            "NULL"
+        })
+    }
+    Ok(ans.join(", "))
+}
+
+// Verify the .gfab has segment mappings in the named set (see add-mappings --set)
+pub fn check_mapping_set(db: &rusqlite::Connection, schema: &str, mapping_set: &str) -> Result<()> {
+    if !column_exists(db, schema, "gfa1_segment_mapping", "mapping_set")?
+        || db
+            .query_row(
+                &format!(
+                    "SELECT 1 FROM {}gfa1_segment_mapping WHERE mapping_set = ? LIMIT 1",
+                    schema
+                ),
+                params![mapping_set],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_none()
+    {
+        return Err(Error::BadCommand(format!(
+            "no segment mappings in set {}",
+            mapping_set
+        )));
+    }
+    Ok(())
+}
+
+// SQL condition on gfa1_segment_mapping selecting the mappings in the given set (which must
+// exist), or by default those in the unnamed set; empty if the .gfab predates mapping sets. Any set
+// name is to be bound to the condition's ? parameter.
+pub fn mapping_set_condition(
+    db: &rusqlite::Connection,
+    schema: &str,
+    mapping_set: Option<&str>,
+) -> Result<&'static str> {
+    if let Some(mapping_set) = mapping_set {
+        check_mapping_set(db, schema, mapping_set)?;
+        return Ok("mapping_set = ?");
+    }
+    if !column_exists(db, schema, "gfa1_segment_mapping", "mapping_set")? {
+        return Ok("");
+    }
+    let named_sets: i64 = db.query_row(
+        &format!(
+            "SELECT count(DISTINCT mapping_set) FROM {}gfa1_segment_mapping WHERE mapping_set IS NOT NULL",
+            schema
+        ),
+        [],
+        |row| row.get(0),
+    )?;
+    if named_sets > 1 {
+        return Err(Error::BadCommand(String::from(
+            "segment mappings are in several named sets; choose one with --mapping-set NAME",
+        )));
+    }
+    Ok("mapping_set IS NULL")
+}
+
+// Whether the input is evidently a .gfab (URL or SQLite file) rather than GFA text
+pub fn is_gfab_input(it: &str) -> Result<bool> {
+    if it.starts_with("http:") || it.starts_with("https:") {
+        return Ok(true);
+    }
+    if it.is_empty() || it == "-" {
+        return Ok(false);
+    }
+    let mut magic = Vec::new();
+    io::Read::read_to_end(&mut io::Read::take(fs::File::open(it)?, 16), &mut magic)?;
+    Ok(magic == b"SQLite format 3\0")
+}
+
 pub fn url_or_extant_file(it: &str) -> Result<()> {
     // not "safe", but usually gives more-helpful error message:
     if !it.starts_with("http:") && !it.starts_with("https:") && !Path::new(it).is_file() {
@@ -157,6 +326,66 @@ pub fn url_or_extant_file(it: &str) -> Result<()> {
     Ok(())
 }
 
+// Append a record of the running gfabase command to the .gfab's provenance log, listing the given
+// input files (with sizes, where available) and optional notes. Skipped, with a warning, if the
+// .gfab predates the log.
+pub fn record_provenance(
+    db: &rusqlite::Connection,
+    inputs: &[&str],
+    notes: Option<&str>,
+) -> Result<()> {
+    if !table_exists(db, "", "gfa1_provenance")? {
+        warn!("not recording provenance; run `gfabase upgrade` on the .gfab to log it");
+        return Ok(());
+    }
+    let mut command_line = vec![String::from("gfabase")];
+    for arg in std::env::args().skip(1) {
+        command_line.push(
+            if arg.is_empty() || arg.contains(|c: char| c.is_whitespace() || "'\"$;|&".contains(c))
+            {
+                format!("'{}'", arg.replace('\'', "'\\''"))
+            } else {
+                arg
+            },
+        )
+    }
+    let mut inputs_json = json::JsonValue::new_array();
+    for input in inputs {
+        let mut input_json = json::object! { name: *input };
+        if *input != "-" && !input.starts_with("http:") && !input.starts_with("https:") {
+            if let Ok(metadata) = fs::metadata(input) {
+                input_json["size"] = metadata.len().into()
+            }
+        }
+        inputs_json.push(input_json).unwrap()
+    }
+    db.execute(
+        "INSERT INTO gfa1_provenance(timestamp, gfabase_version, command_line, inputs_json, notes)
+         VALUES(?, ?, ?, ?, ?)",
+        params![
+            chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            format!("gfabase-v{}", env!("CARGO_PKG_VERSION")),
+            command_line.join(" "),
+            inputs_json.dump(),
+            notes
+        ],
+    )?;
+    Ok(())
+}
+
+// Copy the provenance log of an attached input .gfab (if it has one) into the main .gfab
+pub fn copy_provenance(db: &rusqlite::Connection, schema: &str) -> Result<()> {
+    if table_exists(db, schema, "gfa1_provenance")? && table_exists(db, "", "gfa1_provenance")? {
+        db.execute_batch(&format!(
+            "INSERT INTO gfa1_provenance(timestamp, gfabase_version, command_line, inputs_json, notes)
+                SELECT timestamp, gfabase_version, command_line, inputs_json, notes
+                FROM {}gfa1_provenance ORDER BY provenance_id",
+            schema
+        ))?
+    }
+    Ok(())
+}
+
 pub fn open_gfab(
     filename: &str,
     flags: rusqlite::OpenFlags,
@@ -168,6 +397,7 @@ pub fn open_gfab(
             let gfab_version = check_gfab_schema(&db, "")?;
             debug!("gfabase v{} created {}", gfab_version, filename);
             check_gfab_version(&gfab_version)?;
+            crate::seq::register_sql_functions(&db)?;
             Ok((gfab_version, db))
         }
         Err(err) => {
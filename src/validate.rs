@@ -0,0 +1,477 @@
+use clap::Clap;
+use log::info;
+use rusqlite::{params, OpenFlags, OptionalExtension};
+
+use crate::util::Result;
+use crate::{load, util, view};
+
+#[derive(Clap)]
+pub struct Opts {
+    /// .gfab filename or http[s] URL, or GFA file (.gfa or .gfa.gz, loaded into a temporary .gfab)
+    /// [- for standard input]
+    pub input: String,
+
+    /// Maximum number of example issues to print per category
+    #[clap(long, default_value = "10")]
+    pub max_examples: usize,
+
+    /// Memory budget (GiB)
+    #[clap(long, default_value = "4")]
+    pub memory_gbytes: u32,
+
+    /// log extra progress reports
+    #[clap(short, long)]
+    pub verbose: bool,
+
+    /// log errors only
+    #[clap(short, long)]
+    pub quiet: bool,
+}
+
+// Issues found for one category of checks
+pub struct Category {
+    pub description: &'static str,
+    pub count: usize,
+    pub examples: Vec<String>,
+    max_examples: usize,
+}
+
+impl Category {
+    pub fn new(description: &'static str, max_examples: usize) -> Category {
+        Category {
+            description,
+            count: 0,
+            examples: Vec::new(),
+            max_examples,
+        }
+    }
+
+    pub fn add(&mut self, example: String) {
+        self.count += 1;
+        if self.examples.len() < self.max_examples {
+            self.examples.push(example)
+        }
+    }
+
+    pub fn print(&self) {
+        if self.count == 0 {
+            println!("🗹 {}", self.description);
+        } else {
+            println!("🗷 {}: {} issue(s)", self.description, self.count);
+            for example in &self.examples {
+                println!("\t{}", example);
+            }
+            if self.count > self.examples.len() {
+                println!("\t...");
+            }
+        }
+    }
+}
+
+pub fn main(opts: &Opts) -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let gfab = if !util::is_gfab_input(&opts.input)? {
+        // load GFA into temporary .gfab (load itself errors on some problems, such as unknown
+        // segments)
+        let tmp_gfab = String::from(tmp.path().join("validate.gfab").to_str().unwrap());
+        info!("loading {} ...", &opts.input);
+        load::main(&load::Opts {
+            output_gfab: tmp_gfab.clone(),
+            input_gfa: opts.input.clone(),
+            always_names: false,
+            no_connectivity: true,
+            check_walks: false,
+            add_missing_links: false,
+            keep_inconsistent_lengths: true,
+            no_sequences: false,
+            no_twobit: false,
+            reference_sample: None,
+            reference_path_regex: None,
+            memory_gbytes: opts.memory_gbytes,
+            compress: 1,
+            verbose: false,
+            quiet: true,
+        })?;
+        tmp_gfab
+    } else {
+        opts.input.clone()
+    };
+
+    let mut dbopts = json::object::Object::new();
+    dbopts.insert("immutable", json::JsonValue::from(true));
+    dbopts.insert(
+        "page_cache_MiB",
+        json::JsonValue::from(opts.memory_gbytes * 800),
+    );
+    let (_gfab_version, db) = util::open_gfab(
+        &gfab,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        &dbopts,
+    )?;
+
+    let categories = vec![
+        check_foreign_keys(&db, opts.max_examples)?,
+        check_sequence_lengths(&db, opts.max_examples)?,
+        check_path_links(&db, opts.max_examples)?,
+        check_walk_links(&db, opts.max_examples)?,
+    ];
+    let mut errors = 0;
+    for category in &categories {
+        category.print();
+        errors += category.count;
+    }
+
+    if errors > 0 {
+        return Err(util::Error::ValidationFailed(errors));
+    }
+    info!("🗹 valid");
+    Ok(())
+}
+
+// rows referring to nonexistent segments, paths, or walks
+fn check_foreign_keys(db: &rusqlite::Connection, max_examples: usize) -> Result<Category> {
+    let mut ans = Category::new("references to missing segments/paths/walks", max_examples);
+    // each query yields (referring item, missing row) descriptions
+    let oriented = |alias: &str, segment: &str, reverse: &str| {
+        format!(
+            "coalesce({a}.name, cast({s} AS TEXT)) || CASE WHEN {r} THEN '-' ELSE '+' END",
+            a = alias,
+            s = segment,
+            r = reverse
+        )
+    };
+    let link = format!(
+        "'link ' || {} || ' -> ' || {}",
+        oriented("f", "l.from_segment", "l.from_reverse"),
+        oriented("t", "l.to_segment", "l.to_reverse")
+    );
+    let containment = format!(
+        "'containment ' || {} || ' > ' || {}",
+        oriented("f", "c.container_segment", "c.container_reverse"),
+        oriented("t", "c.contained_segment", "c.contained_reverse")
+    );
+    let walk = "'walk ' || coalesce(w.sample, '') || '#' || w.hap_idx || '#' || w.refseq_name";
+    let mut queries = vec![
+        String::from(
+            "SELECT 'sequence', 'segment ' || s.segment_id
+             FROM gfa1_segment_sequence AS s
+                LEFT JOIN gfa1_segment_meta AS m ON m.segment_id = s.segment_id
+             WHERE m.segment_id IS NULL",
+        ),
+        String::from(
+            "SELECT 'mapping ' || mp.refseq_name || ':' || (mp.refseq_begin + 1) || '-' || mp.refseq_end,
+                    'segment ' || mp.segment_id
+             FROM gfa1_segment_mapping AS mp
+                LEFT JOIN gfa1_segment_meta AS m ON m.segment_id = mp.segment_id
+             WHERE m.segment_id IS NULL",
+        ),
+    ];
+    for (end, column) in &[("f", "from_segment"), ("t", "to_segment")] {
+        queries.push(format!(
+            "SELECT {}, 'segment ' || l.{c}
+             FROM gfa1_link AS l
+                LEFT JOIN gfa1_segment_meta AS f ON f.segment_id = l.from_segment
+                LEFT JOIN gfa1_segment_meta AS t ON t.segment_id = l.to_segment
+             WHERE {e}.segment_id IS NULL",
+            link,
+            c = column,
+            e = end
+        ))
+    }
+    for (end, column) in &[("f", "container_segment"), ("t", "contained_segment")] {
+        queries.push(format!(
+            "SELECT {}, 'segment ' || c.{col}
+             FROM gfa1_containment AS c
+                LEFT JOIN gfa1_segment_meta AS f ON f.segment_id = c.container_segment
+                LEFT JOIN gfa1_segment_meta AS t ON t.segment_id = c.contained_segment
+             WHERE {e}.segment_id IS NULL",
+            containment,
+            col = column,
+            e = end
+        ))
+    }
+    queries.push(String::from(
+        "SELECT 'path ' || e.path_id || ' element ' || e.ordinal, 'path ' || e.path_id
+         FROM gfa1_path_element AS e LEFT JOIN gfa1_path AS p ON p.path_id = e.path_id
+         WHERE p.path_id IS NULL",
+    ));
+    queries.push(String::from(
+        "SELECT 'path ' || coalesce(p.name, cast(e.path_id AS TEXT)) || ' element ' || e.ordinal,
+                'segment ' || e.segment_id
+         FROM gfa1_path_element AS e
+            LEFT JOIN gfa1_path AS p ON p.path_id = e.path_id
+            LEFT JOIN gfa1_segment_meta AS m ON m.segment_id = e.segment_id
+         WHERE m.segment_id IS NULL",
+    ));
+    for column in &["min_segment_id", "max_segment_id"] {
+        queries.push(format!(
+            "SELECT {}, 'segment ' || w.{c}
+             FROM gfa1_walk AS w LEFT JOIN gfa1_segment_meta AS m ON m.segment_id = w.{c}
+             WHERE m.segment_id IS NULL",
+            walk,
+            c = column
+        ))
+    }
+    queries.push(String::from(
+        "SELECT 'walk steps ' || s.walk_id, 'walk ' || s.walk_id
+         FROM gfa1_walk_steps AS s LEFT JOIN gfa1_walk AS w ON w.walk_id = s.walk_id
+         WHERE w.walk_id IS NULL",
+    ));
+    // optional indexes
+    for (table, item) in &[
+        ("gfa1_segment_kmer", "k-mer index"),
+        ("gfa1_connectivity", "connectivity index"),
+    ] {
+        if util::table_exists(db, "", table)? {
+            queries.push(format!(
+                "SELECT DISTINCT '{}', 'segment ' || x.segment_id
+                 FROM {} AS x LEFT JOIN gfa1_segment_meta AS m ON m.segment_id = x.segment_id
+                 WHERE m.segment_id IS NULL",
+                item, table
+            ))
+        }
+    }
+    if util::table_exists(db, "", "gfa1_walk_connectivity")? {
+        queries.push(String::from(
+            "SELECT DISTINCT 'walk connectivity index', 'walk ' || x.walk_id
+             FROM gfa1_walk_connectivity AS x LEFT JOIN gfa1_walk AS w ON w.walk_id = x.walk_id
+             WHERE w.walk_id IS NULL",
+        ))
+    }
+
+    for sql in &queries {
+        let mut query = db.prepare(sql)?;
+        let mut cursor = query.query([])?;
+        while let Some(row) = cursor.next()? {
+            let item: String = row.get(0)?;
+            let missing: String = row.get(1)?;
+            ans.add(format!("{}: {} missing", item, missing))
+        }
+    }
+    Ok(ans)
+}
+
+// sequence_length (taken from LN:i if present) disagreeing with the stored sequence
+fn check_sequence_lengths(db: &rusqlite::Connection, max_examples: usize) -> Result<Category> {
+    let mut ans = Category::new("segment lengths consistent with sequences", max_examples);
+    let mut len_query = db.prepare(
+        "SELECT coalesce(name, cast(segment_id AS TEXT)), sequence_length, twobit_length(sequence_twobit)
+         FROM gfa1_segment_meta INNER JOIN gfa1_segment_sequence USING(segment_id)
+         WHERE sequence_length IS NULL OR sequence_length != twobit_length(sequence_twobit)",
+    )?;
+    let mut len_cursor = len_query.query([])?;
+    while let Some(row) = len_cursor.next()? {
+        let name: String = row.get(0)?;
+        let maybe_len: Option<i64> = row.get(1)?;
+        let seq_len: i64 = row.get(2)?;
+        ans.add(format!(
+            "segment {} LN = {} but sequence length = {}",
+            name,
+            maybe_len.map_or(String::from("NULL"), |l| l.to_string()),
+            seq_len
+        ))
+    }
+    Ok(ans)
+}
+
+// Reverse a CIGAR string for the reverse-complement orientation of an overlap, in which
+// insertions & deletions exchange roles.
+pub fn reverse_cigar(cigar: &str) -> String {
+    let mut ops = Vec::new();
+    let mut num = String::new();
+    for ch in cigar.chars() {
+        if ch.is_ascii_digit() {
+            num.push(ch)
+        } else {
+            let op = match ch {
+                'I' => 'D',
+                'D' => 'I',
+                other => other,
+            };
+            ops.push(format!("{}{}", num, op));
+            num.clear()
+        }
+    }
+    ops.reverse();
+    ops.join("")
+}
+
+// (segment_id, reverse)
+pub type OrientedSegment = (i64, bool);
+
+// Query for the CIGAR(s) of links joining (segment1, reverse1) to (segment2, reverse2), in either
+// bidirected form. The second column indicates whether the link was found in the flipped form.
+pub fn prepare_link_query<'a>(db: &'a rusqlite::Connection) -> Result<rusqlite::Statement<'a>> {
+    Ok(db.prepare(
+        "   SELECT cigar, 0 FROM gfa1_link
+            WHERE from_segment = ?1 AND from_reverse = ?2 AND to_segment = ?3 AND to_reverse = ?4
+         UNION ALL
+            SELECT cigar, 1 FROM gfa1_link
+            WHERE from_segment = ?3 AND from_reverse = NOT ?4 AND to_segment = ?1 AND to_reverse = NOT ?2",
+    )?)
+}
+
+// Look up the link(s) joining the two oriented segments; returns None if there's no such link,
+// otherwise the CIGARs (if any) expressed in the given orientation.
+pub fn find_link(
+    link_query: &mut rusqlite::Statement,
+    (segment1, reverse1): OrientedSegment,
+    (segment2, reverse2): OrientedSegment,
+) -> Result<Option<Vec<Option<String>>>> {
+    let mut ans = None;
+    let mut cursor = link_query.query(params![segment1, reverse1, segment2, reverse2])?;
+    while let Some(row) = cursor.next()? {
+        let maybe_cigar: Option<String> = row.get(0)?;
+        let flipped: bool = row.get(1)?;
+        ans.get_or_insert_with(Vec::new)
+            .push(maybe_cigar.map(|cigar| {
+                if flipped {
+                    reverse_cigar(&cigar)
+                } else {
+                    cigar
+                }
+            }))
+    }
+    Ok(ans)
+}
+
+pub fn oriented_segment_name(
+    name_query: &mut rusqlite::Statement,
+    segment_id: i64,
+    reverse: bool,
+) -> Result<String> {
+    let name: String = name_query
+        .query_row(params![segment_id], |row| row.get(0))
+        .optional()?
+        .unwrap_or_else(|| segment_id.to_string());
+    Ok(name + if reverse { "-" } else { "+" })
+}
+
+pub fn prepare_name_query<'a>(db: &'a rusqlite::Connection) -> Result<rusqlite::Statement<'a>> {
+    Ok(db.prepare(
+        "SELECT coalesce(name, cast(segment_id AS TEXT)) FROM gfa1_segment_meta WHERE segment_id = ?",
+    )?)
+}
+
+// consecutive path elements lacking a link, or whose overlap disagrees with the link CIGAR
+fn check_path_links(db: &rusqlite::Connection, max_examples: usize) -> Result<Category> {
+    let mut ans = Category::new(
+        "path steps follow links with consistent overlaps",
+        max_examples,
+    );
+    let mut link_query = prepare_link_query(db)?;
+    let mut name_query = prepare_name_query(db)?;
+    let mut steps_query = db.prepare(
+        "SELECT
+            coalesce(gfa1_path.name, cast(e1.path_id AS TEXT)), e2.ordinal,
+            e1.segment_id, e1.reverse, e2.segment_id, e2.reverse, e2.cigar_vs_previous
+         FROM gfa1_path_element AS e1
+            INNER JOIN gfa1_path_element AS e2
+                ON e1.path_id = e2.path_id AND e2.ordinal = e1.ordinal + 1
+            LEFT JOIN gfa1_path ON gfa1_path.path_id = e1.path_id
+         ORDER BY e1.path_id, e1.ordinal",
+    )?;
+    let mut steps_cursor = steps_query.query([])?;
+    while let Some(row) = steps_cursor.next()? {
+        let path_name: String = row.get(0)?;
+        let ordinal: i64 = row.get(1)?;
+        let step1 = (row.get(2)?, row.get(3)?);
+        let step2 = (row.get(4)?, row.get(5)?);
+        let maybe_path_cigar: Option<String> = row.get(6)?;
+        let describe = |name_query: &mut rusqlite::Statement| -> Result<String> {
+            Ok(format!(
+                "path {} element {}: {} -> {}",
+                path_name,
+                ordinal,
+                oriented_segment_name(name_query, step1.0, step1.1)?,
+                oriented_segment_name(name_query, step2.0, step2.1)?
+            ))
+        };
+        match find_link(&mut link_query, step1, step2)? {
+            None => ans.add(describe(&mut name_query)? + " has no link"),
+            Some(link_cigars) => {
+                if let Some(path_cigar) = maybe_path_cigar.filter(|c| c != "*") {
+                    let consistent =
+                        link_cigars
+                            .iter()
+                            .any(|maybe_link_cigar| match maybe_link_cigar {
+                                None => true,
+                                Some(c) => c == "*" || *c == path_cigar,
+                            });
+                    if !consistent {
+                        ans.add(format!(
+                            "{} overlap {} disagrees with link CIGAR {}",
+                            describe(&mut name_query)?,
+                            path_cigar,
+                            link_cigars
+                                .iter()
+                                .map(|c| c.clone().unwrap_or_else(|| String::from("*")))
+                                .collect::<Vec<String>>()
+                                .join(",")
+                        ))
+                    }
+                }
+            }
+        }
+    }
+    Ok(ans)
+}
+
+// consecutive walk steps lacking a link
+fn check_walk_links(db: &rusqlite::Connection, max_examples: usize) -> Result<Category> {
+    let mut ans = Category::new("walk steps follow links", max_examples);
+    let mut link_query = prepare_link_query(db)?;
+    let mut name_query = prepare_name_query(db)?;
+    let mut iter_walk_query = view::prepare_iter_walk(db)?;
+    let mut walks_query =
+        db.prepare("SELECT walk_id, sample, hap_idx, refseq_name FROM gfa1_walk ORDER BY walk_id")?;
+    let mut walks_cursor = walks_query.query([])?;
+    while let Some(row) = walks_cursor.next()? {
+        let walk_id: i64 = row.get(0)?;
+        let walk_name = format!(
+            "{}#{}#{}",
+            row.get::<usize, String>(1)?,
+            row.get::<usize, i64>(2)?,
+            row.get::<usize, String>(3)?
+        );
+        for (step, prev, cur) in walk_missing_links(&mut iter_walk_query, walk_id, |prev, cur| {
+            Ok(find_link(&mut link_query, prev, cur)?.is_some())
+        })? {
+            ans.add(format!(
+                "walk {} step {}: no link {} -> {}",
+                walk_name,
+                step,
+                oriented_segment_name(&mut name_query, prev.0, prev.1)?,
+                oriented_segment_name(&mut name_query, cur.0, cur.1)?
+            ))
+        }
+    }
+    Ok(ans)
+}
+
+// Find the steps of the walk which aren't linked to their predecessor, according to the given
+// predicate; returns a vector of (step index, previous oriented segment, oriented segment)
+pub fn walk_missing_links<F>(
+    iter_walk_query: &mut rusqlite::Statement,
+    walk_id: i64,
+    mut is_linked: F,
+) -> Result<Vec<(usize, OrientedSegment, OrientedSegment)>>
+where
+    F: FnMut(OrientedSegment, OrientedSegment) -> Result<bool>,
+{
+    let mut ans = Vec::new();
+    let mut maybe_prev = None;
+    let mut step = 0;
+    view::iter_walk(iter_walk_query, walk_id, |segment_id, reverse| {
+        let cur = (segment_id, reverse);
+        if let Some(prev) = maybe_prev {
+            if !is_linked(prev, cur)? {
+                ans.push((step, prev, cur))
+            }
+        }
+        maybe_prev = Some(cur);
+        step += 1;
+        Ok(true)
+    })?;
+    Ok(ans)
+}
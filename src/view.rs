@@ -4,10 +4,12 @@ use json::JsonValue;
 use log::{info, warn};
 use num_format::{Locale, ToFormattedString};
 use rusqlite::types::ValueRef;
-use rusqlite::{params, OpenFlags, OptionalExtension};
+use rusqlite::{params, OpenFlags};
 use std::{env, fs, io, path, process};
 
 use crate::bad_command;
+use crate::export;
+use crate::seq;
 use crate::util;
 use crate::util::Result;
 
@@ -27,6 +29,29 @@ pub struct Opts {
     /// For each segment with reference mappings, set gr:Z tag with one guessed range summarizing the mappings
     #[clap(long)]
     pub guess_ranges: bool,
+    /// Like --guess-ranges, but cluster each segment's mappings into loci and set gr:Z to up to
+    /// MAX_LOCI of them (;-separated, by covered fraction of the segment), with gs:Z strands and
+    /// gf:Z covered fractions
+    #[clap(long, name = "MAX_LOCI")]
+    pub guess_loci: Option<usize>,
+    /// Modifies --guess-ranges to use only the mappings in this set (see add-mappings --set)
+    /// instead of the unnamed set
+    #[clap(long, name = "NAME")]
+    pub mapping_set: Option<String>,
+    /// Write segment sequences as FASTA instead of GFA (with --guess-ranges in the header comments)
+    #[clap(long)]
+    pub fasta: bool,
+    /// Output format: gfa, fasta (same as --fasta), or graph for visualization with other tools
+    /// (Graphviz dot, graphml, or Cytoscape.js cyjs)
+    #[clap(long, name = "FORMAT", default_value = "gfa", possible_values = export::FORMATS)]
+    pub format: String,
+    /// Modifies --fasta or --format to include these segment tags in the FASTA header comments or
+    /// node attributes (comma-separated, e.g. LN,KC)
+    #[clap(long, name = "TAGS", alias = "fasta-tags")]
+    pub tags: Option<String>,
+    /// Modifies --fasta line width (0 for no line breaks)
+    #[clap(long, default_value = "60")]
+    pub line_width: usize,
 
     /// log extra progress reports
     #[clap(short, long)]
@@ -38,6 +63,17 @@ pub struct Opts {
 }
 
 pub fn main(opts: &Opts) -> Result<()> {
+    if opts.fasta && opts.format != "gfa" && opts.format != "fasta" {
+        bad_command!("--fasta and --format are mutually exclusive")
+    }
+    let format = if opts.fasta { "fasta" } else { &opts.format };
+    if format != "gfa" && opts.bandage {
+        bad_command!("--bandage requires GFA output format")
+    }
+    if format == "fasta" && opts.no_sequences {
+        bad_command!("--fasta is incompatible with --no-sequences")
+    }
+
     // formulate GenomicSQLite configuration JSON
     let mut dbopts = json::object::Object::new();
     dbopts.insert("immutable", json::JsonValue::from(true));
@@ -51,24 +87,48 @@ pub fn main(opts: &Opts) -> Result<()> {
 
     {
         let txn = db.transaction()?;
-        let mut maybe_guesser = if opts.guess_ranges {
-            Some(SegmentRangeGuesser::new(&txn, "")?)
+        let mut maybe_guesser = if opts.guess_ranges || opts.guess_loci.is_some() {
+            Some(SegmentRangeGuesser::new(
+                &txn,
+                "",
+                opts.mapping_set.as_deref(),
+                opts.guess_loci,
+            )?)
         } else {
             None
         };
         let mut tag_editor = |segment_id: i64, tags: &mut json::JsonValue| -> Result<()> {
             if let Some(ref mut guesser) = maybe_guesser {
-                if let Some(gr) = guesser.get(segment_id)? {
-                    tags.insert("gr:Z", gr).unwrap()
-                }
+                guesser.insert_tags(segment_id, tags)?
             }
             Ok(())
         };
 
-        if opts.output_gfa == "-" && !opts.bandage && atty::is(atty::Stream::Stdout) {
+        if format != "gfa" {
+            let tags = opts.tags.as_deref().unwrap_or("");
+            let mut write = |out: &mut dyn io::Write| {
+                if format == "fasta" {
+                    write_segments_fasta(&txn, "", tags, &mut tag_editor, opts.line_width, out)
+                } else {
+                    export::write_graph(&txn, format, "", "", tags, &mut tag_editor, out)
+                }
+            };
+            if opts.output_gfa == "-" && atty::is(atty::Stream::Stdout) {
+                less(write)?
+            } else {
+                let mut writer_box = if format == "fasta" {
+                    seq::fasta_writer(&opts.output_gfa)?
+                } else {
+                    export::writer(&opts.output_gfa, format)?
+                };
+                write(&mut *writer_box)?;
+                writer_box.flush()?
+            }
+        } else if opts.output_gfa == "-" && !opts.bandage && atty::is(atty::Stream::Stdout) {
             // interactive mode: pipe into less -S
             less(|less_in| {
                 write_header(&txn, less_in)
+                    .and_then(|_| write_provenance(&txn, less_in))
                     .and_then(|_| {
                         write_segments(&txn, "", !opts.no_sequences, &mut tag_editor, less_in)
                     })
@@ -85,6 +145,7 @@ pub fn main(opts: &Opts) -> Result<()> {
                 let mut writer_box = writer(&output_gfa)?;
                 let out = &mut *writer_box;
                 write_header(&txn, out)?;
+                write_provenance(&txn, out)?;
                 write_segments(&txn, "", !opts.no_sequences, &mut tag_editor, out)?;
                 write_links(&txn, "", out)?;
                 write_paths(&txn, "", out)?;
@@ -182,6 +243,35 @@ pub fn write_header(db: &rusqlite::Connection, writer: &mut dyn io::Write) -> Re
     Ok(())
 }
 
+// Write the provenance log as GFA comment lines: timestamp, gfabase version, command line, inputs
+// JSON, and any notes, tab-separated
+pub fn write_provenance(db: &rusqlite::Connection, writer: &mut dyn io::Write) -> Result<()> {
+    if !util::table_exists(db, "", "gfa1_provenance")? {
+        return Ok(());
+    }
+    let mut query = db.prepare(
+        "SELECT timestamp, gfabase_version, command_line, coalesce(inputs_json, '[]'), notes
+         FROM gfa1_provenance ORDER BY provenance_id",
+    )?;
+    let mut cursor = query.query([])?;
+    while let Some(row) = cursor.next()? {
+        let timestamp: String = row.get(0)?;
+        let gfabase_version: String = row.get(1)?;
+        let command_line: String = row.get(2)?;
+        let inputs_json: String = row.get(3)?;
+        let notes: Option<String> = row.get(4)?;
+        writer.write_fmt(format_args!(
+            "#\t{}\t{}\t{}\t{}",
+            timestamp, gfabase_version, command_line, inputs_json
+        ))?;
+        if let Some(notes) = notes {
+            writer.write_fmt(format_args!("\t{}", notes))?
+        }
+        writer.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
 pub fn write_segments(
     db: &rusqlite::Connection,
     where_clause: &str,
@@ -227,10 +317,11 @@ pub fn write_segments(
             writer.write_fmt(format_args!("\tLN:i:{}", sequence_length))?;
         }
         write_tags_with_editor(
-            "gfa1_segments_meta",
+            "gfa1_segment_meta",
             rowid,
             &tags_json,
             &mut tag_editor,
+            '\t',
             writer,
         )?;
         writer.write(b"\n")?;
@@ -238,6 +329,67 @@ pub fn write_segments(
     Ok(())
 }
 
+/// Write segments as FASTA, with the selected tags (comma-separated names, plus any inserted by
+/// tag_editor) as header comments. Segments lacking sequence are skipped.
+pub fn write_segments_fasta(
+    db: &rusqlite::Connection,
+    where_clause: &str,
+    tags: &str,
+    mut tag_editor: impl FnMut(i64, &mut json::JsonValue) -> Result<()>,
+    line_width: usize,
+    writer: &mut dyn io::Write,
+) -> Result<()> {
+    let selected_tags: Vec<&str> = tags.split(',').filter(|t| !t.is_empty()).collect();
+    let segments_query_sql = String::from(
+        "SELECT
+            segment_id, coalesce(name, cast(segment_id AS TEXT)), sequence_length,
+            coalesce(tags_json, '{}'), sequence
+         FROM gfa1_segment ",
+    ) + where_clause;
+    let mut segments_query = db.prepare(&segments_query_sql)?;
+    let mut segments_cursor = segments_query.query([])?;
+    let mut skipped = 0;
+    while let Some(segrow) = segments_cursor.next()? {
+        let rowid: i64 = segrow.get(0)?;
+        let name: String = segrow.get(1)?;
+        let maybe_sequence_length: Option<i64> = segrow.get(2)?;
+        let tags_json: String = segrow.get(3)?;
+        let sequence: String = match segrow.get(4)? {
+            Some(sequence) => sequence,
+            None => {
+                skipped += 1;
+                continue;
+            }
+        };
+        let mut header = Vec::new();
+        header.write_fmt(format_args!("{}", name))?;
+        write_tags_with_editor(
+            "gfa1_segment_meta",
+            rowid,
+            &tags_json,
+            |segment_id, tags| {
+                let mut selected = select_tags(&selected_tags, maybe_sequence_length, tags);
+                // tags inserted by the editor are always included
+                tag_editor(segment_id, &mut selected)?;
+                *tags = selected;
+                Ok(())
+            },
+            ' ',
+            &mut header,
+        )?;
+        seq::write_fasta(
+            writer,
+            &String::from_utf8_lossy(&header),
+            &sequence,
+            line_width,
+        )?;
+    }
+    if skipped > 0 {
+        warn!("skipped {} segments lacking sequence", skipped)
+    }
+    Ok(())
+}
+
 pub fn write_links(
     db: &rusqlite::Connection,
     where_clause: &str,
@@ -374,11 +526,33 @@ pub fn write_walks(
     Ok(())
 }
 
+/// Filter segment tags to those selected by name (e.g. KC) or name:type (e.g. KC:i), also
+/// producing LN:i from the sequence length if selected.
+pub fn select_tags(
+    selected_tags: &[&str],
+    maybe_sequence_length: Option<i64>,
+    tags: &json::JsonValue,
+) -> json::JsonValue {
+    let mut selected = json::JsonValue::new_object();
+    if let Some(sequence_length) = maybe_sequence_length {
+        if selected_tags.contains(&"LN") || selected_tags.contains(&"LN:i") {
+            selected.insert("LN:i", sequence_length).unwrap()
+        }
+    }
+    for (k, v) in tags.entries() {
+        if selected_tags.contains(&k) || selected_tags.contains(&k.split(':').next().unwrap()) {
+            selected.insert(k, v.clone()).unwrap()
+        }
+    }
+    selected
+}
+
 fn write_tags_with_editor(
     table: &str,
     rowid: i64,
     tags_json: &str,
     mut editor: impl FnMut(i64, &mut json::JsonValue) -> Result<()>,
+    separator: char,
     writer: &mut dyn io::Write,
 ) -> Result<()> {
     let invalid = || util::Error::InvalidGfab {
@@ -401,90 +575,153 @@ fn write_tags_with_editor(
             // TODO: B & J
             _ => return Err(invalid()),
         };
-        writer.write_fmt(format_args!("\t{}:{}", k, vstr))?;
+        writer.write_fmt(format_args!("{}{}:{}", separator, k, vstr))?;
     }
     Ok(())
 }
 
 fn write_tags(table: &str, rowid: i64, tags_json: &str, writer: &mut dyn io::Write) -> Result<()> {
-    write_tags_with_editor(table, rowid, tags_json, |_, _| Ok(()), writer)
+    write_tags_with_editor(table, rowid, tags_json, |_, _| Ok(()), '\t', writer)
 }
 
 // Helpers roughly guessing a genomic range for a segment based on its PAF mappings. Selects the
 // chromosome with the most coverage in the mappings, then the min and max mapped position on that
-// chromosome.
+// chromosome. Alternatively, given max_loci, clusters each segment's mappings into loci (nearby
+// mappings on the same chromosome) and reports up to that many, ranked by the fraction of the
+// segment they cover, with their predominant strand.
 pub struct SegmentRangeGuesser<'a> {
     getter: rusqlite::Statement<'a>,
     csv_query: rusqlite::Statement<'a>,
+    loci: bool,
+}
+
+// A cluster of one segment's mappings onto a chromosome
+struct Locus {
+    refseq_name: String,
+    refseq_begin: i64,
+    refseq_end: i64,
+    // mapped bases in each orientation
+    forward_bases: i64,
+    reverse_bases: i64,
+    // segment ranges covered by the mappings
+    segment_ranges: Vec<(i64, i64)>,
 }
 
 impl<'a> SegmentRangeGuesser<'_> {
     pub fn new(
         db: &'a rusqlite::Connection,
         where_clause: &str,
+        mapping_set: Option<&str>,
+        max_loci: Option<usize>,
     ) -> Result<SegmentRangeGuesser<'a>> {
+        // consider only the mappings in the given set, or else the unnamed set
+        let set_condition = util::mapping_set_condition(db, "", mapping_set)?;
+        let where_clause = if set_condition.is_empty() {
+            String::from(where_clause)
+        } else if where_clause.is_empty() {
+            format!("WHERE {}", set_condition)
+        } else {
+            format!("{} AND {}", where_clause, set_condition)
+        };
         // analyze mappings to generate temp.segment_range_guess
         db.execute(
             "CREATE TABLE temp.segment_range_guess(
-                segment_id INTEGER PRIMARY KEY,
+                segment_id INTEGER NOT NULL,
+                locus_rank INTEGER NOT NULL,
                 refseq_name TEXT NOT NULL,
-                refseq_begin INTEGER NOT NULL, refseq_end INTEGER NOT NULL)",
+                refseq_begin INTEGER NOT NULL, refseq_end INTEGER NOT NULL,
+                strand TEXT, covered REAL,
+                PRIMARY KEY (segment_id, locus_rank))",
             [],
         )?;
-        let sql = format!(
-            "WITH summary AS
-                (SELECT
-                    segment_id, refseq_name,
-                    min(refseq_begin) AS min_begin, max(refseq_end) AS max_end,
-                    max(refseq_end) - min(refseq_begin) AS coverage,
-                    sum(refseq_end - refseq_begin) AS coverage2
-                FROM gfa1_segment_mapping
-                {}
-                GROUP BY segment_id, refseq_name)
-             INSERT INTO temp.segment_range_guess(segment_id, refseq_name, refseq_begin, refseq_end)
-                SELECT segment_id, refseq_name, min_begin, max_end
-                FROM
-                    (SELECT
-                        segment_id, refseq_name, min_begin, max_end,
-                        row_number() OVER (PARTITION BY segment_id ORDER BY coverage DESC, coverage2 DESC)
-                            AS coverage_rank
-                    FROM summary)
-                WHERE coverage_rank = 1",
-            where_clause
-        );
-        let n = db.execute(&sql, [])?;
+        let n = match max_loci {
+            Some(max_loci) => guess_loci(db, &where_clause, mapping_set, max_loci)?,
+            None => {
+                let sql = format!(
+                    "WITH summary AS
+                        (SELECT
+                            segment_id, refseq_name,
+                            min(refseq_begin) AS min_begin, max(refseq_end) AS max_end,
+                            max(refseq_end) - min(refseq_begin) AS coverage,
+                            sum(refseq_end - refseq_begin) AS coverage2
+                        FROM gfa1_segment_mapping
+                        {}
+                        GROUP BY segment_id, refseq_name)
+                     INSERT INTO temp.segment_range_guess(segment_id, locus_rank, refseq_name, refseq_begin, refseq_end)
+                        SELECT segment_id, 1, refseq_name, min_begin, max_end
+                        FROM
+                            (SELECT
+                                segment_id, refseq_name, min_begin, max_end,
+                                row_number() OVER (PARTITION BY segment_id ORDER BY coverage DESC, coverage2 DESC)
+                                    AS coverage_rank
+                            FROM summary)
+                        WHERE coverage_rank = 1",
+                    where_clause
+                );
+                match mapping_set {
+                    Some(mapping_set) => db.execute(&sql, params![mapping_set])?,
+                    None => db.execute(&sql, [])?,
+                }
+            }
+        };
         info!("guessed ranges for {} segments", n);
         // prepare queries on temp.segment_range_guess
         Ok(SegmentRangeGuesser {
             getter: db.prepare(
-                "SELECT refseq_name, refseq_begin, refseq_end
-                 FROM temp.segment_range_guess WHERE segment_id = ?",
+                "SELECT refseq_name, refseq_begin, refseq_end, strand, covered
+                 FROM temp.segment_range_guess WHERE segment_id = ? ORDER BY locus_rank",
             )?,
             csv_query: db.prepare(
-                "SELECT
-                    coalesce(name, cast(segment_id AS TEXT)),
-                    refseq_name, refseq_begin, refseq_end
-                 FROM temp.segment_range_guess LEFT JOIN gfa1_segment_meta USING(segment_id)",
+                "SELECT segment_id, coalesce(name, cast(segment_id AS TEXT))
+                 FROM temp.segment_range_guess LEFT JOIN gfa1_segment_meta USING(segment_id)
+                 WHERE locus_rank = 1 ORDER BY segment_id",
             )?,
+            loci: max_loci.is_some(),
         })
     }
 
+    // guessed range(s) (;-separated), strand(s), and covered fraction(s)
+    fn get_loci(&mut self, segment_id: i64) -> Result<Option<(String, String, String)>> {
+        let mut ranges = Vec::new();
+        let mut strands = Vec::new();
+        let mut covered = Vec::new();
+        let mut cursor = self.getter.query(params![segment_id])?;
+        while let Some(row) = cursor.next()? {
+            ranges.push(format_guessed_range(
+                &row.get::<_, String>(0)?,
+                row.get(1)?,
+                row.get(2)?,
+            ));
+            strands.push(row.get::<_, Option<String>>(3)?.unwrap_or_default());
+            covered.push(format!(
+                "{:.2}",
+                row.get::<_, Option<f64>>(4)?.unwrap_or(0.0)
+            ));
+        }
+        if ranges.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some((
+            ranges.join(";"),
+            strands.join(";"),
+            covered.join(";"),
+        )))
+    }
+
     pub fn get(&mut self, segment_id: i64) -> Result<Option<String>> {
-        let maybe_row: Option<(String, i64, i64)> = self
-            .getter
-            .query_row(params![segment_id], |row| {
-                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
-            })
-            .optional()?;
-        if let Some((refseq_name, refseq_begin, refseq_end)) = maybe_row {
-            return Ok(Some(format!(
-                "~{}:{}-{}",
-                refseq_name,
-                (refseq_begin + 1).to_formatted_string(&Locale::en),
-                refseq_end.to_formatted_string(&Locale::en)
-            )));
+        Ok(self.get_loci(segment_id)?.map(|(ranges, _, _)| ranges))
+    }
+
+    // set gr:Z, and with loci, gs:Z (strands) and gf:Z (covered fractions)
+    pub fn insert_tags(&mut self, segment_id: i64, tags: &mut json::JsonValue) -> Result<()> {
+        if let Some((ranges, strands, covered)) = self.get_loci(segment_id)? {
+            tags.insert("gr:Z", ranges).unwrap();
+            if self.loci {
+                tags.insert("gs:Z", strands).unwrap();
+                tags.insert("gf:Z", covered).unwrap()
+            }
         }
-        Ok(None)
+        Ok(())
     }
 
     pub fn write_bandage_csv(&mut self, gfa_filename: &str) -> Result<()> {
@@ -493,20 +730,28 @@ impl<'a> SegmentRangeGuesser<'_> {
             + ".guessed_ranges.csv";
         {
             let mut writer = io::BufWriter::new(fs::File::create(&csv_filename)?);
-            writer.write_fmt(format_args!("Name,Guessed range\n"))?;
-            let mut cursor = self.csv_query.query([])?;
-            while let Some(row) = cursor.next()? {
-                let name: String = row.get(0)?;
-                let refseq_name: String = row.get(1)?;
-                let refseq_begin: i64 = row.get(2)?;
-                let refseq_end: i64 = row.get(3)?;
+            if self.loci {
                 writer.write_fmt(format_args!(
-                    "\"{}\",\"~{}:{}-{}\"\n",
-                    name,
-                    refseq_name,
-                    (refseq_begin + 1).to_formatted_string(&Locale::en),
-                    refseq_end.to_formatted_string(&Locale::en)
-                ))?;
+                    "Name,Guessed range,Guessed strand,Covered fraction\n"
+                ))?
+            } else {
+                writer.write_fmt(format_args!("Name,Guessed range\n"))?
+            }
+            let mut segments = Vec::new();
+            {
+                let mut cursor = self.csv_query.query([])?;
+                while let Some(row) = cursor.next()? {
+                    segments.push((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+                }
+            }
+            for (segment_id, name) in segments {
+                if let Some((ranges, strands, covered)) = self.get_loci(segment_id)? {
+                    writer.write_fmt(format_args!("\"{}\",\"{}\"", name, ranges))?;
+                    if self.loci {
+                        writer.write_fmt(format_args!(",\"{}\",\"{}\"", strands, covered))?
+                    }
+                    writer.write_all(b"\n")?
+                }
             }
         }
         info!("wrote CSV with guessed segment ranges to {}", csv_filename);
@@ -514,20 +759,184 @@ impl<'a> SegmentRangeGuesser<'_> {
     }
 }
 
+fn format_guessed_range(refseq_name: &str, refseq_begin: i64, refseq_end: i64) -> String {
+    format!(
+        "~{}:{}-{}",
+        refseq_name,
+        (refseq_begin + 1).to_formatted_string(&Locale::en),
+        refseq_end.to_formatted_string(&Locale::en)
+    )
+}
+
+// Cluster each segment's mappings into loci, then insert up to max_loci of them into
+// temp.segment_range_guess, ranked by covered fraction of the segment, then mapped bases. Mappings
+// on the same chromosome join a locus if they lie within one segment length of it.
+fn guess_loci(
+    db: &rusqlite::Connection,
+    where_clause: &str,
+    mapping_set: Option<&str>,
+    max_loci: usize,
+) -> Result<usize> {
+    let mut mappings_query = db.prepare(&format!(
+        "SELECT segment_id, refseq_name, refseq_begin, refseq_end, m.tags_json, s.sequence_length
+         FROM gfa1_segment_mapping AS m LEFT JOIN gfa1_segment_meta AS s USING(segment_id)
+         {}
+         ORDER BY segment_id, refseq_name, refseq_begin",
+        where_clause
+    ))?;
+    let mut insert_locus = db.prepare(
+        "INSERT INTO temp.segment_range_guess(
+            segment_id, locus_rank, refseq_name, refseq_begin, refseq_end, strand, covered)
+         VALUES(?, ?, ?, ?, ?, ?, ?)",
+    )?;
+    let mut segment_count = 0;
+    let mut flush = |segment_id: i64, segment_length: i64, loci: &mut Vec<Locus>| -> Result<()> {
+        let mut ranked: Vec<(f64, i64, &Locus)> = loci
+            .iter()
+            .map(|locus| {
+                let covered = covered_length(&locus.segment_ranges) as f64
+                    / std::cmp::max(segment_length, 1) as f64;
+                (
+                    covered.min(1.0),
+                    locus.forward_bases + locus.reverse_bases,
+                    locus,
+                )
+            })
+            .collect();
+        ranked.sort_by(|a, b| {
+            b.0.partial_cmp(&a.0)
+                .unwrap()
+                .then(b.1.cmp(&a.1))
+                .then(a.2.refseq_name.cmp(&b.2.refseq_name))
+                .then(a.2.refseq_begin.cmp(&b.2.refseq_begin))
+        });
+        for (rank, (covered, _, locus)) in ranked.iter().take(max_loci).enumerate() {
+            let strand = match locus.forward_bases.cmp(&locus.reverse_bases) {
+                std::cmp::Ordering::Greater => "+",
+                std::cmp::Ordering::Less => "-",
+                std::cmp::Ordering::Equal => ".",
+            };
+            insert_locus.execute(params![
+                segment_id,
+                rank as i64 + 1,
+                locus.refseq_name,
+                locus.refseq_begin,
+                locus.refseq_end,
+                strand,
+                covered
+            ])?;
+        }
+        loci.clear();
+        segment_count += 1;
+        Ok(())
+    };
+
+    let mut cursor = match mapping_set {
+        Some(mapping_set) => mappings_query.query(params![mapping_set])?,
+        None => mappings_query.query([])?,
+    };
+    let mut current: Option<(i64, i64)> = None;
+    let mut loci: Vec<Locus> = Vec::new();
+    while let Some(row) = cursor.next()? {
+        let segment_id: i64 = row.get(0)?;
+        let refseq_name: String = row.get(1)?;
+        let refseq_begin: i64 = row.get(2)?;
+        let refseq_end: i64 = row.get(3)?;
+        let tags = match row.get::<_, Option<String>>(4)? {
+            Some(tags_json) => json::parse(&tags_json).unwrap_or(json::JsonValue::Null),
+            None => json::JsonValue::Null,
+        };
+        // mappings lacking segment range tags (e.g. from rGFA) cover the whole segment
+        let segment_length: i64 = row
+            .get::<_, Option<i64>>(5)?
+            .or_else(|| tags["se:i"].as_i64())
+            .unwrap_or(refseq_end - refseq_begin);
+        let segment_range = (
+            tags["sb:i"].as_i64().unwrap_or(0),
+            tags["se:i"].as_i64().unwrap_or(segment_length),
+        );
+        let reverse = tags["so:Z"].as_str() == Some("-");
+
+        if let Some((current_id, current_length)) = current {
+            if current_id != segment_id {
+                flush(current_id, current_length, &mut loci)?
+            }
+        }
+        current = Some((segment_id, segment_length));
+
+        let mapped_bases = refseq_end - refseq_begin;
+        let locus = match loci.last_mut() {
+            Some(locus)
+                if locus.refseq_name == refseq_name
+                    && refseq_begin <= locus.refseq_end + segment_length =>
+            {
+                locus.refseq_end = std::cmp::max(locus.refseq_end, refseq_end);
+                locus
+            }
+            _ => {
+                loci.push(Locus {
+                    refseq_name,
+                    refseq_begin,
+                    refseq_end,
+                    forward_bases: 0,
+                    reverse_bases: 0,
+                    segment_ranges: Vec::new(),
+                });
+                loci.last_mut().unwrap()
+            }
+        };
+        if reverse {
+            locus.reverse_bases += mapped_bases
+        } else {
+            locus.forward_bases += mapped_bases
+        }
+        locus.segment_ranges.push(segment_range);
+    }
+    if let Some((current_id, current_length)) = current {
+        flush(current_id, current_length, &mut loci)?
+    }
+    Ok(segment_count)
+}
+
+// total length of the union of the given ranges
+fn covered_length(ranges: &[(i64, i64)]) -> i64 {
+    let mut ranges = ranges.to_vec();
+    ranges.sort_unstable();
+    let mut ans = 0;
+    let mut covered_to = i64::MIN;
+    for (begin, end) in ranges {
+        let begin = std::cmp::max(begin, covered_to);
+        if end > begin {
+            ans += end - begin;
+            covered_to = end
+        }
+    }
+    ans
+}
+
 // Helpers for iterating over steps of a GFA Walk, automatically decoding any stored deltas to
 // produce a sequence of (segment_id: i64, reverse_orientation: bool).
 // First, a query preparation amortized over multiple Walks if needed.
 pub fn prepare_iter_walk<'a>(db: &'a rusqlite::Connection) -> Result<rusqlite::Statement<'a>> {
-    match db.prepare(
+    prepare_iter_walk_in(db, "")
+}
+
+// ...or on an attached schema (e.g. "input.")
+pub fn prepare_iter_walk_in<'a>(
+    db: &'a rusqlite::Connection,
+    schema: &str,
+) -> Result<rusqlite::Statement<'a>> {
+    match db.prepare(&format!(
         "SELECT
             json_extract(json_each.value,'$.s'),
             json_extract(json_each.value,'$.+'),
             json_extract(json_each.value,'$.-'),
             json_extract(json_each.value,'$.r')
          FROM
-            gfa1_walk_steps, json_each(gfa1_walk_steps.steps_jsarray)
+            {0}gfa1_walk_steps, json_each({0}gfa1_walk_steps.steps_jsarray)
          WHERE walk_id = ?",
-    ) {
+        schema
+    )) {
         Ok(stmt) => Ok(stmt),
         Err(e) => Err(util::Error::DbError(e)),
     }
@@ -7,7 +7,7 @@ cd "$REPO"
 export BASH_TAP_ROOT=test/bash-tap
 source test/bash-tap/bash-tap-bootstrap
 
-plan tests 18
+plan tests 31
 
 ./cargo build --release
 is "$?" "0" "cargo build"
@@ -52,6 +52,12 @@ gfabase view "${TMPDIR}/roundtrip2.gfab" --no-sequences | grep GATTACA
 is "$?" "1" "view --no-sequences"
 gfabase view "${TMPDIR}/roundtrip2.gfab" | grep GATTACA > /dev/null
 is "$?" "0" "view --no-sequences (control)"
+is "$(gfabase view "${TMPDIR}/roundtrip2.gfab" --fasta --line-width 0 | grep -vc '>')" \
+   "$(grep -c ^S "${TMPDIR}/atcc_staggered.assembly_graph_with_scaffolds.gfa")" \
+   "view --fasta"
+is "$(gfabase view "${TMPDIR}/roundtrip2.gfab" --format graphml | grep -c '<node ')" \
+   "$(grep -c ^S "${TMPDIR}/atcc_staggered.assembly_graph_with_scaffolds.gfa")" \
+   "view --format graphml"
 
 # sub two scaffolds and make sure we get those Paths
 time gfabase --verbose sub "${TMPDIR}/atcc_staggered.assembly_graph_with_scaffolds.gfab" -o "${TMPDIR}/sub.gfab" \
@@ -61,6 +67,8 @@ gfabase view "${TMPDIR}/sub.gfab" | grep NODE_2_length_747618_cov_15.708553_3
 is "$?" "0" "sub scaffold NODE_2_length_747618_cov_15.708553_3"
 gfabase view "${TMPDIR}/sub.gfab" | grep NODE_2_length_747618_cov_15.708553_4
 is "$?" "0" "sub scaffold grep NODE_2_length_747618_cov_15.708553_4"
+gfabase render "${TMPDIR}/sub.gfab" --paths -o "${TMPDIR}/sub.svg"
+is "$(grep -c '<rect .*data-segment-id' "${TMPDIR}/sub.svg")" "$(gfabase view "${TMPDIR}/sub.gfab" | grep -c ^S)" "render"
 
 time gfabase sub --view "${TMPDIR}/atcc_staggered.assembly_graph_with_scaffolds.gfab" \
     20412 106423 14364 133587 133589 17280 > "${TMPDIR}/sub.gfa"
@@ -74,6 +82,43 @@ time gfabase sub --view "${TMPDIR}/atcc_staggered.assembly_graph_with_scaffolds.
 is "$?" "0" "sub --view by path"
 is "$(cat "${TMPDIR}/sub_by_path.gfa" | wc -l | tr -d ' ')" "15" "sub --view by path line count"
 
+gfabase stats --json "${TMPDIR}/atcc_staggered.assembly_graph_with_scaffolds.gfab" | grep '"cutpoints"'
+is "$?" "0" "gfabase stats"
+
+# load without connectivity index (computed on the fly for --cutpoints), then add it with
+# gfabase index
+gfabase load --no-connectivity "${TMPDIR}/atcc_staggered.assembly_graph_with_scaffolds.gfa" -o "${TMPDIR}/no_connectivity.gfab"
+is "$(gfabase sub --view --cutpoints 1 "${TMPDIR}/no_connectivity.gfab" 20412 | sha256sum)" \
+   "$(gfabase sub --view --cutpoints 1 "${TMPDIR}/atcc_staggered.assembly_graph_with_scaffolds.gfab" 20412 | sha256sum)" \
+   "sub --cutpoints without connectivity index"
+gfabase index --connectivity "${TMPDIR}/no_connectivity.gfab"
+is "$?" "0" "gfabase index --connectivity"
+is "$(gfabase sub --view --cutpoints 1 "${TMPDIR}/no_connectivity.gfab" 20412 | sha256sum)" \
+   "$(gfabase sub --view --cutpoints 1 "${TMPDIR}/atcc_staggered.assembly_graph_with_scaffolds.gfab" 20412 | sha256sum)" \
+   "sub --cutpoints after gfabase index"
+
+# merge a subgraph back into the whole graph
+gfabase merge "${TMPDIR}/sub.gfab" "${TMPDIR}/atcc_staggered.assembly_graph_with_scaffolds.gfab" -o "${TMPDIR}/merged.gfab"
+is "$?" "0" "gfabase merge"
+is "$(gfabase view "${TMPDIR}/merged.gfab" | grep -vc '^#')" \
+   "$(gfabase view "${TMPDIR}/atcc_staggered.assembly_graph_with_scaffolds.gfab" | grep -vc '^#')" \
+   "gfabase merge line count"
+
+# diff the whole graph against the merged graph (identical) and against the subgraph
+gfabase diff "${TMPDIR}/atcc_staggered.assembly_graph_with_scaffolds.gfab" "${TMPDIR}/merged.gfab" > "${TMPDIR}/diff_same.txt"
+is "$(grep -v "	0$" "${TMPDIR}/diff_same.txt" | wc -l | tr -d ' ')" "0" "gfabase diff (no differences)"
+gfabase diff "${TMPDIR}/atcc_staggered.assembly_graph_with_scaffolds.gfab" "${TMPDIR}/sub.gfab" -o "${TMPDIR}/diff.gfa" > "${TMPDIR}/diff.txt"
+is "$(grep "^segments removed" "${TMPDIR}/diff.txt" | cut -f2)" \
+   "$(( $(grep -c ^S "${TMPDIR}/atcc_staggered.assembly_graph_with_scaffolds.gfa") - $(gfabase view "${TMPDIR}/sub.gfab" | grep -c ^S) ))" \
+   "gfabase diff segments removed"
+
+# k-mer index & approximate sequence query
+gfabase view "${TMPDIR}/sub.gfab" | awk '$1 == "S" { print ">" $2; print substr($3, 1, 100) }' | head -n 2 > "${TMPDIR}/query.fa"
+gfabase index --kmers "${TMPDIR}/sub.gfab"
+is "$?" "0" "gfabase index --kmers"
+gfabase sub --view "${TMPDIR}/sub.gfab" --query-fasta "${TMPDIR}/query.fa" | grep "^S	$(head -n 1 "${TMPDIR}/query.fa" | tr -d '>')	"
+is "$?" "0" "sub --query-fasta"
+
 # test behavior w/ empty input
 gfabase load /dev/null -o "${TMPDIR}/empty.gfab"
 is "$?" "3" "gfabase load empty"
@@ -0,0 +1,156 @@
+#!/bin/bash
+
+# bash-tap tests for gfabase CLI ops on the small fixture test/data/small.gfa (segments 1-3 & q,
+# with a walk through 1-3) and its mappings test/data/small.{gaf,sam,bam,paf} (all of segment q)
+# requires cargo toolchain and:
+#   pip3 install --upgrade genomicsqlite
+
+set -o pipefail
+
+REPO="$(dirname "$0")/.."
+cd "$REPO"
+export BASH_TAP_ROOT=test/bash-tap
+source test/bash-tap/bash-tap-bootstrap
+export LC_ALL=C
+
+plan tests 43
+
+./cargo build --release
+is "$?" "0" "cargo build"
+PATH="$(pwd)/target/release:${PATH}"
+
+if [[ -z $TMPDIR ]]; then
+    TMPDIR=/tmp
+fi
+TMPDIR=$(mktemp -d "${TMPDIR}/gfabase_small_test_XXXXXX")
+export TMPDIR=$(realpath "$TMPDIR")
+DATA=test/data
+
+# load & validate
+gfabase validate "${DATA}/small.gfa"
+is "$?" "0" "gfabase validate"
+gzip -c "${DATA}/small.gfa" | tee "${TMPDIR}/small.gfa.gz" | gunzip | gfabase validate -
+is "$?" "0" "gfabase validate -"
+gfabase validate "${TMPDIR}/small.gfa.gz"
+is "$?" "0" "gfabase validate .gfa.gz"
+is "$(sed 's/^S\t2\tGGCC/S\t2\tGGCC\tLN:i:5/' "${DATA}/small.gfa" | gfabase validate - | grep -c 'segment 2 LN = 5 but sequence length = 4')" \
+   "1" "gfabase validate (inconsistent LN:i)"
+gfabase load "${DATA}/small.gfa" -o "${TMPDIR}/small.gfab"
+is "$?" "0" "gfabase load"
+gfabase load --reference-sample s "${DATA}/small.gfa" -o "${TMPDIR}/smallref.gfab"
+is "$(gfabase sub "${TMPDIR}/smallref.gfab" --range c:6-7 --view | grep ^S | cut -f2)" "2" "load --reference-sample"
+gfabase load --no-connectivity "${DATA}/small.gfa" -o "${TMPDIR}/small_noconn.gfab"
+is "$(gfabase stats --json "${TMPDIR}/small_noconn.gfab" | sha256sum)" "$(gfabase stats --json "${TMPDIR}/small.gfab" | sha256sum)" \
+   "gfabase stats without connectivity index"
+printf 'S\ta\tACGT\nS\tb\tACGTACGT\n' | gfabase load - -o "${TMPDIR}/isolated.gfab"
+is "$(gfabase stats --json "${TMPDIR}/isolated.gfab" | grep -E '"largest_(segments|bp)"' | tr -d ' \n')" \
+   '"largest_segments":1,"largest_bp":8,' "gfabase stats largest component of isolated segments"
+
+# sequence access
+is "$(gfabase sub --view "${TMPDIR}/small.gfab" --sequence ggcc | grep ^S | cut -f2)" "2" "sub --sequence"
+gfabase sub --view "${TMPDIR}/small.gfab" --sequence "$(printf 'é%.0s' {1..40})"
+is "$?" "1" "sub --sequence (long non-ASCII query not found)"
+cp "${TMPDIR}/small.gfab" "${TMPDIR}/small_kmers.gfab"
+gfabase index --kmers "${TMPDIR}/small_kmers.gfab"
+gfabase sub --view "${TMPDIR}/small_kmers.gfab" --query "$(printf 'é%.0s' {1..40})"
+is "$?" "1" "sub --query (long non-ASCII query not found)"
+is "$(gfabase seq "${TMPDIR}/small.gfab" 2:2-4 1:1-3- --line-width 0 | grep -v '>' | tr -d '\n')" "GCCCGT" "gfabase seq"
+is "$(gfabase seq "${TMPDIR}/small.gfab" 2:3-4- 3 --line-width 0 | grep -v '>' | tr '\n' ' ')" "GG TTAA " \
+   "gfabase seq (segment_dna SQL function)"
+is "$(gfabase spell "${TMPDIR}/small.gfab" --walk 's#1#c' --range 3-10 | tail -n 1)" "GTGGCCTT" "gfabase spell"
+
+# mappings of q onto path >1>2 (GAF, of the same graph or via --gaf-graph), chr9 (SAM/BAM), and chr7/chr5/chr3 (PAF)
+gfabase add-mappings "${TMPDIR}/small.gfab" "${DATA}/small.gaf" -o "${TMPDIR}/mapped.gfab"
+is "$(gfabase sub "${TMPDIR}/mapped.gfab" --range 2:1-4 --view | grep ^S | cut -f2)" "q" "add-mappings GAF"
+grep -P '^S\tq\t' "${DATA}/small.gfa" | gfabase load - -o "${TMPDIR}/q.gfab"
+gfabase add-mappings "${TMPDIR}/q.gfab" "${DATA}/small.gaf" --gaf-graph "${TMPDIR}/small.gfab"
+is "$(gfabase sub "${TMPDIR}/q.gfab" --range 2:1-4 --view | grep ^S | cut -f2)" "q" "add-mappings GAF --gaf-graph"
+gfabase add-mappings "${TMPDIR}/mapped.gfab" "${DATA}/small.sam"
+is "$(gfabase sub "${TMPDIR}/mapped.gfab" --range chr9:16-20 --view | grep ^S | cut -f2)" "q" "add-mappings SAM"
+# small.bam: q reverse on chr9 (2S6M), forward on chr7 with odd-length SEQ (1S6M1H, NM 1),
+# secondary on chr5, and supplementary on chr3
+gfabase add-mappings "${TMPDIR}/small.gfab" "${DATA}/small.bam" -o "${TMPDIR}/bam.gfab"
+is "$(gfabase liftover --to-segments "${TMPDIR}/bam.gfab" chr9:11 chr7:31 | cut -f2- | tr '\t\n' '  ')" "q:6 - q:2 + " \
+   "add-mappings BAM"
+is "$(gfabase sub "${TMPDIR}/bam.gfab" --range chr7:31-36 --min-identity 0.8 --view | grep -c ^S) $(gfabase sub "${TMPDIR}/bam.gfab" --range chr7:31-36 --min-identity 0.9 --view | grep -c ^S)" \
+   "1 0" "add-mappings BAM NM tag"
+gfabase add-mappings "${TMPDIR}/small.gfab" "${DATA}/small.bam" --no-secondary -o "${TMPDIR}/bam2.gfab"
+is "$(gfabase sub "${TMPDIR}/bam.gfab" --range chr5:41-48 --view | grep -c ^S) $(gfabase sub "${TMPDIR}/bam2.gfab" --range chr5:41-48 --view | grep -c ^S)" \
+   "1 0" "add-mappings BAM --no-secondary"
+gfabase add-mappings "${TMPDIR}/small.gfab" "${DATA}/small.bam" --no-supplementary -o "${TMPDIR}/bam3.gfab"
+is "$(gfabase sub "${TMPDIR}/bam.gfab" --range chr3:61-68 --view | grep -c ^S) $(gfabase sub "${TMPDIR}/bam3.gfab" --range chr3:61-68 --view | grep -c ^S) $(gfabase sub "${TMPDIR}/bam3.gfab" --range chr5:41-48 --view | grep -c ^S)" \
+   "1 0 1" "add-mappings BAM --no-supplementary"
+printf 'BAM\1\0\0\0\0\0\0\0\0\377\377\377\377' | gzip | gfabase add-mappings "${TMPDIR}/small.gfab" - --format bam -o "${TMPDIR}/bam4.gfab"
+is "$?" "1" "add-mappings BAM invalid block_size"
+grep 'cg:Z' "${DATA}/small.paf" | gfabase add-mappings "${TMPDIR}/mapped.gfab" -
+is "$(gfabase liftover --to-segments "${TMPDIR}/mapped.gfab" chr7:15 chr7:16 | cut -f2 | tr '\n' ' ')" "* q:5 " "gfabase liftover"
+grep -P '\tchr7\t100\t50\t' "${DATA}/small.paf" | gfabase add-mappings "${TMPDIR}/mapped.gfab" - --set other
+is "$(gfabase view --guess-ranges --mapping-set other "${TMPDIR}/mapped.gfab" | grep "^S	q	" | cut -f5)" "gr:Z:~chr7:51-58" "add-mappings --set"
+grep -w chr5 "${DATA}/small.paf" | gfabase add-mappings "${TMPDIR}/mapped.gfab" -
+is "$(gfabase sub "${TMPDIR}/mapped.gfab" --range chr5:12-13 --min-identity 0.7 --view | grep -c ^S) $(gfabase sub "${TMPDIR}/mapped.gfab" --range chr5:12-13 --min-identity 0.9 --view | grep -c ^S)" \
+   "1 0" "sub --range --min-identity"
+# q on chr1 at MAPQ 60 & 20, and a secondary (tp:A:S) on chr2 at MAPQ 60
+printf 'q\t8\t0\t8\t+\tchr1\t100\t10\t18\t8\t8\t60\nq\t8\t0\t8\t+\tchr1\t100\t50\t58\t8\t8\t20\nq\t8\t0\t8\t+\tchr2\t100\t10\t18\t8\t8\t60\ttp:A:S\n' > "${TMPDIR}/best.paf"
+gfabase add-mappings "${TMPDIR}/small.gfab" "${TMPDIR}/best.paf" --max-per-segment 2 -o "${TMPDIR}/best.gfab"
+is "$(for r in chr1:11-18 chr1:51-58 chr2:11-18; do gfabase sub "${TMPDIR}/best.gfab" --range $r --view | grep -c ^S; done | tr '\n' ' ')" \
+   "1 0 1 " "add-mappings --max-per-segment"
+gfabase add-mappings "${TMPDIR}/small.gfab" "${TMPDIR}/best.paf" --primary-only -o "${TMPDIR}/primary.gfab"
+is "$(for r in chr1:11-18 chr1:51-58 chr2:11-18; do gfabase sub "${TMPDIR}/primary.gfab" --range $r --view | grep -c ^S; done | tr '\n' ' ')" \
+   "1 1 0 " "add-mappings --primary-only"
+gfabase remove-mappings "${TMPDIR}/mapped.gfab" --refseq chr5
+is "$(gfabase sub "${TMPDIR}/mapped.gfab" --range chr5:12-13 --view | grep -c ^S) $(gfabase sub "${TMPDIR}/mapped.gfab" --range chr7:12-13 --view | grep -c ^S)" \
+   "0 1" "remove-mappings"
+is "$(gfabase view "${TMPDIR}/mapped.gfab" | grep '^#' | cut -f4 | cut -d ' ' -f2 | tr '\n' ' ')" \
+   "load add-mappings add-mappings add-mappings add-mappings add-mappings remove-mappings " "provenance log"
+grep -w chr3 "${DATA}/small.paf" | gfabase add-mappings "${TMPDIR}/mapped.gfab" - -o "${TMPDIR}/mapped2.gfab"
+is "$(gfabase sub "${TMPDIR}/mapped.gfab" --range chr3:12-13 --view | grep -c ^S) $(gfabase sub "${TMPDIR}/mapped2.gfab" --range chr3:12-13 --view | grep -c ^S)" \
+   "0 1" "add-mappings -o"
+is "$(gfabase view --guess-loci 3 "${TMPDIR}/mapped.gfab" | grep "^S	q	" | cut -f5- | tr '\t' ' ')" \
+   "gr:Z:~chr7:11-19;~chr9:11-16;~1:1-4 gs:Z:+;-;+ gf:Z:1.00;0.75;0.50" "view --guess-loci"
+
+# without --mapping-set, use only the unnamed set of mappings (not set other, also on chr7)
+is "$(gfabase sub "${TMPDIR}/mapped.gfab" --range chr7:52-53 --view | grep -c ^S) $(gfabase sub "${TMPDIR}/mapped.gfab" --range chr7:52-53 --mapping-set other --view | grep -c ^S)" \
+   "0 1" "sub --range default mapping set"
+grep -w chr3 "${DATA}/small.paf" | gfabase add-mappings "${TMPDIR}/mapped.gfab" - --set another -o "${TMPDIR}/mapped3.gfab"
+gfabase view --guess-ranges "${TMPDIR}/mapped3.gfab" > /dev/null
+is "$?" "1" "--guess-ranges requires --mapping-set given several named sets"
+gfabase remove-mappings "${TMPDIR}/mapped3.gfab" --unnamed-set
+is "$(genomicsqlite "${TMPDIR}/mapped3.gfab" "SELECT group_concat(mapping_set) FROM (SELECT coalesce(mapping_set, '*') AS mapping_set FROM gfa1_segment_mapping ORDER BY 1)" | tail -n 1)" \
+   "another,other" "remove-mappings --unnamed-set"
+
+# a failed load leaves the existing output intact
+sed 's/^L\t2\t+\t3/L\t2\t+\tnope/' "${DATA}/small.gfa" | gfabase load - -o "${TMPDIR}/mapped.gfab"
+is "$(gfabase view "${TMPDIR}/mapped.gfab" | grep -c ^S)" "4" "failed load leaves existing output intact"
+
+# invalid segment tags are reported with the table & row
+cp "${TMPDIR}/small.gfab" "${TMPDIR}/badtags.gfab"
+genomicsqlite "${TMPDIR}/badtags.gfab" "UPDATE gfa1_segment_meta SET tags_json = '{' WHERE segment_id = 2"
+is "$(gfabase view "${TMPDIR}/badtags.gfab" 2>&1 >/dev/null | grep -o 'table = "[a-z0-9_]*", rowid = 2') $(gfabase view --fasta "${TMPDIR}/badtags.gfab" 2>&1 >/dev/null | grep -o 'table = "[a-z0-9_]*", rowid = 2')" \
+   'table = "gfa1_segment_meta", rowid = 2 table = "gfa1_segment_meta", rowid = 2' "invalid segment tags"
+
+# references to missing segments are reported with the referring row
+cp "${TMPDIR}/small.gfab" "${TMPDIR}/badrefs.gfab"
+genomicsqlite "${TMPDIR}/badrefs.gfab" "PRAGMA foreign_keys = OFF; DELETE FROM gfa1_segment_meta WHERE segment_id = 3"
+is "$(gfabase validate "${TMPDIR}/badrefs.gfab" | grep -Fx -e $'\tlink 2+ -> 3-: segment 3 missing' -e $'\twalk s#1#c: segment 3 missing' | wc -l | tr -d ' ')" \
+   "2" "gfabase validate (missing segment)"
+
+# merging same-named segments requires identical sequences
+sed 's/ACGTGGCC/ACGTGGCA/' "${DATA}/small.gfa" | gfabase load - -o "${TMPDIR}/small_q.gfab"
+gfabase merge "${TMPDIR}/small.gfab" "${TMPDIR}/small_q.gfab" -o "${TMPDIR}/merged.gfab"
+is "$?" "1" "gfabase merge (inconsistent sequence)"
+
+# diff matches a renamed, reverse-complemented segment by sequence
+sed 's/^S\tq\tACGTGGCC/S\tr\tGGCCACGT/' "${DATA}/small.gfa" | gfabase load - -o "${TMPDIR}/small_r.gfab"
+is "$(gfabase diff "${TMPDIR}/small.gfab" "${TMPDIR}/small_r.gfab" | grep -A 1 '^segments renamed' | tail -n 1 | tr -d '\t')" \
+   "q -> r (reverse complement)" "gfabase diff (reverse complement)"
+
+# walk checks
+sed 's/>1>2<3/>1>2>3/' "${DATA}/small.gfa" > "${TMPDIR}/invalid.gfa"
+gfabase validate "${TMPDIR}/invalid.gfa"
+is "$?" "1" "gfabase validate (invalid walk)"
+gfabase load --check-walks "${TMPDIR}/invalid.gfa" -o "${TMPDIR}/invalid.gfab"
+is "$?" "1" "gfabase load --check-walks"
+gfabase load --add-missing-links "${TMPDIR}/invalid.gfa" -o "${TMPDIR}/invalid.gfab" && gfabase validate "${TMPDIR}/invalid.gfab"
+is "$?" "0" "gfabase load --add-missing-links"
+
+rm -rf "$TMPDIR"